@@ -1,6 +1,22 @@
-use std::collections::HashMap;
-use std::fmt;
+// Sans la fonctionnalité `std` (active par défaut), seuls `core` et `alloc` sont requis :
+// tout est disponible sauf la lecture et l'écriture de fichiers
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeSet;
+use alloc::format;
+use alloc::rc::Rc;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use core::hash::{Hash, Hasher};
+use core::ops::{ControlFlow, Range};
+#[cfg(feature = "std")]
 use std::fs;
+#[cfg(feature = "std")]
 use std::path::Path;
 
 // Représentation de toutes les valeurs Nix possibles
@@ -11,26 +27,49 @@ pub enum NixValue {
     Int(i64),
     Float(f64),
     String(String),
-    Path(String),
+    InterpolatedString(Vec<StringPart>),
+    // String multi-lignes `''...''`, indentation commune déjà retirée
+    IndentedString(Vec<StringPart>),
+    // String lue avec `with_raw_strings` : les littéraux gardent leur texte source
+    // (échappements, indentation) et sont réécrits sans transformation
+    RawString(StringKind, Vec<StringPart>),
+    // Texte du chemin tel qu'écrit, sans les chevrons pour `<nixpkgs>`
+    Path(PathKind, String),
+    // Chemin contenant des antiquotations : `./${name}.nix`
+    InterpolatedPath(Vec<StringPart>),
     List(Vec<NixValue>),
-    AttrSet(HashMap<String, NixValue>),
+    AttrSet(AttrSetMap),
+    RecAttrSet(AttrSetMap),
     Function(Box<NixFunction>),
     Let(Box<NixLet>),
     With(Box<NixWith>),
-    Inherit(Vec<String>),
-    Import(String),
+    Assert(Box<NixAssert>),
+    If(Box<NixIf>),
+    BinaryOp(Box<NixBinaryOp>),
+    UnaryOp(Box<NixUnaryOp>),
+    Select(Box<NixSelect>),
+    HasAttr(Box<NixHasAttr>),
+    Apply(Box<NixApply>),
+    // Parenthèses du source, conservées seulement en mode sans perte (`NixParser::with_parens`)
+    Paren(Box<NixValue>),
+    // `import` appliqué à un chemin ou une chaîne littérale, argument gardé tel quel
+    Import(Box<NixValue>),
     Variable(String),
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct NixFunction {
     pub params: Vec<String>,
+    // Motif `{ a }:` plutôt que paramètre simple `a:`
+    pub pattern: bool,
+    // Le motif se termine par `...` : les autres attributs de l'argument sont acceptés
+    pub ellipsis: bool,
     pub body: NixValue,
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct NixLet {
-    pub bindings: HashMap<String, NixValue>,
+    pub bindings: AttrSetMap,
     pub body: NixValue,
 }
 
@@ -40,719 +79,9485 @@ pub struct NixWith {
     pub body: NixValue,
 }
 
-// Erreur de parsing avec contexte
-#[derive(Debug, Clone)]
-pub struct ParseError {
-    pub message: String,
-    pub line: usize,
-    pub col: usize,
-    pub context: String,
+// `assert cond; body`
+#[derive(Debug, Clone, PartialEq)]
+pub struct NixAssert {
+    pub cond: NixValue,
+    pub body: NixValue,
 }
 
-impl std::fmt::Display for ParseError {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "Erreur de parsing à la ligne {}, colonne {}:\n{}\nContexte: {}",
-               self.line, self.col, self.message, self.context)
-    }
+// `if cond then a else b`
+#[derive(Debug, Clone, PartialEq)]
+pub struct NixIf {
+    pub cond: NixValue,
+    pub then_branch: NixValue,
+    pub else_branch: NixValue,
+}
+
+// Sélection d'attributs `expr.a.${b} or default`
+#[derive(Debug, Clone, PartialEq)]
+pub struct NixSelect {
+    pub expr: NixValue,
+    // Chaque composant est un nom (sans guillemets) ou une clé calculée
+    pub path: Vec<AttrKey>,
+    pub default: Option<NixValue>,
 }
 
-impl std::error::Error for ParseError {}
+// Test de présence d'un attribut `expr ? a.b`
+#[derive(Debug, Clone, PartialEq)]
+pub struct NixHasAttr {
+    pub expr: NixValue,
+    pub path: Vec<AttrKey>,
+}
 
-// Parser de fichiers Nix
-pub struct NixParser {
-    input: Vec<char>,
-    pos: usize,
-    line: usize,
-    col: usize,
+// Application de fonction `func arg`
+#[derive(Debug, Clone, PartialEq)]
+pub struct NixApply {
+    pub func: NixValue,
+    pub arg: NixValue,
 }
 
-impl NixParser {
-    pub fn new(input: &str) -> Self {
-        Self {
-            input: input.chars().collect(),
-            pos: 0,
-            line: 1,
-            col: 1,
+// Morceau d'une chaîne interpolée `"a ${b} c"`
+#[derive(Debug, Clone, PartialEq)]
+pub enum StringPart {
+    Literal(String),
+    Interpolation(NixValue),
+}
+
+// Forme syntaxique d'un chemin
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PathKind {
+    Relative,   // ./a, ../a
+    Absolute,   // /etc/a
+    Home,       // ~/a
+    SearchPath, // <nixpkgs/lib>
+}
+
+impl PathKind {
+    // Forme d'un chemin écrit littéralement (hors `<...>`)
+    fn of(path: &str) -> PathKind {
+        if path.starts_with('/') {
+            PathKind::Absolute
+        } else if path.starts_with('~') {
+            PathKind::Home
+        } else {
+            PathKind::Relative
         }
     }
+}
 
-    fn current(&self) -> Option<char> {
-        self.input.get(self.pos).copied()
-    }
+// Délimiteurs d'une `RawString`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum StringKind {
+    DoubleQuoted, // "..."
+    Indented,     // ''...''
+}
 
-    fn advance(&mut self) {
-        if let Some(c) = self.current() {
-            if c == '\n' {
-                self.line += 1;
-                self.col = 1;
-            } else {
-                self.col += 1;
-            }
+// Segment d'un chemin d'attributs
+#[derive(Debug, Clone, PartialEq)]
+pub enum AttrKey {
+    // Nom, sans guillemets (`"/"` dans fileSystems."/".options est stocké `/`)
+    Static(String),
+    // Clé calculée `${expr}` ou `"${prefix}-suffix"`
+    Dynamic(NixValue),
+}
+
+impl AttrKey {
+    pub fn as_static(&self) -> Option<&str> {
+        match self {
+            AttrKey::Static(s) => Some(s),
+            AttrKey::Dynamic(_) => None,
         }
-        self.pos += 1;
     }
+}
 
-    fn get_context(&self, range: usize) -> String {
-        let start = self.pos.saturating_sub(range);
-        let end = (self.pos + range).min(self.input.len());
-        let context: String = self.input[start..end].iter().collect();
-        let pointer_pos = (self.pos - start).min(context.len());
-        format!("{}\n{}^", context.replace('\n', "\\n"), " ".repeat(pointer_pos))
+impl From<String> for AttrKey {
+    fn from(key: String) -> Self {
+        AttrKey::Static(key)
     }
+}
 
-    fn error(&self, msg: &str) -> ParseError {
-        ParseError {
-            message: msg.to_string(),
-            line: self.line,
-            col: self.col,
-            context: self.get_context(30),
-        }
+impl From<&str> for AttrKey {
+    fn from(key: &str) -> Self {
+        AttrKey::Static(key.to_string())
     }
+}
 
-    fn skip_whitespace(&mut self) {
-        while let Some(c) = self.current() {
-            if c.is_whitespace() {
-                self.advance();
-            } else if c == '#' {
-                // Commentaire ligne
-                while let Some(ch) = self.current() {
-                    self.advance();
-                    if ch == '\n' {
-                        break;
-                    }
-                }
-            } else if self.peek_string("/*") {
-                // Commentaire bloc
-                self.advance();
-                self.advance();
-                while !self.peek_string("*/") && self.current().is_some() {
-                    self.advance();
-                }
-                if self.peek_string("*/") {
-                    self.advance();
-                    self.advance();
-                }
-            } else {
-                break;
-            }
-        }
+impl PartialEq<str> for AttrKey {
+    fn eq(&self, other: &str) -> bool {
+        self.as_static() == Some(other)
     }
+}
 
-    fn peek_string(&self, s: &str) -> bool {
-        let chars: Vec<char> = s.chars().collect();
-        for (i, &ch) in chars.iter().enumerate() {
-            if self.input.get(self.pos + i) != Some(&ch) {
-                return false;
-            }
+impl fmt::Display for AttrKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AttrKey::Static(s) => write!(f, "{}", quote_attr_name(s)),
+            // Une chaîne interpolée sert directement de clé
+            AttrKey::Dynamic(expr @ NixValue::InterpolatedString(_)) => write!(f, "{}", expr),
+            AttrKey::Dynamic(expr) => write!(f, "${{{}}}", expr),
         }
-        true
     }
+}
 
-    fn parse_identifier(&mut self) -> Result<String, ParseError> {
-        let mut id = String::new();
-        while let Some(c) = self.current() {
-            if c.is_alphanumeric() || c == '_' || c == '-' || c == '\'' {
-                id.push(c);
-                self.advance();
-            } else {
-                break;
-            }
-        }
-        if id.is_empty() {
-            Err(self.error("Expected identifier"))
-        } else {
-            Ok(id)
+// Clé d'une liaison : chemin `a."b.c".${d}`, segment par segment
+#[derive(Debug, Clone, PartialEq)]
+pub struct AttrPath(pub Vec<AttrKey>);
+
+impl AttrPath {
+    pub fn segments(&self) -> &[AttrKey] {
+        &self.0
+    }
+
+    // Nom d'un chemin réduit à un seul segment statique
+    pub fn as_static(&self) -> Option<&str> {
+        match self.0.as_slice() {
+            [key] => key.as_static(),
+            _ => None,
         }
     }
 
-    fn parse_path(&mut self) -> Result<String, ParseError> {
-        let mut path = String::new();
+    // Premier segment, qui est le nom lié dans l'ensemble englobant
+    pub fn head(&self) -> Option<&AttrKey> {
+        self.0.first()
+    }
 
-        // Gérer les chemins relatifs et absolus
-        // Accepter: lettres, chiffres, _, -, /, .
-        while let Some(c) = self.current() {
-            match c {
-                'a'..='z' | 'A'..='Z' | '0'..='9' | '_' | '-' | '/' | '.' => {
-                    path.push(c);
-                    self.advance();
-                }
-                _ => break,
+    // Ordre alphabétique segment par segment, les segments calculés en dernier
+    fn cmp_for_sort(&self, other: &AttrPath) -> core::cmp::Ordering {
+        use core::cmp::Ordering;
+        for (a, b) in self.0.iter().zip(&other.0) {
+            let ordering = match (a, b) {
+                (AttrKey::Static(x), AttrKey::Static(y)) => x.cmp(y),
+                (AttrKey::Static(_), AttrKey::Dynamic(_)) => Ordering::Less,
+                (AttrKey::Dynamic(_), AttrKey::Static(_)) => Ordering::Greater,
+                (AttrKey::Dynamic(_), AttrKey::Dynamic(_)) => Ordering::Equal,
+            };
+            if ordering != Ordering::Equal {
+                return ordering;
             }
         }
+        self.0.len().cmp(&other.0.len())
+    }
+}
 
-        if path.is_empty() {
-            Err(self.error("Expected path"))
-        } else {
-            Ok(path)
-        }
+impl From<AttrKey> for AttrPath {
+    fn from(key: AttrKey) -> Self {
+        AttrPath(vec![key])
     }
+}
 
-    fn parse_attribute_path(&mut self) -> Result<String, ParseError> {
-        let mut path = String::new();
+impl From<Vec<AttrKey>> for AttrPath {
+    fn from(keys: Vec<AttrKey>) -> Self {
+        AttrPath(keys)
+    }
+}
 
-        loop {
-            // Vérifier qu'on n'est pas sur un chemin de fichier
-            if self.current() == Some('.') && self.input.get(self.pos + 1) == Some(&'/') {
-                // C'est un chemin relatif, pas un accès d'attribut
-                return Err(self.error("Path found where identifier expected"));
-            }
+// Une chaîne donne un chemin d'un seul segment : "a.b" est l'attribut `"a.b"`
+impl From<String> for AttrPath {
+    fn from(key: String) -> Self {
+        AttrPath(vec![AttrKey::Static(key)])
+    }
+}
 
-            // Gérer les clés entre guillemets comme fileSystems."/".options
-            let part = if self.current() == Some('"') {
-                let s = self.parse_string()?;
-                // Préserver les guillemets dans le chemin pour le reformatage
-                format!("\"{}\"", s)
-            } else {
-                self.parse_identifier()?
-            };
+impl From<&str> for AttrPath {
+    fn from(key: &str) -> Self {
+        AttrPath(vec![AttrKey::from(key)])
+    }
+}
 
-            path.push_str(&part);
+impl PartialEq<str> for AttrPath {
+    fn eq(&self, other: &str) -> bool {
+        self.as_static() == Some(other)
+    }
+}
 
-            self.skip_whitespace();
-            if self.current() == Some('.') {
-                // Vérifier que le prochain caractère n'est pas '/' (ce serait un chemin)
-                if self.input.get(self.pos + 1) == Some(&'/') {
-                    break;
-                }
-                path.push('.');
-                self.advance();
-                self.skip_whitespace();
-            } else {
-                break;
+impl fmt::Display for AttrPath {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, key) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, ".")?;
             }
+            write!(f, "{}", key)?;
         }
-
-        Ok(path)
+        Ok(())
     }
+}
 
-    fn parse_string(&mut self) -> Result<String, ParseError> {
-        let quote = self.current().ok_or_else(|| self.error("Expected quote"))?;
+// Liaison `clé = valeur;` d'un ensemble d'attributs
+#[derive(Debug, Clone, PartialEq)]
+pub struct AttrEntry {
+    pub key: AttrPath,
+    pub value: NixValue,
+    // Commentaires précédant la liaison (seulement avec `NixParser::with_comments`)
+    pub comments: Vec<String>,
+    // Commentaire `#` sur la même ligne que la liaison
+    pub inline_comment: Option<String>,
+}
 
-        // Gérer les strings multi-lignes ''...''
-        if quote == '\'' && self.input.get(self.pos + 1) == Some(&'\'') {
-            self.advance(); // première '
-            self.advance(); // deuxième '
+impl AttrEntry {
+    pub fn new(key: impl Into<AttrPath>, value: NixValue) -> Self {
+        Self {
+            key: key.into(),
+            value,
+            comments: Vec::new(),
+            inline_comment: None,
+        }
+    }
+}
 
-            let mut s = String::new();
-            while let Some(c) = self.current() {
-                if c == '\'' && self.input.get(self.pos + 1) == Some(&'\'') {
-                    self.advance();
-                    self.advance();
-                    return Ok(s);
-                }
-                s.push(c);
-                self.advance();
-            }
-            return Err(self.error("Unterminated multi-line string"));
+// Héritage `inherit a "b";` ou `inherit (src) a;` d'un ensemble ou d'un `let`
+#[derive(Debug, Clone, PartialEq)]
+pub struct NixInherit {
+    pub from: Option<NixValue>,
+    pub names: Vec<String>,
+    // Commentaires précédant la ligne (seulement avec `NixParser::with_comments`)
+    pub comments: Vec<String>,
+}
+
+impl NixInherit {
+    pub fn new(from: Option<NixValue>, names: Vec<String>) -> Self {
+        Self {
+            from,
+            names,
+            comments: Vec::new(),
         }
+    }
+}
 
-        // String normale
-        self.advance();
+// Ensemble d'attributs conservant l'ordre du source
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct AttrSetMap {
+    entries: Vec<AttrEntry>,
+    // Lignes `inherit`, dans leur ordre ; réécrites avant les liaisons
+    pub inherits: Vec<NixInherit>,
+    // Commentaires situés après la dernière liaison, avant l'accolade fermante
+    pub trailing_comments: Vec<String>,
+}
 
-        let mut s = String::new();
-        let mut escaped = false;
+impl AttrSetMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
 
-        while let Some(c) = self.current() {
-            if escaped {
-                s.push(match c {
-                    'n' => '\n',
-                    't' => '\t',
-                    'r' => '\r',
-                    '\\' => '\\',
-                    '"' => '"',
-                    '\'' => '\'',
-                    _ => c,
-                });
-                escaped = false;
-                self.advance();
-            } else if c == '\\' {
-                escaped = true;
-                self.advance();
-            } else if c == quote {
-                self.advance();
-                return Ok(s);
-            } else {
-                s.push(c);
-                self.advance();
-            }
-        }
-        Err(self.error("Unterminated string"))
+    // Nombre de liaisons, noms hérités compris
+    pub fn len(&self) -> usize {
+        self.entries.len() + self.inherited_names().count()
     }
 
-    fn parse_number(&mut self) -> Result<NixValue, ParseError> {
-        let mut num = String::new();
-        let mut is_float = false;
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty() && self.inherits.is_empty()
+    }
 
-        while let Some(c) = self.current() {
-            if c.is_numeric() || c == '.' || c == '-' {
-                if c == '.' {
-                    is_float = true;
-                }
-                num.push(c);
-                self.advance();
-            } else {
-                break;
-            }
-        }
+    // Les recherches par nom ne concernent que les clés statiques
+    pub fn get(&self, key: &str) -> Option<&NixValue> {
+        self.entries
+            .iter()
+            .find(|e| e.key == *key)
+            .map(|e| &e.value)
+    }
 
-        if is_float {
-            num.parse::<f64>()
-                .map(NixValue::Float)
-                .map_err(|_| self.error("Invalid float"))
-        } else {
-            num.parse::<i64>()
-                .map(NixValue::Int)
-                .map_err(|_| self.error("Invalid integer"))
-        }
+    pub fn get_mut(&mut self, key: &str) -> Option<&mut NixValue> {
+        self.entries
+            .iter_mut()
+            .find(|e| e.key == *key)
+            .map(|e| &mut e.value)
     }
 
-    fn parse_list(&mut self) -> Result<NixValue, ParseError> {
-        self.advance(); // '['
-        self.skip_whitespace();
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.entries.iter().any(|e| e.key == *key)
+    }
 
-        let mut items = Vec::new();
-        while self.current() != Some(']') && self.current().is_some() {
-            items.push(self.parse_value()?);
-            self.skip_whitespace();
+    // Remplace la valeur en conservant sa position si la clé existe déjà
+    pub fn insert(&mut self, key: impl Into<AttrPath>, value: NixValue) -> Option<NixValue> {
+        let key = key.into();
+        match self.entries.iter_mut().find(|e| e.key == key) {
+            Some(existing) => Some(core::mem::replace(&mut existing.value, value)),
+            None => {
+                self.entries.push(AttrEntry::new(key, value));
+                None
+            }
         }
+    }
 
-        if self.current() == Some(']') {
-            self.advance(); // ']'
-        }
-        Ok(NixValue::List(items))
+    pub fn remove(&mut self, key: &str) -> Option<NixValue> {
+        let index = self.entries.iter().position(|e| e.key == *key)?;
+        Some(self.entries.remove(index).value)
     }
 
-    fn parse_attrset(&mut self) -> Result<NixValue, ParseError> {
-        self.advance(); // '{'
-        self.skip_whitespace();
+    pub fn push_entry(&mut self, entry: AttrEntry) {
+        self.entries.push(entry);
+    }
 
-        let mut attrs = HashMap::new();
+    pub fn entries(&self) -> &[AttrEntry] {
+        &self.entries
+    }
 
-        while self.current() != Some('}') && self.current().is_some() {
-            // Gérer 'inherit'
-            if self.peek_string("inherit") {
-                for _ in 0..7 {
-                    self.advance();
-                }
-                self.skip_whitespace();
+    pub fn entries_mut(&mut self) -> &mut [AttrEntry] {
+        &mut self.entries
+    }
 
-                let mut inherited = Vec::new();
-                while self.current() != Some(';') && self.current().is_some() {
-                    let id = self.parse_identifier()?;
-                    inherited.push(id);
-                    self.skip_whitespace();
-                }
-                if self.current() == Some(';') {
-                    self.advance(); // ';'
-                }
-                self.skip_whitespace();
-                continue;
-            }
+    pub fn iter(&self) -> impl Iterator<Item = (&AttrPath, &NixValue)> {
+        self.entries.iter().map(|e| (&e.key, &e.value))
+    }
 
-            // Parser la clé (peut être un chemin d'attributs comme "services.udev.extraRules")
-            let key = if self.current() == Some('"') {
-                self.parse_string()?
-            } else {
-                self.parse_attribute_path()?
-            };
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (&AttrPath, &mut NixValue)> {
+        self.entries.iter_mut().map(|e| (&e.key, &mut e.value))
+    }
 
-            self.skip_whitespace();
+    // Noms liés par les lignes `inherit`
+    pub fn inherited_names(&self) -> impl Iterator<Item = &str> {
+        self.inherits
+            .iter()
+            .flat_map(|i| i.names.iter().map(String::as_str))
+    }
 
-            if self.current() != Some('=') {
-                return Err(self.error(&format!("Expected '=' after key '{}', found {:?}", key, self.current())));
-            }
-            self.advance();
-            self.skip_whitespace();
+    pub fn keys(&self) -> impl Iterator<Item = &AttrPath> {
+        self.entries.iter().map(|e| &e.key)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &NixValue> {
+        self.entries.iter().map(|e| &e.value)
+    }
+
+    pub fn values_mut(&mut self) -> impl Iterator<Item = &mut NixValue> {
+        self.entries.iter_mut().map(|e| &mut e.value)
+    }
+
+    // Trie les liaisons par clé ; les clés calculées restent à la fin, dans leur ordre
+    pub fn sort_keys(&mut self) {
+        self.entries.sort_by(|a, b| a.key.cmp_for_sort(&b.key));
+    }
+}
 
-            let value = self.parse_value()?;
+impl FromIterator<(String, NixValue)> for AttrSetMap {
+    fn from_iter<I: IntoIterator<Item = (String, NixValue)>>(iter: I) -> Self {
+        let mut attrs = AttrSetMap::new();
+        for (key, value) in iter {
             attrs.insert(key, value);
+        }
+        attrs
+    }
+}
 
-            self.skip_whitespace();
-            if self.current() == Some(';') {
-                self.advance();
-                self.skip_whitespace();
+impl IntoIterator for AttrSetMap {
+    type Item = (AttrPath, NixValue);
+    type IntoIter =
+        core::iter::Map<alloc::vec::IntoIter<AttrEntry>, fn(AttrEntry) -> (AttrPath, NixValue)>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.entries.into_iter().map(|e| (e.key, e.value))
+    }
+}
+
+// Opérateurs binaires
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BinOp {
+    Concat,    // ++
+    Mul,       // *
+    Div,       // /
+    Add,       // +
+    Sub,       // -
+    Update,    // //
+    Less,      // <
+    LessEq,    // <=
+    Greater,   // >
+    GreaterEq, // >=
+    Eq,        // ==
+    NotEq,     // !=
+    And,       // &&
+    Or,        // ||
+    Implies,   // ->
+}
+
+impl BinOp {
+    // Priorité selon la table des opérateurs de Nix (plus élevée = plus liante)
+    pub fn precedence(self) -> u8 {
+        match self {
+            BinOp::Implies => 1,
+            BinOp::Or => 2,
+            BinOp::And => 3,
+            BinOp::Eq | BinOp::NotEq => 4,
+            BinOp::Less | BinOp::LessEq | BinOp::Greater | BinOp::GreaterEq => 5,
+            BinOp::Update => 6,
+            BinOp::Add | BinOp::Sub => 8,
+            BinOp::Mul | BinOp::Div => 9,
+            BinOp::Concat => 10,
+        }
+    }
+
+    pub fn is_right_assoc(self) -> bool {
+        matches!(self, BinOp::Concat | BinOp::Update | BinOp::Implies)
+    }
+
+    // Les comparaisons ne s'enchaînent pas sans parenthèses (`a < b < c` est refusé par Nix)
+    pub fn is_non_assoc(self) -> bool {
+        matches!(
+            self,
+            BinOp::Less
+                | BinOp::LessEq
+                | BinOp::Greater
+                | BinOp::GreaterEq
+                | BinOp::Eq
+                | BinOp::NotEq
+        )
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            BinOp::Concat => "++",
+            BinOp::Mul => "*",
+            BinOp::Div => "/",
+            BinOp::Add => "+",
+            BinOp::Sub => "-",
+            BinOp::Update => "//",
+            BinOp::Less => "<",
+            BinOp::LessEq => "<=",
+            BinOp::Greater => ">",
+            BinOp::GreaterEq => ">=",
+            BinOp::Eq => "==",
+            BinOp::NotEq => "!=",
+            BinOp::And => "&&",
+            BinOp::Or => "||",
+            BinOp::Implies => "->",
+        }
+    }
+}
+
+impl fmt::Display for BinOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct NixBinaryOp {
+    pub op: BinOp,
+    pub lhs: NixValue,
+    pub rhs: NixValue,
+}
+
+// Opérateurs préfixes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum UnOp {
+    Not, // !
+    Neg, // -
+}
+
+impl UnOp {
+    // `!` lie moins fort que `+` (`!a + b` est `!(a + b)`), `-` plus fort que `++`
+    pub fn precedence(self) -> u8 {
+        match self {
+            UnOp::Not => 7,
+            UnOp::Neg => 12,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            UnOp::Not => "!",
+            UnOp::Neg => "-",
+        }
+    }
+}
+
+impl fmt::Display for UnOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct NixUnaryOp {
+    pub op: UnOp,
+    pub operand: NixValue,
+}
+
+// Accesseurs typés
+impl NixValue {
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            NixValue::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            NixValue::Int(i) => Some(*i),
+            _ => None,
+        }
+    }
+
+    pub fn as_float(&self) -> Option<f64> {
+        match self {
+            NixValue::Float(fl) => Some(*fl),
+            _ => None,
+        }
+    }
+
+    // Aussi pour une string multi-lignes sans interpolation
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            NixValue::String(s) => Some(s),
+            NixValue::IndentedString(parts) => match parts.as_slice() {
+                [] => Some(""),
+                [StringPart::Literal(s)] => Some(s),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    pub fn as_list(&self) -> Option<&[NixValue]> {
+        match self {
+            NixValue::List(items) => Some(items),
+            _ => None,
+        }
+    }
+
+    // Fonctionne pour les ensembles simples comme pour les `rec { ... }`
+    pub fn as_attrset(&self) -> Option<&AttrSetMap> {
+        match self {
+            NixValue::AttrSet(attrs) | NixValue::RecAttrSet(attrs) => Some(attrs),
+            _ => None,
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&NixValue> {
+        self.as_attrset()?.get(key)
+    }
+
+    // Suit un chemin à travers les ensembles, que les clés soient pointées
+    // (`services.nginx.enable = true;`), imbriquées (`services = { nginx = ...; };`)
+    // ou un mélange des deux. Un nom entre guillemets `"a.b"` reste un seul segment ;
+    // un chemin qui s'arrête au milieu d'une clé pointée ne désigne aucune valeur
+    pub fn get_path(&self, path: &[&str]) -> Option<&NixValue> {
+        if path.is_empty() {
+            return Some(self);
+        }
+        self.as_attrset()?.entries().iter().find_map(|entry| {
+            let key = entry.key.segments();
+            let matches = key.len() <= path.len() && key.iter().zip(path).all(|(k, p)| k == *p);
+            if matches {
+                entry.value.get_path(&path[key.len()..])
+            } else {
+                None
             }
+        })
+    }
+
+    // Conversions consommantes : la valeur d'origine est rendue si le type ne correspond pas
+    pub fn into_attrset(self) -> Result<AttrSetMap, NixValue> {
+        match self {
+            NixValue::AttrSet(attrs) | NixValue::RecAttrSet(attrs) => Ok(attrs),
+            other => Err(other),
         }
+    }
 
-        if self.current() == Some('}') {
-            self.advance(); // '}'
+    pub fn into_list(self) -> Result<Vec<NixValue>, NixValue> {
+        match self {
+            NixValue::List(items) => Ok(items),
+            other => Err(other),
+        }
+    }
+
+    // Pour une sélection `builtins.nom`, renvoie le nom du builtin utilisé
+    pub fn is_builtins_access(&self) -> Option<&str> {
+        match self {
+            NixValue::Select(select) if matches!(&select.expr, NixValue::Variable(v) if v == "builtins") => {
+                select.path.first().and_then(AttrKey::as_static)
+            }
+            _ => None,
+        }
+    }
+
+    // Nombre d'éléments d'une liste, d'attributs d'un ensemble (`inherit` compris) ou de
+    // caractères d'une chaîne sans interpolation (multi-lignes comprise, comme `as_str`).
+    // `None` pour les autres valeurs, qui n'ont pas de longueur, et pour une chaîne
+    // interpolée, dont la longueur n'est connue qu'après évaluation
+    pub fn len(&self) -> Option<usize> {
+        match self {
+            NixValue::List(items) => Some(items.len()),
+            NixValue::AttrSet(attrs) | NixValue::RecAttrSet(attrs) => Some(attrs.len()),
+            _ => self.as_str().map(|s| s.chars().count()),
+        }
+    }
+
+    // Liste, ensemble ou chaîne vide ; `None` quand la notion de vide n'a pas de sens ou
+    // que la réponse dépend de l'évaluation (chaîne interpolée), comme pour `len`
+    pub fn is_empty(&self) -> Option<bool> {
+        self.len().map(|len| len == 0)
+    }
+}
+
+// Conversions depuis les types Rust, pour construire des valeurs à écrire
+impl From<i64> for NixValue {
+    fn from(i: i64) -> Self {
+        NixValue::Int(i)
+    }
+}
+
+impl From<f64> for NixValue {
+    fn from(fl: f64) -> Self {
+        NixValue::Float(fl)
+    }
+}
+
+impl From<bool> for NixValue {
+    fn from(b: bool) -> Self {
+        NixValue::Bool(b)
+    }
+}
+
+impl From<String> for NixValue {
+    fn from(s: String) -> Self {
+        NixValue::String(s)
+    }
+}
+
+impl From<&str> for NixValue {
+    fn from(s: &str) -> Self {
+        NixValue::String(s.to_string())
+    }
+}
+
+impl From<Vec<NixValue>> for NixValue {
+    fn from(items: Vec<NixValue>) -> Self {
+        NixValue::List(items)
+    }
+}
+
+impl From<AttrSetMap> for NixValue {
+    fn from(attrs: AttrSetMap) -> Self {
+        NixValue::AttrSet(attrs)
+    }
+}
+
+impl FromIterator<(String, NixValue)> for NixValue {
+    fn from_iter<I: IntoIterator<Item = (String, NixValue)>>(iter: I) -> Self {
+        NixValue::AttrSet(iter.into_iter().collect())
+    }
+}
+
+/// Lecture d'une expression. `TryFrom<&str>` n'est pas possible, `From<&str>` construisant
+/// déjà une chaîne Nix.
+///
+/// ```
+/// use nixos_parser::NixValue;
+///
+/// let value = "{ a = 1; }".parse::<NixValue>().unwrap();
+/// assert_eq!(value.get("a"), Some(&NixValue::Int(1)));
+///
+/// // `1 2` est une application, mais rien ne doit suivre l'expression
+/// assert!("1 2".parse::<NixValue>().is_ok());
+/// assert!("1 )".parse::<NixValue>().is_err());
+/// ```
+impl core::str::FromStr for NixValue {
+    type Err = ParseError;
+
+    // Toute l'entrée doit former une seule expression : `"1 )"` est une erreur
+    fn from_str(input: &str) -> Result<Self, ParseError> {
+        parse_nix_string(input)
+    }
+}
+
+impl NixValue {
+    // Application symbolique `self a b c`, imbriquée à gauche comme dans le source
+    pub fn apply_args(self, args: &[NixValue]) -> NixValue {
+        args.iter().fold(self, |func, arg| {
+            NixValue::Apply(Box::new(NixApply {
+                func,
+                arg: arg.clone(),
+            }))
+        })
+    }
+}
+
+// Construction d'un ensemble d'attributs liaison par liaison :
+// `AttrSetBuilder::new().set("a", 1).set(vec!["b".into(), "c".into()], "x").build()`
+#[derive(Debug, Clone, Default)]
+pub struct AttrSetBuilder {
+    attrs: AttrSetMap,
+    rec: bool,
+}
+
+impl AttrSetBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Une clé déjà liée garde sa position et prend la nouvelle valeur
+    pub fn set(mut self, key: impl Into<AttrPath>, value: impl Into<NixValue>) -> Self {
+        self.attrs.insert(key, value.into());
+        self
+    }
+
+    // Produit `rec { ... }`
+    pub fn rec(mut self) -> Self {
+        self.rec = true;
+        self
+    }
+
+    pub fn build(self) -> NixValue {
+        if self.rec {
+            NixValue::RecAttrSet(self.attrs)
+        } else {
+            NixValue::AttrSet(self.attrs)
+        }
+    }
+}
+
+// Construction de `let ... in body` : `LetBuilder::new().bind("x", 1).body(x).build()`
+#[derive(Debug, Clone, Default)]
+pub struct LetBuilder {
+    bindings: AttrSetMap,
+    body: Option<NixValue>,
+}
+
+impl LetBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn bind(mut self, name: impl Into<String>, value: impl Into<NixValue>) -> Self {
+        self.bindings.insert(name.into(), value.into());
+        self
+    }
+
+    pub fn body(mut self, body: impl Into<NixValue>) -> Self {
+        self.body = Some(body.into());
+        self
+    }
+
+    // Sans corps, le `let` s'évalue à `null`
+    pub fn build(self) -> NixValue {
+        let body = self.body.unwrap_or(NixValue::Null);
+        NixValue::Let(Box::new(NixLet {
+            bindings: self.bindings,
+            body,
+        }))
+    }
+}
+
+// FNV-1a 64 bits : `DefaultHasher` n'existe pas sans `std`, et une empreinte qui ne
+// dépend pas de la version de Rust peut être conservée
+struct Fnv1a(u64);
+
+impl Default for Fnv1a {
+    fn default() -> Self {
+        Fnv1a(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for Fnv1a {
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = (self.0 ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+// Transformations de l'arbre
+impl NixValue {
+    // Applique `f` à chaque sous-expression directe
+    fn for_each_child_mut(&mut self, f: &mut impl FnMut(&mut NixValue)) {
+        match self {
+            NixValue::Null
+            | NixValue::Bool(_)
+            | NixValue::Int(_)
+            | NixValue::Float(_)
+            | NixValue::String(_)
+            | NixValue::Path(..)
+            | NixValue::Variable(_) => {}
+            NixValue::InterpolatedString(parts)
+            | NixValue::IndentedString(parts)
+            | NixValue::RawString(_, parts)
+            | NixValue::InterpolatedPath(parts) => {
+                for part in parts {
+                    if let StringPart::Interpolation(expr) = part {
+                        f(expr);
+                    }
+                }
+            }
+            NixValue::List(items) => items.iter_mut().for_each(f),
+            NixValue::AttrSet(attrs) | NixValue::RecAttrSet(attrs) => {
+                attrs
+                    .inherits
+                    .iter_mut()
+                    .filter_map(|i| i.from.as_mut())
+                    .for_each(&mut *f);
+                for entry in attrs.entries_mut() {
+                    for key in &mut entry.key.0 {
+                        if let AttrKey::Dynamic(expr) = key {
+                            f(expr);
+                        }
+                    }
+                    f(&mut entry.value);
+                }
+            }
+            NixValue::Function(func) => f(&mut func.body),
+            NixValue::Let(let_expr) => {
+                let bindings = &mut let_expr.bindings;
+                bindings
+                    .inherits
+                    .iter_mut()
+                    .filter_map(|i| i.from.as_mut())
+                    .for_each(&mut *f);
+                bindings.values_mut().for_each(&mut *f);
+                f(&mut let_expr.body);
+            }
+            NixValue::With(with_expr) => {
+                f(&mut with_expr.expr);
+                f(&mut with_expr.body);
+            }
+            NixValue::Assert(assert_expr) => {
+                f(&mut assert_expr.cond);
+                f(&mut assert_expr.body);
+            }
+            NixValue::If(if_expr) => {
+                f(&mut if_expr.cond);
+                f(&mut if_expr.then_branch);
+                f(&mut if_expr.else_branch);
+            }
+            NixValue::BinaryOp(binary_op) => {
+                f(&mut binary_op.lhs);
+                f(&mut binary_op.rhs);
+            }
+            NixValue::UnaryOp(unary_op) => f(&mut unary_op.operand),
+            NixValue::Paren(inner) | NixValue::Import(inner) => f(inner),
+            NixValue::Select(select) => {
+                f(&mut select.expr);
+                for key in &mut select.path {
+                    if let AttrKey::Dynamic(expr) = key {
+                        f(expr);
+                    }
+                }
+                if let Some(default) = &mut select.default {
+                    f(default);
+                }
+            }
+            NixValue::HasAttr(has_attr) => {
+                f(&mut has_attr.expr);
+                for key in &mut has_attr.path {
+                    if let AttrKey::Dynamic(expr) = key {
+                        f(expr);
+                    }
+                }
+            }
+            NixValue::Apply(apply) => {
+                f(&mut apply.func);
+                f(&mut apply.arg);
+            }
+        }
+    }
+
+    // Trie les clés de tous les ensembles d'attributs de l'arbre (l'ordre des listes est conservé)
+    pub fn sort_attrs_recursively(&mut self) {
+        self.sort_keys_recursively(false);
+    }
+
+    // Copie dont les ensembles d'attributs et les liaisons `let` sont triés à tous les niveaux
+    pub fn sorted(&self) -> NixValue {
+        let mut value = self.clone();
+        value.sort_keys_recursively(true);
+        value
+    }
+
+    // Égalité qui ignore l'ordre des liaisons (ensembles et `let`) mais pas celui des
+    // listes : une réorganisation des attributs ne change pas le sens d'une expression
+    pub fn semantic_eq(&self, other: &NixValue) -> bool {
+        self.sorted() == other.sorted()
+    }
+
+    // Empreinte de l'arbre, compatible avec `semantic_eq` : deux arbres égaux à l'ordre
+    // des liaisons près ont la même empreinte. Les flottants sont pris par leurs bits
+    // (`-0.0` compté comme `0.0`), les commentaires sont ignorés
+    pub fn structural_hash(&self) -> u64 {
+        let mut state = Fnv1a::default();
+        self.sorted().hash_structure(&mut state);
+        state.finish()
+    }
+
+    fn hash_structure(&self, state: &mut impl Hasher) {
+        core::mem::discriminant(self).hash(state);
+        match self {
+            NixValue::Bool(b) => b.hash(state),
+            NixValue::Int(i) => i.hash(state),
+            NixValue::Float(fl) => (if *fl == 0.0 { 0.0 } else { *fl }).to_bits().hash(state),
+            NixValue::String(s) | NixValue::Variable(s) => s.hash(state),
+            NixValue::Path(kind, path) => (kind, path).hash(state),
+            NixValue::RawString(kind, _) => kind.hash(state),
+            NixValue::AttrSet(attrs) | NixValue::RecAttrSet(attrs) => {
+                attrs.keys().for_each(|key| key.to_string().hash(state));
+                attrs.inherited_names().for_each(|name| name.hash(state));
+            }
+            NixValue::Let(let_expr) => {
+                let_expr
+                    .bindings
+                    .keys()
+                    .for_each(|key| key.to_string().hash(state));
+                let_expr
+                    .bindings
+                    .inherited_names()
+                    .for_each(|name| name.hash(state));
+            }
+            NixValue::Function(func) => (&func.params, func.pattern, func.ellipsis).hash(state),
+            NixValue::BinaryOp(binary_op) => binary_op.op.hash(state),
+            NixValue::UnaryOp(unary_op) => unary_op.op.hash(state),
+            NixValue::Select(select) => select.path.iter().for_each(|k| k.to_string().hash(state)),
+            NixValue::HasAttr(has_attr) => {
+                has_attr.path.iter().for_each(|k| k.to_string().hash(state))
+            }
+            _ => {}
+        }
+        if let NixValue::InterpolatedString(parts)
+        | NixValue::IndentedString(parts)
+        | NixValue::RawString(_, parts)
+        | NixValue::InterpolatedPath(parts) = self
+        {
+            for part in parts {
+                if let StringPart::Literal(s) = part {
+                    s.hash(state);
+                }
+            }
+        }
+        self.for_each_child(&mut |child| child.hash_structure(state));
+    }
+
+    fn sort_keys_recursively(&mut self, include_let: bool) {
+        match self {
+            NixValue::AttrSet(attrs) | NixValue::RecAttrSet(attrs) => attrs.sort_keys(),
+            NixValue::Let(let_expr) if include_let => let_expr.bindings.sort_keys(),
+            _ => {}
+        }
+        self.for_each_child_mut(&mut |child| child.sort_keys_recursively(include_let));
+    }
+}
+
+// Réécritures de l'arbre
+impl NixValue {
+    // Copie où `f` remplace chaque nœud, de bas en haut : `f` reçoit un nœud dont les
+    // enfants sont déjà transformés
+    pub fn transform(&self, mut f: impl FnMut(NixValue) -> NixValue) -> NixValue {
+        let mut value = self.clone();
+        value.transform_in_place(&mut f);
+        value
+    }
+
+    fn transform_in_place<F: FnMut(NixValue) -> NixValue>(&mut self, f: &mut F) {
+        self.for_each_child_mut(&mut |child| child.transform_in_place(f));
+        let node = core::mem::replace(self, NixValue::Null);
+        *self = f(node);
+    }
+
+    // Copie où `f` réécrit le texte de chaque string littérale, y compris les morceaux
+    // littéraux des strings interpolées (les strings brutes gardent leur source)
+    pub fn map_strings(&self, f: impl Fn(&str) -> String) -> NixValue {
+        self.transform(|node| match node {
+            NixValue::String(s) => NixValue::String(f(&s)),
+            NixValue::InterpolatedString(parts) => {
+                NixValue::InterpolatedString(map_literal_parts(parts, &f))
+            }
+            NixValue::IndentedString(parts) => {
+                NixValue::IndentedString(map_literal_parts(parts, &f))
+            }
+            other => other,
+        })
+    }
+
+    // Copie où les chemins littéraux (`import` d'un chemin compris) sont normalisés sans
+    // accès au disque : `.` et les `/` répétés disparaissent, `..` remonte d'un segment
+    // quand il y en a un. `./foo/../bar` devient `./bar`, `/..` reste `/`, et chaque
+    // chemin garde sa forme (`<nixpkgs/a/..>` devient `<nixpkgs>`). Les chaînes, même
+    // importées, ne sont pas touchées
+    pub fn canonicalize_paths(&self) -> NixValue {
+        self.transform(|node| match node {
+            NixValue::Path(kind, path) => NixValue::Path(kind, canonical_path(kind, &path)),
+            other => other,
+        })
+    }
+}
+
+fn map_literal_parts(parts: Vec<StringPart>, f: &impl Fn(&str) -> String) -> Vec<StringPart> {
+    parts
+        .into_iter()
+        .map(|part| match part {
+            StringPart::Literal(s) => StringPart::Literal(f(&s)),
+            interpolation => interpolation,
+        })
+        .collect()
+}
+// Résolution lexicale de `.` et `..` dans un chemin de forme `kind` ; le premier segment
+// d'un chemin `~/...` ou `<...>` n'est jamais retiré
+fn canonical_path(kind: PathKind, path: &str) -> String {
+    let (root, rest) = match kind {
+        PathKind::Absolute => ("/", path),
+        PathKind::Relative => ("./", path),
+        PathKind::Home => ("~/", path.strip_prefix('~').unwrap_or(path)),
+        PathKind::SearchPath => path.split_once('/').unwrap_or((path, "")),
+    };
+    let mut segments: Vec<&str> = Vec::new();
+    for segment in rest.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." if segments.last().is_some_and(|last| *last != "..") => {
+                segments.pop();
+            }
+            // Rien au-dessus de la racine
+            ".." if kind == PathKind::Absolute => {}
+            segment => segments.push(segment),
+        }
+    }
+    let joined = segments.join("/");
+    match kind {
+        PathKind::SearchPath if joined.is_empty() => root.to_string(),
+        PathKind::SearchPath => format!("{}/{}", root, joined),
+        // `..` en tête s'écrit sans `./` ; un chemin Nix contient toujours un `/`
+        PathKind::Relative if segments.first() == Some(&"..") => match segments.len() {
+            1 => "../.".to_string(),
+            _ => joined,
+        },
+        _ if joined.is_empty() && kind != PathKind::Absolute => format!("{}.", root),
+        _ => format!("{}{}", root, joined),
+    }
+}
+
+// Noms rendus par `NixValue::free_variables` : un `HashSet` avec la fonctionnalité `std`,
+// un `BTreeSet` sans, faute de table de hachage dans `alloc`
+#[cfg(feature = "std")]
+pub type NameSet = std::collections::HashSet<String>;
+#[cfg(not(feature = "std"))]
+pub type NameSet = BTreeSet<String>;
+
+// Analyse des portées
+impl NixValue {
+    // Applique `f` à chaque sous-expression directe
+    fn for_each_child<'a>(&'a self, f: &mut impl FnMut(&'a NixValue)) {
+        match self {
+            NixValue::Null
+            | NixValue::Bool(_)
+            | NixValue::Int(_)
+            | NixValue::Float(_)
+            | NixValue::String(_)
+            | NixValue::Path(..)
+            | NixValue::Variable(_) => {}
+            NixValue::InterpolatedString(parts)
+            | NixValue::IndentedString(parts)
+            | NixValue::RawString(_, parts)
+            | NixValue::InterpolatedPath(parts) => {
+                for part in parts {
+                    if let StringPart::Interpolation(expr) = part {
+                        f(expr);
+                    }
+                }
+            }
+            NixValue::List(items) => items.iter().for_each(f),
+            NixValue::AttrSet(attrs) | NixValue::RecAttrSet(attrs) => {
+                attrs
+                    .inherits
+                    .iter()
+                    .filter_map(|i| i.from.as_ref())
+                    .for_each(&mut *f);
+                for entry in attrs.entries() {
+                    for key in entry.key.segments() {
+                        if let AttrKey::Dynamic(expr) = key {
+                            f(expr);
+                        }
+                    }
+                    f(&entry.value);
+                }
+            }
+            NixValue::Function(func) => f(&func.body),
+            NixValue::Let(let_expr) => {
+                let bindings = &let_expr.bindings;
+                bindings
+                    .inherits
+                    .iter()
+                    .filter_map(|i| i.from.as_ref())
+                    .for_each(&mut *f);
+                bindings.values().for_each(&mut *f);
+                f(&let_expr.body);
+            }
+            NixValue::With(with_expr) => {
+                f(&with_expr.expr);
+                f(&with_expr.body);
+            }
+            NixValue::Assert(assert_expr) => {
+                f(&assert_expr.cond);
+                f(&assert_expr.body);
+            }
+            NixValue::If(if_expr) => {
+                f(&if_expr.cond);
+                f(&if_expr.then_branch);
+                f(&if_expr.else_branch);
+            }
+            NixValue::BinaryOp(binary_op) => {
+                f(&binary_op.lhs);
+                f(&binary_op.rhs);
+            }
+            NixValue::UnaryOp(unary_op) => f(&unary_op.operand),
+            NixValue::Paren(inner) | NixValue::Import(inner) => f(inner),
+            NixValue::Select(select) => {
+                f(&select.expr);
+                for key in &select.path {
+                    if let AttrKey::Dynamic(expr) = key {
+                        f(expr);
+                    }
+                }
+                if let Some(default) = &select.default {
+                    f(default);
+                }
+            }
+            NixValue::HasAttr(has_attr) => {
+                f(&has_attr.expr);
+                for key in &has_attr.path {
+                    if let AttrKey::Dynamic(expr) = key {
+                        f(expr);
+                    }
+                }
+            }
+            NixValue::Apply(apply) => {
+                f(&apply.func);
+                f(&apply.arg);
+            }
+        }
+    }
+
+    // Sous-expressions directes, dans l'ordre du source (clés calculées comprises), pour
+    // un parcours manuel de l'arbre sans passer par un `Visitor`
+    pub fn children(&self) -> impl Iterator<Item = &NixValue> {
+        let mut children = Vec::new();
+        self.for_each_child(&mut |child| children.push(child));
+        children.into_iter()
+    }
+
+    // Variables référencées sans être liées par un `let`, un paramètre de fonction
+    // ou un `rec { ... }` englobant. `with` ne lie rien statiquement : les noms
+    // qu'il pourrait fournir sont donc rapportés comme libres.
+    pub fn free_variables(&self) -> NameSet {
+        let mut free = NameSet::new();
+        self.collect_free_variables(&mut Vec::new(), &mut free);
+        free
+    }
+
+    fn collect_free_variables<'a>(&'a self, bound: &mut Vec<&'a str>, free: &mut NameSet) {
+        let scope_len = bound.len();
+        match self {
+            NixValue::Variable(name) => {
+                if !bound.contains(&name.as_str()) {
+                    free.insert(name.clone());
+                }
+            }
+            NixValue::Function(func) => {
+                bound.extend(func.params.iter().map(String::as_str));
+                func.body.collect_free_variables(bound, free);
+            }
+            NixValue::Let(let_expr) => {
+                let bindings = &let_expr.bindings;
+                Self::collect_inherited_names(bindings, bound, free);
+                bound.extend(bindings.keys().filter_map(|path| path.head()?.as_static()));
+                bound.extend(bindings.inherited_names());
+                self.for_each_child(&mut |child| child.collect_free_variables(bound, free));
+            }
+            NixValue::RecAttrSet(attrs) => {
+                Self::collect_inherited_names(attrs, bound, free);
+                bound.extend(attrs.keys().filter_map(|path| path.head()?.as_static()));
+                bound.extend(attrs.inherited_names());
+                self.for_each_child(&mut |child| child.collect_free_variables(bound, free));
+            }
+            NixValue::AttrSet(attrs) => {
+                Self::collect_inherited_names(attrs, bound, free);
+                self.for_each_child(&mut |child| child.collect_free_variables(bound, free));
+            }
+            _ => self.for_each_child(&mut |child| child.collect_free_variables(bound, free)),
+        }
+        bound.truncate(scope_len);
+    }
+
+    // `inherit a;` lit `a` dans la portée englobante, avant les liaisons de l'ensemble
+    fn collect_inherited_names(attrs: &AttrSetMap, bound: &[&str], free: &mut NameSet) {
+        for inherit in attrs.inherits.iter().filter(|i| i.from.is_none()) {
+            for name in &inherit.names {
+                if !bound.contains(&name.as_str()) {
+                    free.insert(name.clone());
+                }
+            }
+        }
+    }
+}
+
+// Statistiques d'un arbre, pour mesurer la complexité d'une configuration
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NixStats {
+    // Nombre total de nœuds, racine comprise
+    pub nodes: usize,
+    // Ensembles d'attributs, `rec` compris
+    pub attrsets: usize,
+    pub lists: usize,
+    pub functions: usize,
+    pub lets: usize,
+    pub applications: usize,
+    // Strings de toute forme (simples, interpolées, indentées ou brutes)
+    pub strings: usize,
+    // Imbrication maximale, comme `NixValue::depth()`
+    pub max_depth: usize,
+}
+
+impl NixValue {
+    // Imbrication maximale : 0 pour une feuille, 1 pour `{ a = 1; }`, 2 pour `[ { a = 1; } ]`
+    pub fn depth(&self) -> usize {
+        let mut depth = 0;
+        self.for_each_child(&mut |child| depth = depth.max(child.depth() + 1));
+        depth
+    }
+
+    pub fn stats(&self) -> NixStats {
+        let mut stats = NixStats::default();
+        stats.max_depth = self.collect_stats(&mut stats);
+        stats
+    }
+
+    // Compte le nœud et ses descendants, et renvoie la profondeur du sous-arbre
+    fn collect_stats(&self, stats: &mut NixStats) -> usize {
+        stats.nodes += 1;
+        match self {
+            NixValue::AttrSet(_) | NixValue::RecAttrSet(_) => stats.attrsets += 1,
+            NixValue::List(_) => stats.lists += 1,
+            NixValue::Function(_) => stats.functions += 1,
+            NixValue::Let(_) => stats.lets += 1,
+            NixValue::Apply(_) => stats.applications += 1,
+            NixValue::String(_)
+            | NixValue::InterpolatedString(_)
+            | NixValue::IndentedString(_)
+            | NixValue::RawString(..) => stats.strings += 1,
+            _ => {}
+        }
+        let mut depth = 0;
+        self.for_each_child(&mut |child| depth = depth.max(child.collect_stats(stats) + 1));
+        depth
+    }
+}
+
+// Extrait lu par `NixParser::parse_fragment`, éventuellement encore incomplet
+#[derive(Debug, Clone, PartialEq)]
+pub enum NixFragment {
+    Complete(NixValue),
+    // `let x = 1;` tapé sans son `in` : les liaisons lues, sans corps
+    PartialLet(AttrSetMap),
+}
+
+impl NixFragment {
+    pub fn is_complete(&self) -> bool {
+        matches!(self, NixFragment::Complete(_))
+    }
+}
+
+// Position d'un nœud dans le source : octets `start..end`, blancs et commentaires exclus
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+// Positions d'un nœud et de ses enfants, dans l'ordre de `NixValue::children()`
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpanTree {
+    pub span: Span,
+    pub children: Vec<SpanTree>,
+}
+
+// Erreur de parsing avec contexte
+#[derive(Debug, Clone)]
+pub struct ParseError {
+    pub message: String,
+    pub line: usize,
+    // Colonne en caractères (une tabulation compte pour un), à partir de 1
+    pub col: usize,
+    // Colonne en octets UTF-8 depuis le début de la ligne, à partir de 1
+    pub byte_col: usize,
+    // Ligne de l'erreur (tronquée autour de la position) et `^` sous la colonne
+    pub context: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Erreur de parsing à la ligne {}, colonne {}:\n{}\nContexte:\n{}",
+            self.line, self.col, self.message, self.context
+        )
+    }
+}
+
+impl core::error::Error for ParseError {}
+
+// Erreur des fonctions qui lisent ou écrivent des fichiers
+#[cfg(feature = "std")]
+#[derive(Debug)]
+pub enum NixError {
+    Parse(ParseError),
+    Io(std::io::Error),
+}
+
+#[cfg(feature = "std")]
+impl fmt::Display for NixError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NixError::Parse(e) => write!(f, "{}", e),
+            NixError::Io(e) => write!(f, "Erreur d'entrée/sortie: {}", e),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for NixError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            NixError::Parse(e) => Some(e),
+            NixError::Io(e) => Some(e),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<ParseError> for NixError {
+    fn from(e: ParseError) -> Self {
+        NixError::Parse(e)
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<std::io::Error> for NixError {
+    fn from(e: std::io::Error) -> Self {
+        NixError::Io(e)
+    }
+}
+
+// Le type d'erreur par défaut est `NixError` ; `Result<T, ParseError>` reste possible
+#[cfg(feature = "std")]
+pub type Result<T, E = NixError> = core::result::Result<T, E>;
+
+// Erreur d'évaluation (`NixValue::eval`)
+#[derive(Debug, Clone, PartialEq)]
+pub enum EvalError {
+    // Construction hors du sous-ensemble évaluable : fonction, import, `with` inconnu...
+    Unsupported(String),
+    UndefinedVariable(String),
+    MissingAttribute(String),
+    TypeMismatch(String),
+    DivisionByZero,
+    IntegerOverflow,
+    AssertionFailed,
+    InfiniteRecursion,
+    IndexOutOfBounds(i64),
+}
+
+impl fmt::Display for EvalError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EvalError::Unsupported(what) => write!(f, "Évaluation non supportée: {}", what),
+            EvalError::UndefinedVariable(name) => write!(f, "Variable non définie: {}", name),
+            EvalError::MissingAttribute(name) => write!(f, "Attribut manquant: {}", name),
+            EvalError::TypeMismatch(msg) => write!(f, "Erreur de type: {}", msg),
+            EvalError::DivisionByZero => write!(f, "Division par zéro"),
+            EvalError::IntegerOverflow => write!(f, "Dépassement de capacité d'un entier"),
+            EvalError::AssertionFailed => write!(f, "Échec d'une assertion"),
+            EvalError::InfiniteRecursion => write!(f, "Récursion infinie"),
+            EvalError::IndexOutOfBounds(index) => write!(f, "Indice hors limites: {}", index),
+        }
+    }
+}
+
+impl core::error::Error for EvalError {}
+
+// Parser de fichiers Nix
+pub struct NixParser<'a> {
+    input: &'a str,
+    // Position en octets dans `input`, toujours sur une frontière de caractère
+    pos: usize,
+    line: usize,
+    col: usize,
+    keep_comments: bool,
+    keep_parens: bool,
+    // Imbrication courante, bornée par `max_depth` pour ne pas déborder la pile
+    depth: usize,
+    max_depth: usize,
+    // Fragments concaténés (`parse_nix_string_many`) : un littéral ne prend pas d'arguments
+    split_fragments: bool,
+    raw_strings: bool,
+    // Accepter `,` à la place de `;` entre les liaisons d'un ensemble
+    comma_separators: bool,
+    // Constructions tolérées, relevées pendant le parsing
+    warnings: Vec<Warning>,
+    // Sous-arbres de positions déjà fermés (seulement pendant `parse_with_spans`)
+    spans: Option<Vec<SpanTree>>,
+    // Fin du dernier lexème lu, et fin du dernier blanc ou commentaire sauté
+    token_end: usize,
+    trivia_end: usize,
+    // Erreurs déjà rencontrées (seulement pendant `parse_with_recovery`)
+    errors: Option<Vec<ParseError>>,
+    // `let` interrompus par la fin de l'entrée, avec leur position (seulement pendant
+    // `parse_fragment`)
+    partial_lets: Option<Vec<(usize, AttrSetMap)>>,
+}
+
+// Préfixe lu par `parse_prefixed`, en attente de son corps
+enum Prefix {
+    With(NixValue),
+    Assert(NixValue),
+    // Liaisons, et sous-arbres de positions des `inherit (src)` à placer en tête
+    Let(AttrSetMap, Vec<SpanTree>),
+}
+
+impl<'a> NixParser<'a> {
+    pub const DEFAULT_MAX_DEPTH: usize = 256;
+
+    pub fn new(input: &'a str) -> Self {
+        Self {
+            input,
+            pos: 0,
+            line: 1,
+            col: 1,
+            keep_comments: false,
+            keep_parens: false,
+            depth: 0,
+            max_depth: Self::DEFAULT_MAX_DEPTH,
+            split_fragments: false,
+            raw_strings: false,
+            comma_separators: false,
+            warnings: Vec::new(),
+            spans: None,
+            token_end: 0,
+            trivia_end: 0,
+            errors: None,
+            partial_lets: None,
+        }
+    }
+
+    // Conserver les commentaires des liaisons d'attributs dans l'arbre
+    pub fn with_comments(mut self, keep: bool) -> Self {
+        self.keep_comments = keep;
+        self
+    }
+
+    // Conserver les parenthèses sous forme de nœuds `Paren`, même redondantes ;
+    // par défaut elles sont retirées et le formatteur ne remet que les nécessaires
+    pub fn with_parens(mut self, keep: bool) -> Self {
+        self.keep_parens = keep;
+        self
+    }
+
+    // Garder les strings telles qu'écrites, séquences d'échappement et indentation
+    // comprises (`RawString`), pour qu'elles soient réécrites à l'octet près
+    pub fn with_raw_strings(mut self, raw: bool) -> Self {
+        self.raw_strings = raw;
+        self
+    }
+
+    // Accepter `{ a = 1, b = 2 }` (ou `let a = 1, b = 2 in b`), écrit par erreur par des
+    // générateurs habitués au JSON : chaque virgule utilisée comme séparateur, ou `;`
+    // manquant, est signalé dans `warnings()`
+    pub fn with_comma_separators(mut self, tolerate: bool) -> Self {
+        self.comma_separators = tolerate;
+        self
+    }
+
+    // Avertissements du parsing déjà effectué
+    pub fn warnings(&self) -> &[Warning] {
+        &self.warnings
+    }
+
+    // Profondeur d'imbrication maximale (expressions, listes, opérateurs préfixes) ;
+    // au-delà, le parsing échoue proprement au lieu de faire déborder la pile
+    pub fn with_max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    // Exécute `parse` un niveau d'imbrication plus bas
+    fn nested<T>(
+        &mut self,
+        parse: impl FnOnce(&mut Self) -> Result<T, ParseError>,
+    ) -> Result<T, ParseError> {
+        if self.depth >= self.max_depth {
+            return Err(self.error("Maximum nesting depth exceeded"));
+        }
+        self.depth += 1;
+        let result = parse(self);
+        self.depth -= 1;
+        result
+    }
+
+    // En mode reprise, note l'erreur d'une liaison ou d'un élément commencé à `start` et
+    // saute jusqu'au prochain séparateur `stop` ou jusqu'au fermant `closer` du bloc ;
+    // sinon, ou sans progrès possible, la renvoie
+    fn recover(
+        &mut self,
+        err: ParseError,
+        start: usize,
+        closer: Option<char>,
+        stop: impl Fn(char) -> bool,
+    ) -> Result<(), ParseError> {
+        if self.errors.is_none() {
+            return Err(err);
+        }
+        self.skip_until(closer, &stop);
+        if self.pos == start && !self.current().is_some_and(&stop) {
+            // Bloqué sur un fermant qui ne correspond à rien
+            return Err(err);
+        }
+        if let Some(errors) = &mut self.errors {
+            errors.push(err);
+        }
+        Ok(())
+    }
+
+    // Avance jusqu'au prochain caractère `stop` ou `closer` hors des parenthèses, crochets,
+    // accolades, strings et commentaires ; un autre fermant en trop est sauté
+    fn skip_until(&mut self, closer: Option<char>, stop: &impl Fn(char) -> bool) {
+        let mut depth = 0usize;
+        while let Some(c) = self.current() {
+            match c {
+                _ if depth == 0 && (stop(c) || Some(c) == closer) => break,
+                '(' | '[' | '{' => depth += 1,
+                ')' | ']' | '}' => depth = depth.saturating_sub(1),
+                '"' => {
+                    self.advance();
+                    while let Some(c) = self.current().filter(|&c| c != '"') {
+                        if c == '\\' {
+                            self.advance();
+                        }
+                        self.advance();
+                    }
+                }
+                '#' => {
+                    while self.current().is_some_and(|c| c != '\n') {
+                        self.advance();
+                    }
+                    continue;
+                }
+                _ => {}
+            }
+            self.advance();
+        }
+    }
+
+    fn current(&self) -> Option<char> {
+        self.input[self.pos..].chars().next()
+    }
+
+    // Caractère situé `offset` caractères après la position courante
+    fn peek_char(&self, offset: usize) -> Option<char> {
+        self.input[self.pos..].chars().nth(offset)
+    }
+
+    fn advance(&mut self) {
+        if let Some(c) = self.current() {
+            // `\r` seul termine aussi une ligne ; dans `\r\n`, seul le `\n` compte
+            if c == '\n' || (c == '\r' && self.peek_char(1) != Some('\n')) {
+                self.line += 1;
+                self.col = 1;
+            } else {
+                self.col += 1;
+            }
+            self.pos += c.len_utf8();
+        }
+    }
+
+    // Début (en octets) de la ligne contenant `pos`
+    fn line_start(&self, pos: usize) -> usize {
+        self.input[..pos].rfind(['\n', '\r']).map_or(0, |i| i + 1)
+    }
+
+    fn get_context(&self, pos: usize, range: usize) -> String {
+        let line_start = self.line_start(pos);
+        let line_end = self.input[pos..]
+            .find(['\n', '\r'])
+            .map_or(self.input.len(), |i| pos + i);
+        let before = &self.input[line_start..pos];
+        let start = before
+            .char_indices()
+            .rev()
+            .take(range)
+            .last()
+            .map_or(pos, |(i, _)| line_start + i);
+        let after = &self.input[pos..line_end];
+        let end = pos
+            + after
+                .char_indices()
+                .nth(range)
+                .map_or(after.len(), |(i, _)| i);
+        // Marge de la largeur affichée de chaque caractère, tabulations recopiées : `^`
+        // reste sous la colonne malgré les tabulations, accents combinants et idéogrammes
+        let padding: String = self.input[start..pos]
+            .chars()
+            .map(|c| {
+                if c == '\t' {
+                    "\t".to_string()
+                } else {
+                    " ".repeat(display_width(c))
+                }
+            })
+            .collect();
+        format!("{}\n{}^", &self.input[start..end], padding)
+    }
+
+    fn error(&self, msg: &str) -> ParseError {
+        self.error_at(msg, self.pos, self.line, self.col)
+    }
+
+    // Erreur située à une position antérieure (ex: délimiteur ouvrant jamais fermé)
+    fn error_at(&self, msg: &str, pos: usize, line: usize, col: usize) -> ParseError {
+        ParseError {
+            message: msg.to_string(),
+            line,
+            col,
+            byte_col: pos - self.line_start(pos) + 1,
+            context: self.get_context(pos, 30),
+        }
+    }
+
+    // Délimiteur fermant qui ne correspond pas à celui attendu (`[ 1 }`)
+    fn mismatched_delimiter(
+        &self,
+        expected: char,
+        what: &str,
+        open_line: usize,
+        open_col: usize,
+    ) -> Option<ParseError> {
+        let found = self
+            .current()
+            .filter(|c| matches!(c, ']' | '}' | ')') && *c != expected)?;
+        Some(self.error(&format!(
+            "Expected '{}' to close {} opened at line {}, col {}, found '{}'",
+            expected, what, open_line, open_col, found
+        )))
+    }
+
+    fn skip_whitespace(&mut self) {
+        self.skip_trivia(false);
+    }
+
+    // Comme skip_whitespace, mais renvoie les commentaires rencontrés si le parser les conserve
+    fn take_comments(&mut self) -> Vec<String> {
+        self.skip_trivia(self.keep_comments)
+    }
+
+    // Commentaire `#` placé sur la même ligne, juste après une liaison
+    fn take_inline_comment(&mut self) -> Option<String> {
+        if !self.keep_comments {
+            return None;
+        }
+        while matches!(self.current(), Some(' ') | Some('\t')) {
+            self.advance();
+        }
+        if self.current() != Some('#') {
+            return None;
+        }
+        let start = self.pos;
+        while self.current().is_some_and(|c| c != '\n' && c != '\r') {
+            self.advance();
+        }
+        Some(self.input[start..self.pos].trim_end().to_string())
+    }
+
+    fn skip_trivia(&mut self, collect: bool) -> Vec<String> {
+        if self.pos != self.trivia_end {
+            self.token_end = self.pos;
+        }
+        let mut comments = Vec::new();
+        while let Some(c) = self.current() {
+            if c.is_whitespace() {
+                self.advance();
+            } else if c == '#' {
+                // Commentaire ligne
+                let start = self.pos;
+                while let Some(ch) = self.current() {
+                    if ch == '\n' || ch == '\r' {
+                        break;
+                    }
+                    self.advance();
+                }
+                if collect {
+                    comments.push(self.input[start..self.pos].trim_end().to_string());
+                }
+            } else if self.peek_string("/*") {
+                // Commentaire bloc
+                let start = self.pos;
+                self.advance();
+                self.advance();
+                while !self.peek_string("*/") && self.current().is_some() {
+                    self.advance();
+                }
+                if self.peek_string("*/") {
+                    self.advance();
+                    self.advance();
+                }
+                if collect {
+                    comments.push(self.input[start..self.pos].to_string());
+                }
+            } else {
+                break;
+            }
+        }
+        self.trivia_end = self.pos;
+        comments
+    }
+
+    // Fin du dernier lexème lu, sans les blancs et commentaires qui le suivent
+    fn token_end(&self) -> usize {
+        if self.pos == self.trivia_end {
+            self.token_end
+        } else {
+            self.pos
+        }
+    }
+
+    // Nombre de sous-arbres de positions fermés, à relever avant de lire un nœud
+    fn span_mark(&self) -> usize {
+        self.spans.as_ref().map_or(0, Vec::len)
+    }
+
+    // Ferme le nœud qui vient d'être lu : ses enfants sont les sous-arbres fermés depuis
+    // `mark` ; sans `start`, il commence avec son premier enfant
+    fn close_span(&mut self, mark: usize, start: Option<usize>) {
+        let end = self.token_end();
+        if let Some(stack) = &mut self.spans {
+            let children = stack.split_off(mark);
+            let start = start
+                .or(children.first().map(|c| c.span.start))
+                .unwrap_or(end);
+            stack.push(SpanTree {
+                span: Span { start, end },
+                children,
+            });
+        }
+    }
+
+    // Retire les sous-arbres fermés depuis `mark` (nœuds absorbés ou abandonnés)
+    fn take_spans(&mut self, mark: usize) -> Vec<SpanTree> {
+        self.spans
+            .as_mut()
+            .map_or_else(Vec::new, |stack| stack.split_off(mark))
+    }
+
+    fn insert_spans(&mut self, at: usize, trees: Vec<SpanTree>) {
+        if let Some(stack) = &mut self.spans {
+            stack.splice(at..at, trees);
+        }
+    }
+
+    // Rouvre le dernier nœud fermé, pour le refermer autrement (`rec { }`, `let { }`)
+    fn reopen_span(&mut self) {
+        if let Some(tree) = self.spans.as_mut().and_then(Vec::pop) {
+            self.insert_spans(self.span_mark(), tree.children);
+        }
+    }
+
+    fn peek_string(&self, s: &str) -> bool {
+        self.input[self.pos..].starts_with(s)
+    }
+
+    // Longueur en caractères d'une URI `schéma:reste` à la position courante, selon
+    // la grammaire de Nix (le schéma ne doit être suivi d'aucun espace)
+    fn peek_uri_len(&self) -> Option<usize> {
+        let mut chars = self.input[self.pos..].chars();
+        if !chars.next()?.is_ascii_alphabetic() {
+            return None;
+        }
+        let mut len = 1;
+        loop {
+            match chars.next()? {
+                ':' => break,
+                c if c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.') => len += 1,
+                _ => return None,
+            }
+        }
+        let rest = chars
+            .take_while(|&c| c.is_ascii_alphanumeric() || "%/?:@&=+$,-_.!~*'".contains(c))
+            .count();
+        (rest > 0).then_some(len + 1 + rest)
+    }
+
+    // Longueur d'un chemin de recherche `<nixpkgs/lib>`, chevrons compris
+    fn peek_search_path_len(&self) -> Option<usize> {
+        let rest = self.input[self.pos..].strip_prefix('<')?;
+        let end = rest.find('>')?;
+        let inner = &rest[..end];
+        let valid = inner.split('/').all(|segment| {
+            !segment.is_empty()
+                && segment
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "._-+".contains(c))
+        });
+        valid.then_some(end + 2)
+    }
+
+    // Chemin de recherche `<nixpkgs/lib>`, résolu par Nix via NIX_PATH
+    fn parse_search_path(&mut self) -> Result<NixValue, ParseError> {
+        let len = self
+            .peek_search_path_len()
+            .ok_or_else(|| self.error("Expected search path like <nixpkgs>"))?;
+        let (start, mark) = (self.pos, self.span_mark());
+        let path = self.input[self.pos + 1..self.pos + len - 1].to_string();
+        for _ in 0..len {
+            self.advance();
+        }
+        self.close_span(mark, Some(start));
+        Ok(NixValue::Path(PathKind::SearchPath, path))
+    }
+
+    // Mot-clé entier à la position courante (`with` mais pas `without`)
+    fn peek_keyword(&self, keyword: &str) -> bool {
+        self.peek_identifier() == keyword
+    }
+
+    // Identifiant commençant à la position courante, sans le consommer
+    fn peek_identifier(&self) -> &str {
+        let rest = &self.input[self.pos..];
+        let end = rest
+            .find(|c: char| !(c.is_alphanumeric() || c == '_' || c == '-' || c == '\''))
+            .unwrap_or(rest.len());
+        &rest[..end]
+    }
+
+    // Comme dans Nix, un `-` qui suit un caractère d'identifiant en fait partie :
+    // `a-b` est l'identifiant `a-b`, alors que `a - b` et `a -b` sont des soustractions
+    // (un `-` en début d'opérande est une négation, voir `parse_unary`)
+    fn parse_identifier(&mut self) -> Result<String, ParseError> {
+        let mut id = String::new();
+        while let Some(c) = self.current() {
+            if c.is_alphanumeric() || c == '_' || c == '-' || c == '\'' {
+                id.push(c);
+                self.advance();
+            } else {
+                break;
+            }
+        }
+        if id.is_empty() {
+            Err(self.error("Expected identifier"))
+        } else {
+            Ok(id)
+        }
+    }
+
+    // `Path` si le chemin est constant, `InterpolatedPath` s'il contient des `${...}`
+    fn parse_path(&mut self) -> Result<NixValue, ParseError> {
+        let (start, mark) = (self.pos, self.span_mark());
+        let mut parts = Vec::new();
+        let mut path = String::new();
+
+        // Gérer les chemins relatifs, absolus et `~/...`
+        // Accepter: lettres, chiffres, _, -, /, ., et les antiquotations
+        if self.current() == Some('~') {
+            path.push('~');
+            self.advance();
+        }
+        while let Some(c) = self.current() {
+            match c {
+                'a'..='z' | 'A'..='Z' | '0'..='9' | '_' | '-' | '/' | '.' => {
+                    path.push(c);
+                    self.advance();
+                }
+                '$' if self.peek_char(1) == Some('{') => {
+                    if !path.is_empty() {
+                        parts.push(StringPart::Literal(core::mem::take(&mut path)));
+                    }
+                    parts.push(StringPart::Interpolation(self.parse_interpolation()?));
+                }
+                _ => break,
+            }
+        }
+
+        if path.is_empty() && parts.is_empty() {
+            return Err(self.error("Expected path"));
+        }
+        self.close_span(mark, Some(start));
+        if parts.is_empty() {
+            return Ok(NixValue::Path(PathKind::of(&path), path));
+        }
+        if !path.is_empty() {
+            parts.push(StringPart::Literal(path));
+        }
+        Ok(NixValue::InterpolatedPath(parts))
+    }
+
+    // Clé d'une liaison : chemin `a."b".${c}` dont chaque segment peut être calculé
+    fn parse_attr_key(&mut self) -> Result<AttrPath, ParseError> {
+        let mut path = vec![self.parse_attr_segment()?];
+        loop {
+            self.skip_whitespace();
+            if self.current() != Some('.') || self.peek_char(1) == Some('/') {
+                break;
+            }
+            self.advance(); // '.'
+            self.skip_whitespace();
+            path.push(self.parse_attr_segment()?);
+        }
+        Ok(AttrPath(path))
+    }
+
+    // Segment d'un chemin d'attributs : nom, nom entre guillemets ou clé calculée
+    fn parse_attr_segment(&mut self) -> Result<AttrKey, ParseError> {
+        if self.peek_string("${") {
+            Ok(AttrKey::Dynamic(self.parse_interpolation()?))
+        } else if self.current() == Some('"') {
+            let mark = self.span_mark();
+            match self.parse_string_value()? {
+                NixValue::String(s) => {
+                    // Une clé constante n'est pas un nœud de l'arbre
+                    self.take_spans(mark);
+                    Ok(AttrKey::Static(s))
+                }
+                interpolated => Ok(AttrKey::Dynamic(interpolated)),
+            }
+        } else if self.current() == Some('.') && self.peek_char(1) == Some('/') {
+            // C'est un chemin relatif, pas un accès d'attribut
+            Err(self.error("Path found where identifier expected"))
+        } else {
+            Ok(AttrKey::Static(self.parse_identifier()?))
+        }
+    }
+
+    // Antiquotation `${ expr }`
+    fn parse_interpolation(&mut self) -> Result<NixValue, ParseError> {
+        self.advance(); // '$'
+        self.advance(); // '{'
+        self.skip_whitespace();
+        if self.current() == Some('}') {
+            // `${}` est refusé par Nix : il faut une expression
+            return Err(self.error("Empty interpolation"));
+        }
+        let expr = self.parse_value()?;
+        self.skip_whitespace();
+        if self.current() != Some('}') {
+            return Err(self.error("Expected '}' to close interpolation"));
+        }
+        self.advance(); // '}'
+        Ok(expr)
+    }
+
+    // Chaîne littérale : `String` si elle est constante, `InterpolatedString` sinon ;
+    // une string multi-lignes `''...''` reste une `IndentedString`
+    fn parse_string_value(&mut self) -> Result<NixValue, ParseError> {
+        let (start, mark) = (self.pos, self.span_mark());
+        let indented = self.peek_string("''");
+        let parts = self.parse_string_parts(false)?;
+        self.close_span(mark, Some(start));
+        if indented {
+            Ok(NixValue::IndentedString(parts))
+        } else if parts.iter().all(|p| matches!(p, StringPart::Literal(_))) {
+            let s = parts
+                .into_iter()
+                .map(|p| match p {
+                    StringPart::Literal(s) => s,
+                    StringPart::Interpolation(_) => unreachable!(),
+                })
+                .collect();
+            Ok(NixValue::String(s))
+        } else {
+            Ok(NixValue::InterpolatedString(parts))
+        }
+    }
+
+    // Avec `raw`, les littéraux gardent leur texte source : ni échappement décodé,
+    // ni indentation retirée
+    fn parse_string_parts(&mut self, raw: bool) -> Result<Vec<StringPart>, ParseError> {
+        let quote = self.current().ok_or_else(|| self.error("Expected quote"))?;
+        let mut parts = Vec::new();
+        let mut s = String::new();
+
+        // Gérer les strings multi-lignes ''...''
+        if quote == '\'' && self.peek_char(1) == Some('\'') {
+            self.advance(); // première '
+            self.advance(); // deuxième '
+
+            // Une première ligne faite seulement d'espaces est ignorée
+            let first_line = &self.input[self.pos..];
+            let spaces = first_line.len() - first_line.trim_start_matches(' ').len();
+            if !raw && first_line[spaces..].starts_with('\n') {
+                for _ in 0..=spaces {
+                    self.advance();
+                }
+            }
+
+            let mut pieces = Vec::new();
+            while let Some(c) = self.current() {
+                if c == '\'' && self.peek_char(1) == Some('\'') {
+                    let (escaped, len) = match self.peek_char(2) {
+                        // ''' -> '' et ''$ -> $
+                        Some('\'') => ("''".to_string(), 3),
+                        Some('$') => ("$".to_string(), 3),
+                        // ''\x -> échappement comme dans une string normale
+                        Some('\\') => {
+                            let escaped = self
+                                .peek_char(3)
+                                .ok_or_else(|| self.error("Unterminated multi-line string"))?;
+                            (unescape_char(escaped).to_string(), 4)
+                        }
+                        _ => {
+                            self.advance();
+                            self.advance();
+                            if !s.is_empty() {
+                                pieces.push(IndentedPiece::Text(s));
+                            }
+                            if raw {
+                                let parts = pieces.into_iter().map(IndentedPiece::into_raw_part);
+                                return Ok(parts.collect());
+                            }
+                            return Ok(strip_indentation(pieces));
+                        }
+                    };
+                    if raw {
+                        // Séquence recopiée telle quelle dans le texte
+                        let start = self.pos;
+                        for _ in 0..len {
+                            self.advance();
+                        }
+                        s.push_str(&self.input[start..self.pos]);
+                        continue;
+                    }
+                    if !s.is_empty() {
+                        pieces.push(IndentedPiece::Text(core::mem::take(&mut s)));
+                    }
+                    pieces.push(IndentedPiece::Escaped(escaped));
+                    for _ in 0..len {
+                        self.advance();
+                    }
+                } else if c == '$' && self.peek_char(1) == Some('$') {
+                    // `$${` est littéral : le second `$` n'ouvre pas d'interpolation
+                    s.push_str("$$");
+                    self.advance();
+                    self.advance();
+                } else if c == '$' && self.peek_char(1) == Some('{') {
+                    if !s.is_empty() {
+                        pieces.push(IndentedPiece::Text(core::mem::take(&mut s)));
+                    }
+                    pieces.push(IndentedPiece::Interpolation(self.parse_interpolation()?));
+                } else {
+                    s.push(c);
+                    self.advance();
+                }
+            }
+            return Err(self.error("Unterminated multi-line string"));
+        }
+
+        // String normale
+        self.advance();
+
+        let mut escaped = false;
+
+        while let Some(c) = self.current() {
+            if escaped {
+                if raw {
+                    s.push('\\');
+                    s.push(c);
+                } else {
+                    s.push(unescape_char(c));
+                }
+                escaped = false;
+                self.advance();
+            } else if c == '\\' {
+                escaped = true;
+                self.advance();
+            } else if c == quote {
+                self.advance();
+                if !s.is_empty() {
+                    parts.push(StringPart::Literal(s));
+                }
+                return Ok(parts);
+            } else if c == '$' && self.peek_char(1) == Some('$') {
+                // Comme `\${`, `$${` est littéral
+                s.push_str("$$");
+                self.advance();
+                self.advance();
+            } else if c == '$' && self.peek_char(1) == Some('{') {
+                if !s.is_empty() {
+                    parts.push(StringPart::Literal(core::mem::take(&mut s)));
+                }
+                parts.push(StringPart::Interpolation(self.parse_interpolation()?));
+            } else {
+                s.push(c);
+                self.advance();
+            }
+        }
+        Err(self.error("Unterminated string"))
+    }
+
+    // Littéral numérique à la position courante : `3`, `.5`, `-3` ou `-.5`
+    fn at_number_start(&self) -> bool {
+        let offset = usize::from(self.current() == Some('-'));
+        match self.peek_char(offset) {
+            Some(c) if c.is_ascii_digit() => true,
+            Some('.') => self
+                .peek_char(offset + 1)
+                .is_some_and(|c| c.is_ascii_digit()),
+            _ => false,
+        }
+    }
+
+    fn parse_number(&mut self) -> Result<NixValue, ParseError> {
+        let (start, mark) = (self.pos, self.span_mark());
+        let value = self.parse_number_literal()?;
+        self.close_span(mark, Some(start));
+        Ok(value)
+    }
+
+    fn parse_number_literal(&mut self) -> Result<NixValue, ParseError> {
+        let mut num = String::new();
+        let mut is_float = false;
+
+        while let Some(c) = self.current() {
+            // Un `-` n'est un signe qu'en tête : `1-2` est une soustraction
+            if c.is_ascii_digit() || c == '.' || (c == '-' && num.is_empty()) {
+                if c == '.' {
+                    is_float = true;
+                }
+                num.push(c);
+                self.advance();
+            } else if is_float && (c == 'e' || c == 'E') {
+                // Exposant d'un flottant : `1.5e3`, `2.0E-2`
+                let sign = usize::from(matches!(self.peek_char(1), Some('+') | Some('-')));
+                if !self.peek_char(1 + sign).is_some_and(|c| c.is_ascii_digit()) {
+                    break;
+                }
+                for _ in 0..=sign {
+                    num.push(self.current().unwrap_or_default());
+                    self.advance();
+                }
+            } else {
+                break;
+            }
+        }
+
+        if is_float {
+            num.parse::<f64>()
+                .map(NixValue::Float)
+                .map_err(|_| self.error("Invalid float"))
+        } else {
+            num.parse::<i64>()
+                .map(NixValue::Int)
+                .map_err(|_| self.error("Invalid integer"))
+        }
+    }
+
+    fn parse_list(&mut self) -> Result<NixValue, ParseError> {
+        let (open_pos, open_line, open_col) = (self.pos, self.line, self.col);
+        let mark = self.span_mark();
+        self.advance(); // '['
+        self.skip_whitespace();
+
+        let mut items = Vec::new();
+        while self.current() != Some(']') && self.current().is_some() {
+            if let Some(err) = self.mismatched_delimiter(']', "list", open_line, open_col) {
+                return Err(err);
+            }
+            // Les éléments d'une liste sont des opérandes : `[ 1 -2 ]` contient deux éléments
+            let start = self.pos;
+            match self.nested(Self::parse_list_item) {
+                Ok(item) => items.push(item),
+                Err(err) => self.recover(err, start, Some(']'), char::is_whitespace)?,
+            }
+            self.skip_whitespace();
+        }
+
+        if self.current() != Some(']') {
+            return Err(self.error_at(
+                "Unterminated list, expected ']'",
+                open_pos,
+                open_line,
+                open_col,
+            ));
+        }
+        self.advance(); // ']'
+        self.close_span(mark, Some(open_pos));
+        Ok(NixValue::List(items))
+    }
+
+    // Opérande, éventuellement nié : dans `[ - 3 ]`, le `-` détaché porte sur l'élément
+    fn parse_list_item(&mut self) -> Result<NixValue, ParseError> {
+        if self.current() == Some('-') && !self.at_number_start() {
+            let (start, mark) = (self.pos, self.span_mark());
+            self.advance();
+            self.skip_whitespace();
+            let operand = self.nested(Self::parse_list_item)?;
+            self.close_span(mark, Some(start));
+            return Ok(NixValue::UnaryOp(Box::new(NixUnaryOp {
+                op: UnOp::Neg,
+                operand,
+            })));
+        }
+        self.parse_select()
+    }
+
+    fn parse_attrset(&mut self) -> Result<NixValue, ParseError> {
+        let (open_pos, open_line, open_col) = (self.pos, self.line, self.col);
+        let mark = self.span_mark();
+        self.advance(); // '{'
+        let mut comments = self.take_comments();
+
+        let mut attrs = AttrSetMap::new();
+        // Les sources d'`inherit` passent avant les entrées, comme dans `children()`
+        let mut inherit_spans = Vec::new();
+
+        while self.current() != Some('}') && self.current().is_some() {
+            let mismatched = self.mismatched_delimiter('}', "attribute set", open_line, open_col);
+            if let Some(err) = mismatched {
+                return Err(err);
+            }
+
+            // Gérer 'inherit'
+            if self.peek_keyword("inherit") {
+                let inherit_mark = self.span_mark();
+                let mut inherit = self.parse_inherit()?;
+                inherit_spans.extend(self.take_spans(inherit_mark));
+                inherit.comments = core::mem::take(&mut comments);
+                attrs.inherits.push(inherit);
+                comments = self.take_comments();
+                continue;
+            }
+
+            let start = self.pos;
+            let (key, value) = match self.parse_attr_binding() {
+                Ok(binding) => binding,
+                Err(err) => {
+                    self.recover(err, start, Some('}'), |c| c == ';')?;
+                    if self.current() == Some(';') {
+                        self.advance();
+                    }
+                    comments = self.take_comments();
+                    continue;
+                }
+            };
+
+            self.skip_whitespace();
+            let mut inline_comment = None;
+            if self.current() == Some(';') {
+                self.advance();
+                inline_comment = self.take_inline_comment();
+            } else if self.comma_separators && self.current() == Some(',') {
+                let name = key.to_string();
+                self.warnings.push(Warning {
+                    kind: WarningKind::CommaSeparator,
+                    name,
+                });
+                self.advance();
+                inline_comment = self.take_inline_comment();
+            } else if self.comma_separators {
+                let name = key.to_string();
+                self.warnings.push(Warning {
+                    kind: WarningKind::MissingSeparator,
+                    name,
+                });
+            } else if let Some(err) =
+                self.mismatched_delimiter('}', "attribute set", open_line, open_col)
+            {
+                return Err(err);
+            } else {
+                // La liaison est gardée : seul le séparateur manque
+                let err = self.error(&format!("Expected ';' after attribute '{}'", key));
+                match &mut self.errors {
+                    Some(errors) => errors.push(err),
+                    None => return Err(err),
+                }
+            }
+
+            attrs.push_entry(AttrEntry {
+                key,
+                value,
+                comments: core::mem::take(&mut comments),
+                inline_comment,
+            });
+            comments = self.take_comments();
+        }
+        attrs.trailing_comments = comments;
+
+        if self.current() != Some('}') {
+            return Err(self.error_at(
+                "Unterminated attribute set, expected '}'",
+                open_pos,
+                open_line,
+                open_col,
+            ));
+        }
+        self.advance(); // '}'
+        self.insert_spans(mark, inherit_spans);
+        self.close_span(mark, Some(open_pos));
+        Ok(NixValue::AttrSet(attrs))
+    }
+
+    // `key = value` d'un ensemble, sans le `;`
+    fn parse_attr_binding(&mut self) -> Result<(AttrPath, NixValue), ParseError> {
+        // Parser la clé (peut être un chemin d'attributs comme "services.udev.extraRules")
+        let key = self.parse_attr_key()?;
+
+        self.skip_whitespace();
+
+        if self.current() != Some('=') {
+            return Err(self.error(&format!(
+                "Expected '=' after key '{}', found {:?}",
+                key,
+                self.current()
+            )));
+        }
+        self.advance();
+        self.skip_whitespace();
+
+        let value = self.parse_value()?;
+        Ok((key, value))
+    }
+
+    // `inherit a "b";` ou `inherit (src) a;`, le mot-clé étant à la position courante
+    fn parse_inherit(&mut self) -> Result<NixInherit, ParseError> {
+        let (start_pos, start_line, start_col) = (self.pos, self.line, self.col);
+        for _ in 0..7 {
+            self.advance();
+        }
+        self.skip_whitespace();
+
+        let mut from = None;
+        if self.current() == Some('(') {
+            self.advance(); // '('
+            from = Some(self.parse_value()?);
+            self.skip_whitespace();
+            if self.current() != Some(')') {
+                return Err(self.error("Expected ')' after inherit source"));
+            }
+            self.advance(); // ')'
+            self.skip_whitespace();
+        }
+
+        let mut names = Vec::new();
+        while self.current() != Some(';') {
+            if self.current().is_none() {
+                let msg = "Unterminated inherit, expected ';'";
+                return Err(self.error_at(msg, start_pos, start_line, start_col));
+            }
+            match self.parse_attr_segment()? {
+                AttrKey::Static(name) => names.push(name),
+                AttrKey::Dynamic(_) => {
+                    return Err(self.error("Dynamic attributes are not allowed in inherit"));
+                }
+            }
+            self.skip_whitespace();
+        }
+        self.advance(); // ';'
+
+        Ok(NixInherit::new(from, names))
+    }
+
+    // Ensemble récursif `rec { ... }` ; renvoie None (sans consommer) si ce n'en est pas un
+    fn parse_rec_attrset(&mut self) -> Result<Option<NixValue>, ParseError> {
+        if !self.peek_keyword("rec") {
+            return Ok(None);
+        }
+
+        let saved_pos = self.pos;
+        let saved_line = self.line;
+        let saved_col = self.col;
+
+        for _ in 0..3 {
+            self.advance();
+        }
+        self.skip_whitespace();
+
+        if self.current() != Some('{') {
+            self.pos = saved_pos;
+            self.line = saved_line;
+            self.col = saved_col;
+            return Ok(None);
+        }
+
+        let mark = self.span_mark();
+        let attrs = self.parse_attrset()?;
+        self.reopen_span();
+        self.close_span(mark, Some(saved_pos));
+        match attrs {
+            NixValue::AttrSet(attrs) => Ok(Some(NixValue::RecAttrSet(attrs))),
+            other => Ok(Some(other)),
+        }
+    }
+
+    // Paramètres `{ a, b, ... }`, présence de `...`, et si une virgule ou `...` prouve
+    // que c'est un motif
+    fn parse_function_params(&mut self) -> Result<(Vec<String>, bool, bool), ParseError> {
+        self.advance(); // '{'
+        self.skip_whitespace();
+
+        let mut params = Vec::new();
+        let mut ellipsis = false;
+        let mut is_pattern = false;
+
+        while self.current() != Some('}') && self.current().is_some() {
+            // Gérer le '...' qui termine les paramètres
+            if self.peek_string("...") {
+                self.advance();
+                self.advance();
+                self.advance();
+                self.skip_whitespace();
+                ellipsis = true;
+                is_pattern = true;
+
+                // Peut avoir une virgule après ...
+                if self.current() == Some(',') {
+                    self.advance();
+                    self.skip_whitespace();
+                }
+
+                // Si on trouve '}', on sort
+                if self.current() == Some('}') {
+                    break;
+                }
+                continue;
+            }
+
+            let param = self.parse_identifier()?;
+            params.push(param);
+            self.skip_whitespace();
+
+            // Gérer la virgule
+            if self.current() == Some(',') {
+                self.advance();
+                self.skip_whitespace();
+                is_pattern = true;
+            }
+        }
+
+        if self.current() == Some('}') {
+            self.advance(); // '}'
+        }
+        Ok((params, ellipsis, is_pattern))
+    }
+
+    // `let ... in` sans son corps ; la forme historique `let { }`, complète, arrête la chaîne
+    fn parse_let(&mut self, mark: usize) -> Result<ControlFlow<NixValue, Prefix>, ParseError> {
+        let (let_pos, let_line, let_col) = (self.pos, self.line, self.col);
+        for _ in 0..3 {
+            self.advance();
+        } // "let"
+        self.skip_whitespace();
+
+        // Forme historique `let { a = 1; body = a; }`, équivalente à `let a = 1; in a`
+        if self.current() == Some('{') {
+            let NixValue::AttrSet(mut bindings) = self.parse_attrset()? else {
+                unreachable!()
+            };
+            if self.spans.is_some() {
+                self.move_body_span(mark, &bindings);
+            }
+            let Some(body) = bindings.remove("body") else {
+                return Err(self.error_at(
+                    "Legacy 'let { ... }' requires a 'body' attribute",
+                    let_pos,
+                    let_line,
+                    let_col,
+                ));
+            };
+            self.close_span(mark, Some(let_pos));
+            return Ok(ControlFlow::Break(NixValue::Let(Box::new(NixLet {
+                bindings,
+                body,
+            }))));
+        }
+
+        // Aucune liaison : `let in body` est valide
+        let mut bindings = AttrSetMap::new();
+        let mut inherit_spans = Vec::new();
+
+        while !self.peek_keyword("in") && self.current().is_some() {
+            if self.peek_keyword("inherit") {
+                let inherit_mark = self.span_mark();
+                let inherit = self.parse_inherit()?;
+                inherit_spans.extend(self.take_spans(inherit_mark));
+                bindings.inherits.push(inherit);
+                self.skip_whitespace();
+                continue;
+            }
+
+            let start = self.pos;
+            let key = match self.parse_let_binding() {
+                // Une liaison répétée est gardée, pour que `validate` la signale
+                Ok((key, value)) => {
+                    bindings.push_entry(AttrEntry::new(key.clone(), value));
+                    Some(key)
+                }
+                Err(err) => {
+                    self.recover(err, start, None, |c| c == ';')?;
+                    None
+                }
+            };
+
+            // Même règle que dans un ensemble : `;` obligatoire, sauf en mode tolérant
+            self.skip_whitespace();
+            if self.current() == Some(';') {
+                self.advance();
+            } else if let Some(name) = key {
+                if self.comma_separators && self.current() == Some(',') {
+                    self.warnings.push(Warning {
+                        kind: WarningKind::CommaSeparator,
+                        name,
+                    });
+                    self.advance();
+                } else if self.comma_separators {
+                    self.warnings.push(Warning {
+                        kind: WarningKind::MissingSeparator,
+                        name,
+                    });
+                } else {
+                    let err = self.error(&format!("Expected ';' after let binding '{}'", name));
+                    match &mut self.errors {
+                        Some(errors) => errors.push(err),
+                        None => return Err(err),
+                    }
+                }
+            }
+            self.skip_whitespace();
+        }
+
+        if !self.peek_keyword("in") {
+            if let Some(partial_lets) = &mut self.partial_lets {
+                partial_lets.push((let_pos, bindings));
+            }
+            let msg = "Expected 'in' after let bindings";
+            return Err(self.error_at(msg, let_pos, let_line, let_col));
+        }
+        for _ in 0..2 {
+            self.advance();
+        } // "in"
+        Ok(ControlFlow::Continue(Prefix::Let(bindings, inherit_spans)))
+    }
+
+    fn parse_let_binding(&mut self) -> Result<(String, NixValue), ParseError> {
+        let key = self.parse_identifier()?;
+        self.skip_whitespace();
+
+        if self.current() != Some('=') {
+            return Err(self.error("Expected '=' in let binding"));
+        }
+        self.advance();
+        self.skip_whitespace();
+
+        let value = self.parse_value()?;
+        Ok((key, value))
+    }
+
+    // Dans `let { ... }`, l'attribut `body` devient le corps : son sous-arbre de positions
+    // passe après ceux des liaisons
+    fn move_body_span(&mut self, mark: usize, bindings: &AttrSetMap) {
+        self.reopen_span();
+        let Some(stack) = &mut self.spans else {
+            return;
+        };
+        let mut index = mark
+            + bindings
+                .inherits
+                .iter()
+                .filter(|i| i.from.is_some())
+                .count();
+        for entry in bindings.entries() {
+            if entry.key == *"body" {
+                let body = stack.remove(index);
+                stack.push(body);
+                return;
+            }
+            let segments = entry.key.segments().iter();
+            index += segments
+                .filter(|k| matches!(k, AttrKey::Dynamic(_)))
+                .count()
+                + 1;
+        }
+    }
+
+    // `with e;`, `assert c;` et `let ... in` en tête d'expression. Une chaîne de préfixes
+    // (`with a; with b; assert c; corps`) est lue en boucle puis imbriquée de l'intérieur
+    // vers l'extérieur : la pile ne grandit pas avec la longueur de la chaîne
+    fn parse_prefixed(&mut self) -> Result<NixValue, ParseError> {
+        let mut prefixes = Vec::new();
+        let mut body = loop {
+            self.skip_whitespace();
+            let (start, mark) = (self.pos, self.span_mark());
+            let prefix = if self.peek_keyword("with") {
+                let msg = "Expected ';' after with expression";
+                Prefix::With(self.parse_prefix_expr("with", msg)?)
+            } else if self.peek_keyword("assert") {
+                let msg = "Expected ';' after assert condition";
+                Prefix::Assert(self.parse_prefix_expr("assert", msg)?)
+            } else if self.peek_keyword("let") {
+                match self.parse_let(mark)? {
+                    ControlFlow::Continue(prefix) => prefix,
+                    ControlFlow::Break(legacy) => break legacy,
+                }
+            } else {
+                // Le corps s'étend jusqu'à la fin de l'expression (priorité la plus basse)
+                break self.parse_value_inner()?;
+            };
+            prefixes.push((start, mark, prefix));
+        };
+
+        while let Some((start, mark, prefix)) = prefixes.pop() {
+            body = match prefix {
+                Prefix::With(expr) => NixValue::With(Box::new(NixWith { expr, body })),
+                Prefix::Assert(cond) => NixValue::Assert(Box::new(NixAssert { cond, body })),
+                Prefix::Let(bindings, inherit_spans) => {
+                    self.insert_spans(mark, inherit_spans);
+                    NixValue::Let(Box::new(NixLet { bindings, body }))
+                }
+            };
+            self.close_span(mark, Some(start));
+        }
+        Ok(body)
+    }
+
+    // Mot-clé `with` ou `assert`, puis son expression terminée par `;`
+    fn parse_prefix_expr(&mut self, keyword: &str, msg: &str) -> Result<NixValue, ParseError> {
+        for _ in 0..keyword.len() {
+            self.advance();
+        }
+        self.skip_whitespace();
+
+        let expr = self.parse_value()?;
+        self.skip_whitespace();
+
+        if self.current() != Some(';') {
+            return Err(self.error(msg));
+        }
+        self.advance();
+        Ok(expr)
+    }
+
+    fn parse_if(&mut self) -> Result<NixValue, ParseError> {
+        let (start, mark) = (self.pos, self.span_mark());
+        self.advance();
+        self.advance(); // "if"
+        self.skip_whitespace();
+
+        let cond = self.parse_value()?;
+        self.skip_whitespace();
+
+        if !self.peek_keyword("then") {
+            return Err(self.error("Expected 'then' after if condition"));
+        }
+        for _ in 0..4 {
+            self.advance();
+        }
+        let then_branch = self.parse_value()?;
+        self.skip_whitespace();
+
+        if !self.peek_keyword("else") {
+            return Err(self.error("Expected 'else' after then branch"));
+        }
+        for _ in 0..4 {
+            self.advance();
+        }
+        let else_branch = self.parse_value()?;
+
+        self.close_span(mark, Some(start));
+        Ok(NixValue::If(Box::new(NixIf {
+            cond,
+            then_branch,
+            else_branch,
+        })))
+    }
+
+    fn peek_binary_op(&self) -> Option<(BinOp, usize)> {
+        let c = self.current()?;
+        let next = self.peek_char(1);
+        let op = match (c, next) {
+            ('/', Some('/')) => (BinOp::Update, 2),
+            ('+', Some('+')) => (BinOp::Concat, 2),
+            ('=', Some('=')) => (BinOp::Eq, 2),
+            ('!', Some('=')) => (BinOp::NotEq, 2),
+            ('<', Some('=')) => (BinOp::LessEq, 2),
+            ('>', Some('=')) => (BinOp::GreaterEq, 2),
+            ('&', Some('&')) => (BinOp::And, 2),
+            ('|', Some('|')) => (BinOp::Or, 2),
+            ('-', Some('>')) => (BinOp::Implies, 2),
+            ('+', _) => (BinOp::Add, 1),
+            ('-', _) => (BinOp::Sub, 1),
+            ('*', _) => (BinOp::Mul, 1),
+            // Sans espace après, '/' commence un chemin et non une division
+            ('/', Some(n)) if n.is_whitespace() => (BinOp::Div, 1),
+            ('<', _) => (BinOp::Less, 1),
+            ('>', _) => (BinOp::Greater, 1),
+            _ => return None,
+        };
+        Some(op)
+    }
+
+    // Parsing des opérateurs binaires par remontée de priorité
+    fn parse_binary(&mut self, min_prec: u8) -> Result<NixValue, ParseError> {
+        let mark = self.span_mark();
+        let mut lhs = self.parse_unary()?;
+        // Dernier opérateur non associatif de ce niveau, pour refuser `a < b < c`
+        let mut non_assoc: Option<BinOp> = None;
+
+        loop {
+            self.skip_whitespace();
+            if self.current() == Some('?') {
+                if NixValue::HAS_ATTR_PRECEDENCE < min_prec {
+                    break;
+                }
+                lhs = self.parse_has_attr(lhs)?;
+                self.close_span(mark, None);
+                continue;
+            }
+            let Some((op, len)) = self.peek_binary_op() else {
+                break;
+            };
+            let prec = op.precedence();
+            if prec < min_prec {
+                break;
+            }
+            if let Some(prev) =
+                non_assoc.filter(|prev| op.is_non_assoc() && prev.precedence() == prec)
+            {
+                return Err(self.error(&format!(
+                    "Operator '{}' cannot follow '{}' without parentheses",
+                    op.as_str(),
+                    prev.as_str()
+                )));
+            }
+            non_assoc = op.is_non_assoc().then_some(op);
+
+            for _ in 0..len {
+                self.advance();
+            }
+            self.skip_whitespace();
+
+            let next_min = if op.is_right_assoc() { prec } else { prec + 1 };
+            let rhs = self.parse_binary(next_min)?;
+            lhs = NixValue::BinaryOp(Box::new(NixBinaryOp { op, lhs, rhs }));
+            self.close_span(mark, None);
+        }
+
+        Ok(lhs)
+    }
+
+    // `expr ? a.b`, le `?` étant le caractère courant
+    fn parse_has_attr(&mut self, expr: NixValue) -> Result<NixValue, ParseError> {
+        self.advance(); // '?'
+        self.skip_whitespace();
+
+        let mut path = vec![self.parse_attr_segment()?];
+        loop {
+            self.skip_whitespace();
+            if self.current() != Some('.') {
+                break;
+            }
+            self.advance(); // '.'
+            self.skip_whitespace();
+            path.push(self.parse_attr_segment()?);
+        }
+
+        Ok(NixValue::HasAttr(Box::new(NixHasAttr { expr, path })))
+    }
+
+    // `!x` et `-x` ; un littéral `-3` collé au chiffre reste un nombre
+    fn parse_unary(&mut self) -> Result<NixValue, ParseError> {
+        let op = match self.current() {
+            Some('!') => UnOp::Not,
+            Some('-') if !self.at_number_start() => UnOp::Neg,
+            _ => return self.parse_application(),
+        };
+        let (start, mark) = (self.pos, self.span_mark());
+        self.advance();
+        self.skip_whitespace();
+
+        let operand = self.nested(|p| p.parse_binary(op.precedence() + 1))?;
+        self.close_span(mark, Some(start));
+        Ok(NixValue::UnaryOp(Box::new(NixUnaryOp { op, operand })))
+    }
+
+    fn parse_value(&mut self) -> Result<NixValue, ParseError> {
+        self.nested(Self::parse_value_inner)
+    }
+
+    fn parse_value_inner(&mut self) -> Result<NixValue, ParseError> {
+        self.skip_whitespace();
+
+        // Détecter une fonction avec pattern { param1, param2, ... }:
+        if self.current() == Some('{') {
+            let saved_pos = self.pos;
+            let saved_line = self.line;
+            let saved_col = self.col;
+            let mark = self.span_mark();
+
+            // Essayer de parser comme paramètres de fonction
+            if let Ok((params, ellipsis, is_pattern)) = self.parse_function_params() {
+                self.skip_whitespace();
+
+                // Vérifier si c'est suivi de ':' pour confirmer que c'est une fonction
+                if self.current() == Some(':') {
+                    self.advance(); // ':'
+                    self.skip_whitespace();
+
+                    let body = self.parse_value()?;
+                    self.close_span(mark, Some(saved_pos));
+                    let func = NixFunction {
+                        params,
+                        pattern: true,
+                        ellipsis,
+                        body,
+                    };
+                    return Ok(NixValue::Function(Box::new(func)));
+                }
+
+                // `{ a, b }` ou `{ ... }` ne peuvent pas être des ensembles d'attributs
+                if is_pattern {
+                    return Err(self.error("Expected ':' after function pattern"));
+                }
+            }
+
+            // Si ce n'est pas une fonction, revenir en arrière et parser comme attrset
+            self.pos = saved_pos;
+            self.line = saved_line;
+            self.col = saved_col;
+            return self.parse_binary(0);
+        }
+
+        if self.peek_keyword("let") || self.peek_keyword("with") || self.peek_keyword("assert") {
+            return self.parse_prefixed();
+        }
+
+        if self.peek_keyword("if") {
+            return self.parse_if();
+        }
+
+        // Vérifier si c'est une fonction simple: param: body
+        // (`x:x`, sans espace, est une URI et non une fonction)
+        if self
+            .current()
+            .is_some_and(|c| c.is_alphabetic() || c == '_')
+            && self.peek_uri_len().is_none()
+        {
+            let saved_pos = self.pos;
+            let saved_line = self.line;
+            let saved_col = self.col;
+            let mark = self.span_mark();
+
+            let id = self.parse_identifier()?;
+            self.skip_whitespace();
+
+            if self.current() == Some(':') {
+                self.advance();
+                self.skip_whitespace();
+                let body = self.parse_value()?;
+                self.close_span(mark, Some(saved_pos));
+                return Ok(NixValue::Function(Box::new(NixFunction {
+                    params: vec![id],
+                    pattern: false,
+                    ellipsis: false,
+                    body,
+                })));
+            }
+
+            self.pos = saved_pos;
+            self.line = saved_line;
+            self.col = saved_col;
+        }
+
+        self.parse_binary(0)
+    }
+
+    // Le caractère courant peut-il commencer un argument d'application ?
+    fn at_argument_start(&self) -> bool {
+        match self.current() {
+            Some('(') | Some('[') | Some('{') | Some('"') | Some('\'') => true,
+            Some('.') => {
+                matches!(self.peek_char(1), Some('/') | Some('.')) || self.at_number_start()
+            }
+            // '/' seul est une division et '//' une mise à jour, pas un chemin
+            Some('/') => self
+                .peek_char(1)
+                .is_some_and(|c| c != '/' && !c.is_whitespace()),
+            Some('~') => self.peek_char(1) == Some('/'),
+            // `<nixpkgs>` est un chemin, `a <b` une comparaison
+            Some('<') => self.peek_search_path_len().is_some(),
+            Some(c) if c.is_ascii_digit() => true,
+            // `or` n'est un opérateur qu'après une sélection, déjà lue par `parse_select` :
+            // ici c'est une variable, et `f or` applique `f`
+            Some(c) if c.is_alphabetic() || c == '_' => !matches!(
+                self.peek_identifier(),
+                "in" | "then" | "else" | "if" | "let" | "with" | "assert" | "inherit"
+            ),
+            _ => false,
+        }
+    }
+
+    // Application de fonction `f a b`, associative à gauche
+    fn parse_application(&mut self) -> Result<NixValue, ParseError> {
+        let mark = self.span_mark();
+        let mut func = self.parse_select()?;
+
+        // `{ a = 1; } { b = 2; }` : deux fragments, et non l'application d'un ensemble
+        if self.split_fragments
+            && matches!(
+                func,
+                NixValue::Null
+                    | NixValue::Bool(_)
+                    | NixValue::Int(_)
+                    | NixValue::Float(_)
+                    | NixValue::String(_)
+                    | NixValue::InterpolatedString(_)
+                    | NixValue::IndentedString(_)
+                    | NixValue::RawString(..)
+                    | NixValue::Path(..)
+                    | NixValue::InterpolatedPath(_)
+                    | NixValue::List(_)
+                    | NixValue::AttrSet(_)
+                    | NixValue::RecAttrSet(_)
+            )
+        {
+            return Ok(func);
+        }
+
+        loop {
+            self.skip_whitespace();
+            if !self.at_argument_start() {
+                break;
+            }
+            let arg = self.parse_select()?;
+            func = match (func, arg) {
+                // `import` est une fonction comme une autre ; appliquée à un chemin ou une
+                // chaîne littérale, elle donne le nœud `Import`, dont l'argument est le seul
+                // enfant
+                (NixValue::Variable(name), arg @ (NixValue::String(_) | NixValue::Path(..)))
+                    if name == "import" =>
+                {
+                    // Seul l'intervalle de `import` lui-même disparaît
+                    let spans = self.take_spans(mark);
+                    let start = spans.first().map(|t| t.span.start);
+                    self.insert_spans(mark, spans.into_iter().skip(1).collect());
+                    self.close_span(mark, start);
+                    NixValue::Import(Box::new(arg))
+                }
+                (func, arg) => {
+                    self.close_span(mark, None);
+                    NixValue::Apply(Box::new(NixApply { func, arg }))
+                }
+            };
+        }
+
+        Ok(func)
+    }
+
+    // Sélection d'attributs `expr.a."b".c` sur un opérande
+    fn parse_select(&mut self) -> Result<NixValue, ParseError> {
+        let mark = self.span_mark();
+        let expr = self.parse_operand()?;
+        let mut path = Vec::new();
+
+        loop {
+            self.skip_whitespace();
+            // Un '.' suivi de '/' ou '.' commence un chemin, pas une sélection
+            if self.current() != Some('.') || matches!(self.peek_char(1), Some('/') | Some('.')) {
+                break;
+            }
+            self.advance(); // '.'
+            self.skip_whitespace();
+
+            path.push(self.parse_attr_segment()?);
+        }
+
+        if path.is_empty() {
+            return Ok(expr);
+        }
+
+        // Valeur par défaut `or`, reconnue seulement après une sélection
+        let mut default = None;
+        if self.peek_identifier() == "or" {
+            self.advance();
+            self.advance();
+            self.skip_whitespace();
+            default = Some(self.parse_select()?);
+        }
+
+        self.close_span(mark, None);
+        Ok(NixValue::Select(Box::new(NixSelect {
+            expr,
+            path,
+            default,
+        })))
+    }
+
+    // Opérande d'une expression : littéraux, listes, ensembles, variables...
+    fn parse_operand(&mut self) -> Result<NixValue, ParseError> {
+        self.skip_whitespace();
+        let (start, mark) = (self.pos, self.span_mark());
+
+        match self.current() {
+            Some('(') => {
+                self.advance(); // '('
+                let inner = self.parse_value()?;
+                self.skip_whitespace();
+                if self.current() != Some(')') {
+                    return Err(self.error("Expected ')' to close parenthesized expression"));
+                }
+                self.advance(); // ')'
+                if self.keep_parens {
+                    self.close_span(mark, Some(start));
+                    Ok(NixValue::Paren(Box::new(inner)))
+                } else {
+                    Ok(inner)
+                }
+            }
+            Some('{') => self.parse_attrset(),
+            Some('[') => self.parse_list(),
+            // String normale, ou multi-ligne ''...''
+            Some('"') | Some('\'') if self.raw_strings => {
+                let kind = if self.peek_string("''") {
+                    StringKind::Indented
+                } else {
+                    StringKind::DoubleQuoted
+                };
+                let parts = self.parse_string_parts(true)?;
+                self.close_span(mark, Some(start));
+                Ok(NixValue::RawString(kind, parts))
+            }
+            Some('"') | Some('\'') => self.parse_string_value(),
+            Some(_) if self.at_number_start() => self.parse_number(),
+            Some('.') => {
+                // Peut être un chemin relatif (./path ou ../../path) ou un accès d'attribut
+                // On regarde le caractère suivant
+                if let Some(next) = self.peek_char(1)
+                    && (next == '/' || next == '.')
+                {
+                    // C'est un chemin relatif
+                    return self.parse_path();
+                }
+                // Sinon c'est probablement une erreur ou un cas spécial
+                Err(self.error("Unexpected '.' - expected path or attribute access"))
+            }
+            Some('/') => {
+                // C'est un chemin absolu
+                self.parse_path()
+            }
+            Some('~') if self.peek_char(1) == Some('/') => self.parse_path(),
+            Some('<') => self.parse_search_path(),
+            Some(_) if self.peek_uri_len().is_some() => {
+                // URI nue (`https://example.com`), gardée comme une string
+                for _ in 0..self.peek_uri_len().unwrap_or(0) {
+                    self.advance();
+                }
+                self.close_span(mark, Some(start));
+                Ok(NixValue::String(self.input[start..self.pos].to_string()))
+            }
+            // Rien d'autre qu'un identifiant ne peut commencer ici : `@`, `;`, `)`...
+            Some(c) if !c.is_alphabetic() && c != '_' => {
+                Err(self.error(&format!("Unexpected character '{}'", c)))
+            }
+            Some(_) => self.parse_word(start, mark),
+            None => Err(self.error("Unexpected end of input")),
+        }
+    }
+
+    // Mot-clé (`null`, `true`, `false`, `rec { }`, `import`) ou variable ; hors de
+    // `parse_operand` pour garder petit son cadre de pile, traversé à chaque imbrication
+    fn parse_word(&mut self, start: usize, mark: usize) -> Result<NixValue, ParseError> {
+        let value = if self.peek_keyword("null") {
+            for _ in 0..4 {
+                self.advance();
+            }
+            NixValue::Null
+        } else if self.peek_keyword("true") {
+            for _ in 0..4 {
+                self.advance();
+            }
+            NixValue::Bool(true)
+        } else if self.peek_keyword("false") {
+            for _ in 0..5 {
+                self.advance();
+            }
+            NixValue::Bool(false)
+        } else if let Some(attrs) = self.parse_rec_attrset()? {
+            return Ok(attrs);
+        } else {
+            NixValue::Variable(self.parse_identifier()?)
+        };
+        self.close_span(mark, Some(start));
+        Ok(value)
+    }
+
+    pub fn parse(&mut self) -> Result<NixValue, ParseError> {
+        self.parse_value()
+    }
+
+    // Comme `parse`, mais une liaison ou un élément de liste invalide est noté puis sauté :
+    // toutes les erreurs sont renvoyées, avec l'arbre privé des parties invalides si
+    // le reste a pu être lu
+    pub fn parse_with_recovery(&mut self) -> (Option<NixValue>, Vec<ParseError>) {
+        self.errors = Some(Vec::new());
+        let result = self.parse();
+        let mut errors = self.errors.take().unwrap_or_default();
+        match result {
+            Ok(value) => (Some(value), errors),
+            Err(err) => {
+                errors.push(err);
+                (None, errors)
+            }
+        }
+    }
+
+    // Comme `parse`, pour un extrait en cours d'écriture : un `let` dont l'entrée s'arrête
+    // avant le `in` rend ses liaisons au lieu d'une erreur. Seul le `let` de tête est
+    // concerné, un `let` incomplet plus profond reste une erreur
+    pub fn parse_fragment(&mut self) -> Result<NixFragment, ParseError> {
+        self.skip_whitespace();
+        let start = self.pos;
+        self.partial_lets = Some(Vec::new());
+        let result = self.parse();
+        let partial_lets = self.partial_lets.take().unwrap_or_default();
+        match result {
+            Ok(value) => Ok(NixFragment::Complete(value)),
+            Err(err) => partial_lets
+                .into_iter()
+                .find(|(pos, _)| *pos == start)
+                .map(|(_, bindings)| NixFragment::PartialLet(bindings))
+                .ok_or(err),
+        }
+    }
+
+    // Comme `parse`, en relevant aussi la position de chaque nœud de l'arbre
+    pub fn parse_with_spans(&mut self) -> Result<(NixValue, SpanTree), ParseError> {
+        self.spans = Some(Vec::new());
+        let result = self.parse();
+        let mut spans = self.spans.take().unwrap_or_default();
+        let value = result?;
+        debug_assert_eq!(spans.len(), 1, "one span tree per parsed expression");
+        let tree = spans.pop().unwrap_or(SpanTree {
+            span: Span {
+                start: 0,
+                end: self.input.len(),
+            },
+            children: Vec::new(),
+        });
+        Ok((value, tree))
+    }
+
+    // Met à jour un arbre lu par `parse_with_spans`, avec les mêmes options, après une
+    // modification du source : le parseur lit le nouveau source, `edited` est la plage
+    // remplacée dans l'ancien et `inserted` la longueur du texte qui la remplace. Seul le
+    // plus petit ensemble, liste, string ou parenthèse qui contient la modification est
+    // reparsé puis greffé dans l'arbre ; si aucun ne la contient, ou si ses délimiteurs ne
+    // tiennent plus, tout le source est reparsé. `warnings()` ne couvre que le texte relu
+    pub fn reparse_range(
+        &mut self,
+        old: &(NixValue, SpanTree),
+        edited: Range<usize>,
+        inserted: usize,
+    ) -> Result<(NixValue, SpanTree), ParseError> {
+        let (value, spans) = old;
+        let input = self.input;
+        let shift = |pos: usize| {
+            if pos >= edited.end {
+                pos + inserted - edited.len()
+            } else {
+                pos
+            }
+        };
+        let Some((path, span)) = reparse_target(value, spans, input, &edited, &shift) else {
+            return self.parse_with_spans();
+        };
+        let text = &input[span.start..span.end];
+        let mut parser = self.with_input(text);
+        let (node, mut node_spans) = match parser.parse_with_spans() {
+            Ok(parsed) if parser.is_at_end() && same_delimiters(&parsed.0, text) => parsed,
+            _ => return self.parse_with_spans(),
+        };
+        self.warnings.append(&mut parser.warnings);
+
+        let mut value = value.clone();
+        replace_child(&mut value, &path, &mut Some(node));
+        let mut spans = spans.clone();
+        shift_spans(&mut spans, &shift);
+        shift_spans(&mut node_spans, &|pos| pos + span.start);
+        let target = path
+            .iter()
+            .fold(&mut spans, |tree, &i| &mut tree.children[i]);
+        *target = node_spans;
+        Ok((value, spans))
+    }
+
+    // Parseur neuf de `input`, avec les mêmes options que celui-ci
+    fn with_input<'b>(&self, input: &'b str) -> NixParser<'b> {
+        NixParser {
+            keep_comments: self.keep_comments,
+            keep_parens: self.keep_parens,
+            max_depth: self.max_depth,
+            raw_strings: self.raw_strings,
+            comma_separators: self.comma_separators,
+            ..NixParser::new(input)
+        }
+    }
+
+    // Parse l'expression suivante et s'arrête juste après (espaces et commentaires
+    // suivants compris) : appeler à nouveau reprend au même endroit
+    pub fn parse_expression(&mut self) -> Result<NixValue, ParseError> {
+        self.parse_value()
+    }
+
+    // Position courante : (octet dans l'entrée, ligne, colonne)
+    pub fn position(&self) -> (usize, usize, usize) {
+        (self.pos, self.line, self.col)
+    }
+
+    pub fn is_at_end(&self) -> bool {
+        self.pos >= self.input.len()
+    }
+
+    // Erreur sur le premier caractère qui suit l'expression lue, blancs et commentaires
+    // mis à part
+    fn expect_end(&mut self) -> Result<(), ParseError> {
+        self.skip_whitespace();
+        match self.current() {
+            None => Ok(()),
+            Some(c) => Err(self.error(&format!("Unexpected character '{}'", c))),
+        }
+    }
+}
+
+// Fonction principale pour parser un fichier
+#[cfg(feature = "std")]
+pub fn parse_nix_file<P: AsRef<Path>>(path: P) -> Result<NixValue> {
+    let content = fs::read_to_string(path)?;
+    Ok(parse_nix_string(&content)?)
+}
+
+// Parse le contenu d'un flux (stdin, socket...), lu en entier avant l'analyse
+#[cfg(feature = "std")]
+pub fn parse_nix_reader<R: std::io::Read>(mut reader: R) -> Result<NixValue> {
+    let mut content = String::new();
+    reader.read_to_string(&mut content)?;
+    Ok(parse_nix_string(&content)?)
+}
+
+// Fonction pour parser une chaîne Nix. Contrairement à `NixParser::parse`, qui peut
+// reprendre après l'expression lue, toute l'entrée doit former une seule expression
+pub fn parse_nix_string(input: &str) -> Result<NixValue, ParseError> {
+    let mut parser = NixParser::new(input);
+    let value = parser.parse()?;
+    parser.expect_end()?;
+    Ok(value)
+}
+
+// Parse une chaîne Nix en rapportant toutes les erreurs au lieu de la première
+pub fn parse_with_recovery(input: &str) -> (Option<NixValue>, Vec<ParseError>) {
+    NixParser::new(input).parse_with_recovery()
+}
+
+// Parse un extrait en cours d'écriture (`NixParser::parse_fragment`)
+pub fn parse_nix_fragment(input: &str) -> Result<NixFragment, ParseError> {
+    NixParser::new(input).parse_fragment()
+}
+
+// `NixParser::reparse_range` avec les options par défaut
+pub fn reparse_range(
+    old: &(NixValue, SpanTree),
+    input: &str,
+    edited: Range<usize>,
+    inserted: usize,
+) -> Result<(NixValue, SpanTree), ParseError> {
+    NixParser::new(input).reparse_range(old, edited, inserted)
+}
+
+// Chemin (indices dans `children()`) et nouvelle position du plus petit nœud délimité
+// qui contient strictement la plage modifiée : ses délimiteurs sont alors intacts
+fn reparse_target(
+    value: &NixValue,
+    spans: &SpanTree,
+    input: &str,
+    edited: &Range<usize>,
+    shift: &impl Fn(usize) -> usize,
+) -> Option<(Vec<usize>, Span)> {
+    let (mut value, mut spans) = (value, spans);
+    let mut path = Vec::new();
+    let mut target = None;
+    loop {
+        let Span { start, end } = spans.span;
+        if start < edited.start && edited.end < end {
+            let span = Span {
+                start,
+                end: shift(end),
+            };
+            if input
+                .get(span.start..span.end)
+                .is_some_and(|text| same_delimiters(value, text))
+            {
+                target = Some((path.clone(), span));
+            }
+        }
+        let inner =
+            |tree: &SpanTree| tree.span.start <= edited.start && edited.end <= tree.span.end;
+        let Some(i) = spans.children.iter().position(inner) else {
+            return target;
+        };
+        value = value.children().nth(i)?;
+        spans = &spans.children[i];
+        path.push(i);
+    }
+}
+
+// `text` commence et finit par les délimiteurs d'un nœud du genre de `value` ; reparsé
+// seul, un tel texte donne le même nœud qu'au milieu du source
+fn same_delimiters(value: &NixValue, text: &str) -> bool {
+    let (open, close) = match value {
+        NixValue::List(_) => ("[", "]"),
+        NixValue::AttrSet(_) => ("{", "}"),
+        NixValue::RecAttrSet(_) => ("rec", "}"),
+        NixValue::String(_) | NixValue::InterpolatedString(_) => ("\"", "\""),
+        NixValue::IndentedString(_) | NixValue::RawString(StringKind::Indented, _) => ("''", "''"),
+        NixValue::RawString(StringKind::DoubleQuoted, _) => ("\"", "\""),
+        NixValue::Paren(_) => ("(", ")"),
+        _ => return false,
+    };
+    text.starts_with(open) && text.ends_with(close)
+}
+
+fn replace_child(value: &mut NixValue, path: &[usize], node: &mut Option<NixValue>) {
+    let Some((&index, rest)) = path.split_first() else {
+        *value = node.take().expect("node replaced once");
+        return;
+    };
+    let mut i = 0;
+    value.for_each_child_mut(&mut |child| {
+        if i == index {
+            replace_child(child, rest, node);
+        }
+        i += 1;
+    });
+}
+
+fn shift_spans(tree: &mut SpanTree, shift: &impl Fn(usize) -> usize) {
+    tree.span = Span {
+        start: shift(tree.span.start),
+        end: shift(tree.span.end),
+    };
+    for child in &mut tree.children {
+        shift_spans(child, shift);
+    }
+}
+
+// Parse plusieurs expressions concaténées, séparées par des espaces ou des commentaires.
+// Un fragment commençant par une variable absorbe comme arguments les ensembles, listes
+// et littéraux qui le suivent (`f { } { }` est une application) ; une erreur indique le
+// numéro (à partir de 0) du fragment fautif
+pub fn parse_nix_string_many(input: &str) -> Result<Vec<NixValue>, ParseError> {
+    let mut parser = NixParser::new(input);
+    parser.split_fragments = true;
+
+    let mut values = Vec::new();
+    parser.skip_whitespace();
+    while !parser.is_at_end() {
+        let value = parser.parse_value().map_err(|mut e| {
+            e.message = format!("In fragment {}: {}", values.len(), e.message);
+            e
+        })?;
+        values.push(value);
+        parser.skip_whitespace();
+    }
+    Ok(values)
+}
+
+// Import depuis JSON : objets en ensembles, tableaux en listes
+impl NixValue {
+    // Les nombres sans partie décimale ni exposant deviennent des `Int` s'ils tiennent
+    // sur 64 bits ; les clés qui ne sont pas des identifiants seront écrites entre guillemets
+    pub fn from_json(input: &str) -> Result<NixValue, ParseError> {
+        let mut parser = NixParser::new(input);
+        parser.skip_json_whitespace();
+        let value = parser.parse_json_value()?;
+        parser.skip_json_whitespace();
+        if !parser.is_at_end() {
+            return Err(parser.error("Unexpected trailing characters after JSON value"));
+        }
+        Ok(value)
+    }
+}
+
+impl<'a> NixParser<'a> {
+    fn skip_json_whitespace(&mut self) {
+        while matches!(self.current(), Some(' ' | '\t' | '\n' | '\r')) {
+            self.advance();
+        }
+    }
+
+    fn parse_json_value(&mut self) -> Result<NixValue, ParseError> {
+        match self.current() {
+            Some('{') => self.nested(Self::parse_json_object),
+            Some('[') => self.nested(Self::parse_json_array),
+            Some('"') => Ok(NixValue::String(self.parse_json_string()?)),
+            Some('-' | '0'..='9') => self.parse_json_number(),
+            Some(_) => {
+                for (word, value) in [
+                    ("null", NixValue::Null),
+                    ("true", NixValue::Bool(true)),
+                    ("false", NixValue::Bool(false)),
+                ] {
+                    if self.peek_string(word) {
+                        for _ in 0..word.len() {
+                            self.advance();
+                        }
+                        return Ok(value);
+                    }
+                }
+                Err(self.error("Expected JSON value"))
+            }
+            None => Err(self.error("Unexpected end of input")),
+        }
+    }
+
+    fn parse_json_object(&mut self) -> Result<NixValue, ParseError> {
+        self.advance(); // '{'
+        self.skip_json_whitespace();
+
+        let mut attrs = AttrSetMap::new();
+        if self.current() == Some('}') {
+            self.advance();
+            return Ok(NixValue::AttrSet(attrs));
+        }
+        loop {
+            if self.current() != Some('"') {
+                return Err(self.error("Expected string key in JSON object"));
+            }
+            let key = self.parse_json_string()?;
+            self.skip_json_whitespace();
+            if self.current() != Some(':') {
+                return Err(self.error("Expected ':' after JSON object key"));
+            }
+            self.advance();
+            self.skip_json_whitespace();
+            let value = self.parse_json_value()?;
+            attrs.insert(key, value);
+
+            self.skip_json_whitespace();
+            match self.current() {
+                Some(',') => {
+                    self.advance();
+                    self.skip_json_whitespace();
+                }
+                Some('}') => {
+                    self.advance();
+                    return Ok(NixValue::AttrSet(attrs));
+                }
+                _ => return Err(self.error("Expected ',' or '}' in JSON object")),
+            }
+        }
+    }
+
+    fn parse_json_array(&mut self) -> Result<NixValue, ParseError> {
+        self.advance(); // '['
+        self.skip_json_whitespace();
+
+        let mut items = Vec::new();
+        if self.current() == Some(']') {
+            self.advance();
+            return Ok(NixValue::List(items));
+        }
+        loop {
+            items.push(self.parse_json_value()?);
+            self.skip_json_whitespace();
+            match self.current() {
+                Some(',') => {
+                    self.advance();
+                    self.skip_json_whitespace();
+                }
+                Some(']') => {
+                    self.advance();
+                    return Ok(NixValue::List(items));
+                }
+                _ => return Err(self.error("Expected ',' or ']' in JSON array")),
+            }
+        }
+    }
+
+    fn parse_json_string(&mut self) -> Result<String, ParseError> {
+        self.advance(); // '"'
+        let mut s = String::new();
+        loop {
+            match self.current() {
+                Some('"') => {
+                    self.advance();
+                    return Ok(s);
+                }
+                Some('\\') => {
+                    self.advance();
+                    let escaped = match self.current() {
+                        Some('"') => '"',
+                        Some('\\') => '\\',
+                        Some('/') => '/',
+                        Some('b') => '\u{8}',
+                        Some('f') => '\u{c}',
+                        Some('n') => '\n',
+                        Some('r') => '\r',
+                        Some('t') => '\t',
+                        Some('u') => {
+                            s.push(self.parse_json_unicode_escape()?);
+                            continue;
+                        }
+                        _ => return Err(self.error("Invalid escape in JSON string")),
+                    };
+                    self.advance();
+                    s.push(escaped);
+                }
+                Some(c) if (c as u32) < 0x20 => {
+                    return Err(self.error("Control character in JSON string"));
+                }
+                Some(c) => {
+                    s.push(c);
+                    self.advance();
+                }
+                None => return Err(self.error("Unterminated JSON string")),
+            }
+        }
+    }
+
+    // `\uXXXX`, le `u` étant le caractère courant ; une paire de substitution
+    // `\uD83D\uDE00` donne un seul caractère
+    fn parse_json_unicode_escape(&mut self) -> Result<char, ParseError> {
+        let high = self.parse_json_hex4()?;
+        if !(0xD800..0xDC00).contains(&high) {
+            return char::from_u32(high).ok_or_else(|| self.error("Invalid unicode escape"));
+        }
+        if self.current() != Some('\\') || self.peek_char(1) != Some('u') {
+            return Err(self.error("Expected low surrogate in unicode escape"));
+        }
+        self.advance(); // '\'
+        let low = self.parse_json_hex4()?;
+        if !(0xDC00..0xE000).contains(&low) {
+            return Err(self.error("Invalid low surrogate in unicode escape"));
+        }
+        char::from_u32(0x10000 + ((high - 0xD800) << 10) + (low - 0xDC00))
+            .ok_or_else(|| self.error("Invalid unicode escape"))
+    }
+
+    // `uXXXX`, le `u` étant le caractère courant
+    fn parse_json_hex4(&mut self) -> Result<u32, ParseError> {
+        self.advance(); // 'u'
+        let mut code = 0;
+        for _ in 0..4 {
+            let digit = self
+                .current()
+                .and_then(|c| c.to_digit(16))
+                .ok_or_else(|| self.error("Expected 4 hex digits in unicode escape"))?;
+            code = code * 16 + digit;
+            self.advance();
+        }
+        Ok(code)
+    }
+
+    // Grammaire stricte de JSON : `-?(0|[1-9][0-9]*)(\.[0-9]+)?([eE][+-]?[0-9]+)?`, là
+    // où `f64::from_str` accepterait aussi `01`, `1.`, `.5` ou `+1`
+    fn parse_json_number(&mut self) -> Result<NixValue, ParseError> {
+        let start = self.pos;
+        if self.current() == Some('-') {
+            self.advance();
+        }
+        match self.current() {
+            Some('0') => self.advance(),
+            Some('1'..='9') => {
+                self.skip_json_digits();
+            }
+            _ => return Err(self.error("Invalid JSON number")),
+        }
+        if self.current() == Some('.') {
+            self.advance();
+            if self.skip_json_digits() == 0 {
+                return Err(self.error("Invalid JSON number"));
+            }
+        }
+        if matches!(self.current(), Some('e' | 'E')) {
+            self.advance();
+            if matches!(self.current(), Some('+' | '-')) {
+                self.advance();
+            }
+            if self.skip_json_digits() == 0 {
+                return Err(self.error("Invalid JSON number"));
+            }
+        }
+        // `01` : un zéro initial ne peut pas être suivi d'autres chiffres
+        if matches!(self.current(), Some('0'..='9')) {
+            return Err(self.error("Invalid JSON number"));
+        }
+        let num = &self.input[start..self.pos];
+        if !num.contains(['.', 'e', 'E'])
+            && let Ok(i) = num.parse::<i64>()
+        {
+            return Ok(NixValue::Int(i));
+        }
+        num.parse::<f64>()
+            .map(NixValue::Float)
+            .map_err(|_| self.error("Invalid JSON number"))
+    }
+
+    // Nombre de chiffres décimaux consommés
+    fn skip_json_digits(&mut self) -> usize {
+        let start = self.pos;
+        while matches!(self.current(), Some('0'..='9')) {
+            self.advance();
+        }
+        self.pos - start
+    }
+}
+
+// Options de mise en forme du code Nix produit
+#[derive(Debug, Clone)]
+pub struct FormatOptions {
+    // Nombre d'espaces par niveau d'indentation
+    pub indent_width: usize,
+    // Largeur maximale d'une liste ou d'un ensemble gardé sur une seule ligne
+    // (indentation comprise) ; 0 développe toujours sur plusieurs lignes
+    pub max_width: usize,
+    // Aligner les `=` des liaisons d'un même bloc
+    pub align_equals: bool,
+    // Avec `align_equals`, les clés plus longues que cette largeur ne sont pas alignées et
+    // n'élargissent pas l'alignement de leurs voisines
+    pub max_align_width: Option<usize>,
+    // Écrire les attributs et les liaisons `let` par ordre alphabétique plutôt que dans
+    // l'ordre du source
+    pub sort_keys: bool,
+    // Garder sur une ligne les listes de valeurs simples (`ports = [ 80 443 ];`),
+    // quelle que soit `max_width`
+    pub inline_leaf_lists: bool,
+    // Tout écrire sur une seule ligne (`{ a = 1; b = [ 2 ]; }`) ; les commentaires,
+    // qui ne tiendraient pas sur une ligne, sont omis
+    pub compact: bool,
+    // Garder sur une ligne les listes et ensembles dont la profondeur (`depth()`) ne
+    // dépasse pas ce seuil, les niveaux supérieurs restant développés
+    pub collapse_depth: Option<usize>,
+}
+
+impl FormatOptions {
+    pub fn align_equals(mut self, align: bool) -> Self {
+        self.align_equals = align;
+        self
+    }
+
+    pub fn align_at_most(mut self, width: usize) -> Self {
+        self.align_equals = true;
+        self.max_align_width = Some(width);
+        self
+    }
+
+    pub fn collapse_below(mut self, depth: usize) -> Self {
+        self.collapse_depth = Some(depth);
+        self
+    }
+}
+
+impl Default for FormatOptions {
+    fn default() -> Self {
+        Self {
+            indent_width: 2,
+            max_width: 0,
+            align_equals: false,
+            max_align_width: None,
+            sort_keys: false,
+            inline_leaf_lists: false,
+            compact: false,
+            collapse_depth: None,
+        }
+    }
+}
+
+// Valeur associée à ses options, pour passer par `fmt::Display`
+struct Formatted<'a> {
+    value: &'a NixValue,
+    opts: &'a FormatOptions,
+    indent: usize,
+}
+
+impl fmt::Display for Formatted<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.value.write_with_indent(f, self.opts, self.indent)
+    }
+}
+
+// Formatteur pour écrire des valeurs Nix
+impl fmt::Display for NixValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.write_with_indent(f, &FormatOptions::default(), 0)
+    }
+}
+
+impl NixValue {
+    // Rendu sur une seule ligne, sans retour à la ligne final
+    pub fn to_compact_string(&self) -> String {
+        self.format(&FormatOptions {
+            compact: true,
+            ..FormatOptions::default()
+        })
+    }
+
+    pub fn format(&self, opts: &FormatOptions) -> String {
+        if opts.sort_keys {
+            let sorted = self.sorted();
+            return Formatted {
+                value: &sorted,
+                opts,
+                indent: 0,
+            }
+            .to_string();
+        }
+        Formatted {
+            value: self,
+            opts,
+            indent: 0,
+        }
+        .to_string()
+    }
+
+    fn write_with_indent(
+        &self,
+        f: &mut fmt::Formatter,
+        opts: &FormatOptions,
+        indent: usize,
+    ) -> fmt::Result {
+        let indent_str = " ".repeat(indent * opts.indent_width);
+        let inner_str = " ".repeat((indent + 1) * opts.indent_width);
+
+        match self {
+            NixValue::Null => write!(f, "null"),
+            NixValue::Bool(b) => write!(f, "{}", b),
+            NixValue::Int(i) => write!(f, "{}", i),
+            // `1.0` et non `1`, qui serait relu comme un entier
+            NixValue::Float(fl) if fl.is_finite() && *fl % 1.0 == 0.0 => write!(f, "{:.1}", fl),
+            NixValue::Float(fl) => write!(f, "{}", fl),
+            NixValue::String(s) => write!(f, "\"{}\"", escape_nix_string(s)),
+            NixValue::IndentedString(parts)
+                if !opts.compact && indented_string_round_trips(parts) =>
+            {
+                let multiline = parts
+                    .iter()
+                    .any(|p| matches!(p, StringPart::Literal(s) if s.contains('\n')));
+                write!(f, "''")?;
+                if multiline {
+                    writeln!(f)?;
+                }
+                let mut at_line_start = multiline;
+                for (i, part) in parts.iter().enumerate() {
+                    match part {
+                        StringPart::Literal(s) => {
+                            let mut escaped = escape_indented_string(s);
+                            // `$${` ne serait pas relu comme `$` suivi d'une interpolation
+                            if escaped.ends_with('$')
+                                && matches!(parts.get(i + 1), Some(StringPart::Interpolation(_)))
+                            {
+                                escaped.pop();
+                                escaped.push_str("''$");
+                            }
+                            // Lignes réindentées au niveau courant, lignes vides laissées vides
+                            for line in escaped.split_inclusive('\n') {
+                                if at_line_start && line != "\n" {
+                                    write!(f, "{}", inner_str)?;
+                                }
+                                write!(f, "{}", line)?;
+                                at_line_start = line.ends_with('\n');
+                            }
+                        }
+                        StringPart::Interpolation(expr) => {
+                            if at_line_start {
+                                write!(f, "{}", inner_str)?;
+                                at_line_start = false;
+                            }
+                            write!(f, "${{")?;
+                            expr.write_with_indent(f, opts, indent)?;
+                            write!(f, "}}")?;
+                        }
+                    }
+                }
+                if at_line_start {
+                    write!(f, "{}", indent_str)?;
+                }
+                write!(f, "''")
+            }
+            // Sinon, même contenu entre guillemets doubles
+            NixValue::InterpolatedString(parts) | NixValue::IndentedString(parts) => {
+                write!(f, "\"")?;
+                for (i, part) in parts.iter().enumerate() {
+                    match part {
+                        StringPart::Literal(s) => {
+                            let mut escaped = escape_nix_string(s);
+                            // `$${` serait relu comme un littéral et non comme `$` suivi
+                            // d'une interpolation
+                            if escaped.ends_with('$')
+                                && matches!(parts.get(i + 1), Some(StringPart::Interpolation(_)))
+                            {
+                                escaped.pop();
+                                escaped.push_str("\\$");
+                            }
+                            write!(f, "{}", escaped)?;
+                        }
+                        StringPart::Interpolation(expr) => {
+                            write!(f, "${{")?;
+                            expr.write_with_indent(f, opts, indent)?;
+                            write!(f, "}}")?;
+                        }
+                    }
+                }
+                write!(f, "\"")
+            }
+            NixValue::RawString(kind, parts) => {
+                let delimiter = match kind {
+                    StringKind::DoubleQuoted => "\"",
+                    StringKind::Indented => "''",
+                };
+                write!(f, "{}", delimiter)?;
+                for part in parts {
+                    match part {
+                        StringPart::Literal(s) => write!(f, "{}", s)?,
+                        StringPart::Interpolation(expr) => {
+                            write!(f, "${{")?;
+                            expr.write_with_indent(f, opts, indent)?;
+                            write!(f, "}}")?;
+                        }
+                    }
+                }
+                write!(f, "{}", delimiter)
+            }
+            NixValue::Path(PathKind::SearchPath, p) => write!(f, "<{}>", p),
+            NixValue::Path(_, p) => write!(f, "{}", p),
+            NixValue::InterpolatedPath(parts) => {
+                for part in parts {
+                    match part {
+                        StringPart::Literal(s) => write!(f, "{}", s)?,
+                        StringPart::Interpolation(expr) => {
+                            write!(f, "${{")?;
+                            expr.write_with_indent(f, opts, indent)?;
+                            write!(f, "}}")?;
+                        }
+                    }
+                }
+                Ok(())
+            }
+            NixValue::Variable(v) => write!(f, "{}", v),
+            NixValue::Import(arg) => {
+                write!(f, "import ")?;
+                arg.write_operand(f, opts, indent, NixValue::APPLY_PRECEDENCE + 1)
+            }
+
+            NixValue::Select(select) => {
+                select.expr.write_operand(f, opts, indent, u8::MAX)?;
+                for key in &select.path {
+                    write!(f, ".{}", key)?;
+                }
+                if let Some(default) = &select.default {
+                    write!(f, " or ")?;
+                    default.write_operand(f, opts, indent, NixValue::APPLY_PRECEDENCE + 1)?;
+                }
+                Ok(())
+            }
+
+            NixValue::List(_) | NixValue::AttrSet(_) | NixValue::RecAttrSet(_)
+                if self.fits_inline(opts, indent) =>
+            {
+                self.write_inline(f, opts, indent)
+            }
+
+            NixValue::List(items) => {
+                writeln!(f, "[")?;
+                for item in items.iter() {
+                    write!(f, "{}", inner_str)?;
+                    item.write_list_item(f, opts, indent + 1)?;
+                    writeln!(f)?;
+                }
+                write!(f, "{}]", indent_str)
+            }
+
+            NixValue::AttrSet(attrs) | NixValue::RecAttrSet(attrs) => {
+                if matches!(self, NixValue::RecAttrSet(_)) {
+                    write!(f, "rec ")?;
+                }
+                writeln!(f, "{{")?;
+                for inherit in &attrs.inherits {
+                    for comment in &inherit.comments {
+                        writeln!(f, "{}{}", inner_str, comment)?;
+                    }
+                    write!(f, "{}", inner_str)?;
+                    write_inherit(f, inherit, opts, indent + 1)?;
+                    writeln!(f)?;
+                }
+                let keys: Vec<String> = attrs.keys().map(|key| key.to_string()).collect();
+                let key_width = aligned_key_width(&keys, opts);
+                for (entry, key) in attrs.entries().iter().zip(&keys) {
+                    for comment in &entry.comments {
+                        writeln!(f, "{}{}", inner_str, comment)?;
+                    }
+                    // Les segments sont mis entre guillemets si nécessaire (fileSystems."/".options)
+                    write!(f, "{}{:<width$} = ", inner_str, key, width = key_width)?;
+                    entry.value.write_with_indent(f, opts, indent + 1)?;
+                    write!(f, ";")?;
+                    if let Some(comment) = &entry.inline_comment {
+                        write!(f, " {}", comment)?;
+                    }
+                    writeln!(f)?;
+                }
+                for comment in &attrs.trailing_comments {
+                    writeln!(f, "{}{}", inner_str, comment)?;
+                }
+                write!(f, "{}}}", indent_str)
+            }
+
+            NixValue::Let(let_expr) if opts.compact => {
+                write!(f, "let ")?;
+                for inherit in &let_expr.bindings.inherits {
+                    write_inherit(f, inherit, opts, indent)?;
+                    write!(f, " ")?;
+                }
+                for (key, value) in let_expr.bindings.iter() {
+                    write!(f, "{} = ", key)?;
+                    value.write_with_indent(f, opts, indent)?;
+                    write!(f, "; ")?;
+                }
+                write!(f, "in ")?;
+                let_expr.body.write_with_indent(f, opts, indent)
+            }
+
+            NixValue::Let(let_expr) => {
+                writeln!(f, "let")?;
+                for inherit in &let_expr.bindings.inherits {
+                    write!(f, "{}", inner_str)?;
+                    write_inherit(f, inherit, opts, indent + 1)?;
+                    writeln!(f)?;
+                }
+                let keys: Vec<String> = let_expr
+                    .bindings
+                    .keys()
+                    .map(|key| key.to_string())
+                    .collect();
+                let key_width = aligned_key_width(&keys, opts);
+                for (value, key) in let_expr.bindings.values().zip(&keys) {
+                    write!(f, "{}{:<width$} = ", inner_str, key, width = key_width)?;
+                    value.write_with_indent(f, opts, indent + 1)?;
+                    writeln!(f, ";")?;
+                }
+                write!(f, "{}in ", indent_str)?;
+                let_expr.body.write_with_indent(f, opts, indent)
+            }
+
+            NixValue::Function(func) => {
+                if let (false, [param]) = (func.pattern, func.params.as_slice()) {
+                    // Fonction simple: x: body
+                    write!(f, "{}: ", param)?;
+                } else {
+                    // Fonction avec pattern: { x, y, ... }:, `...` seulement s'il y était
+                    let ellipsis = func.ellipsis.then_some("...");
+                    let params: Vec<&str> = func
+                        .params
+                        .iter()
+                        .map(String::as_str)
+                        .chain(ellipsis)
+                        .collect();
+                    if params.is_empty() {
+                        write!(f, "{{ }}: ")?;
+                    } else {
+                        write!(f, "{{ {} }}: ", params.join(", "))?;
+                    }
+                }
+                func.body.write_with_indent(f, opts, indent)
+            }
+
+            NixValue::BinaryOp(binary_op) => {
+                let prec = binary_op.op.precedence();
+                let (lhs_min, rhs_min) = if binary_op.op.is_non_assoc() {
+                    (prec + 1, prec + 1)
+                } else if binary_op.op.is_right_assoc() {
+                    (prec + 1, prec)
+                } else {
+                    (prec, prec + 1)
+                };
+                binary_op.lhs.write_operand(f, opts, indent, lhs_min)?;
+                write!(f, " {} ", binary_op.op)?;
+                binary_op.rhs.write_operand(f, opts, indent, rhs_min)
+            }
+
+            NixValue::HasAttr(has_attr) => {
+                let prec = NixValue::HAS_ATTR_PRECEDENCE;
+                has_attr.expr.write_operand(f, opts, indent, prec)?;
+                write!(f, " ?")?;
+                for (i, key) in has_attr.path.iter().enumerate() {
+                    write!(f, "{}{}", if i == 0 { " " } else { "." }, key)?;
+                }
+                Ok(())
+            }
+
+            NixValue::Apply(apply) => {
+                let prec = NixValue::APPLY_PRECEDENCE;
+                apply.func.write_operand(f, opts, indent, prec)?;
+                write!(f, " ")?;
+                apply.arg.write_operand(f, opts, indent, prec + 1)
+            }
+
+            NixValue::Assert(assert_expr) => {
+                write!(f, "assert ")?;
+                assert_expr.cond.write_with_indent(f, opts, indent)?;
+                write!(f, "; ")?;
+                assert_expr.body.write_with_indent(f, opts, indent)
+            }
+
+            NixValue::If(if_expr) => {
+                write!(f, "if ")?;
+                if_expr.cond.write_with_indent(f, opts, indent)?;
+                write!(f, " then ")?;
+                if_expr.then_branch.write_with_indent(f, opts, indent)?;
+                write!(f, " else ")?;
+                if_expr.else_branch.write_with_indent(f, opts, indent)
+            }
+
+            NixValue::UnaryOp(unary_op) => {
+                write!(f, "{}", unary_op.op)?;
+                // `- 3` et non `-3`, qui serait relu comme un littéral négatif
+                if unary_op.op == UnOp::Neg
+                    && matches!(unary_op.operand, NixValue::Int(_) | NixValue::Float(_))
+                {
+                    write!(f, " ")?;
+                }
+                unary_op
+                    .operand
+                    .write_operand(f, opts, indent, unary_op.op.precedence())
+            }
+
+            NixValue::Paren(inner) => {
+                write!(f, "(")?;
+                inner.write_with_indent(f, opts, indent)?;
+                write!(f, ")")
+            }
+
+            NixValue::With(with_expr) => {
+                write!(f, "with ")?;
+                with_expr.expr.write_with_indent(f, opts, indent)?;
+                write!(f, "; ")?;
+                with_expr.body.write_with_indent(f, opts, indent)
+            }
+        }
+    }
+
+    // Une liste ou un ensemble court, sans commentaires, tient sur une ligne
+    fn fits_inline(&self, opts: &FormatOptions, indent: usize) -> bool {
+        if opts.compact {
+            return true;
+        }
+        // `[ ]` et `{ }`, sauf s'il reste des commentaires à écrire dans l'ensemble
+        match self {
+            NixValue::List(items) if items.is_empty() => return true,
+            NixValue::AttrSet(attrs) | NixValue::RecAttrSet(attrs)
+                if attrs.is_empty() && attrs.trailing_comments.is_empty() =>
+            {
+                return true;
+            }
+            _ => {}
+        }
+        if opts.inline_leaf_lists
+            && let NixValue::List(items) = self
+            && items.iter().all(NixValue::is_leaf)
+        {
+            return true;
+        }
+        let collapse = opts
+            .collapse_depth
+            .is_some_and(|depth| self.depth() <= depth);
+        if opts.max_width == 0 && !collapse {
+            return false;
+        }
+        if let NixValue::AttrSet(attrs) | NixValue::RecAttrSet(attrs) = self {
+            let has_comments = !attrs.trailing_comments.is_empty()
+                || attrs.inherits.iter().any(|i| !i.comments.is_empty())
+                || attrs
+                    .entries()
+                    .iter()
+                    .any(|e| !e.comments.is_empty() || e.inline_comment.is_some());
+            if has_comments {
+                return false;
+            }
+        }
+        let inline = InlineFormatted {
+            value: self,
+            opts,
+            indent,
+        }
+        .to_string();
+        !inline.contains('\n')
+            && (collapse || indent * opts.indent_width + inline.chars().count() <= opts.max_width)
+    }
+
+    // `[ 1 2 3 ]`, `{ a = 1; b = 2; }`
+    fn write_inline(
+        &self,
+        f: &mut fmt::Formatter,
+        opts: &FormatOptions,
+        indent: usize,
+    ) -> fmt::Result {
+        match self {
+            NixValue::List(items) => {
+                write!(f, "[ ")?;
+                for item in items {
+                    item.write_list_item(f, opts, indent)?;
+                    write!(f, " ")?;
+                }
+                write!(f, "]")
+            }
+            NixValue::AttrSet(attrs) | NixValue::RecAttrSet(attrs) => {
+                if matches!(self, NixValue::RecAttrSet(_)) {
+                    write!(f, "rec ")?;
+                }
+                write!(f, "{{ ")?;
+                for inherit in &attrs.inherits {
+                    write_inherit(f, inherit, opts, indent)?;
+                    write!(f, " ")?;
+                }
+                for entry in attrs.entries() {
+                    write!(f, "{} = ", entry.key)?;
+                    entry.value.write_with_indent(f, opts, indent)?;
+                    write!(f, "; ")?;
+                }
+                write!(f, "}}")
+            }
+            _ => self.write_with_indent(f, opts, indent),
+        }
+    }
+
+    // Les éléments d'une liste sont des sélections : `[ (f x) ]` et non `[ f x ]`
+    fn write_list_item(
+        &self,
+        f: &mut fmt::Formatter,
+        opts: &FormatOptions,
+        indent: usize,
+    ) -> fmt::Result {
+        if matches!(self, NixValue::Int(_) | NixValue::Float(_)) {
+            self.write_with_indent(f, opts, indent)
+        } else {
+            self.write_operand(f, opts, indent, NixValue::APPLY_PRECEDENCE + 1)
+        }
+    }
+
+    // Valeur simple, sans sous-expressions
+    fn is_leaf(&self) -> bool {
+        matches!(
+            self,
+            NixValue::Null
+                | NixValue::Bool(_)
+                | NixValue::Int(_)
+                | NixValue::Float(_)
+                | NixValue::String(_)
+                | NixValue::Path(..)
+                | NixValue::Variable(_)
+        )
+    }
+
+    // Priorité de l'application, au-dessus de tous les opérateurs binaires
+    const APPLY_PRECEDENCE: u8 = 13;
+
+    // `?` lie moins fort que la négation mais plus que `++`
+    const HAS_ATTR_PRECEDENCE: u8 = 11;
+
+    // Priorité de l'expression une fois écrite : 0 pour les formes qui s'étendent
+    // le plus loin possible à droite (fonction, let, with), u8::MAX pour les atomes
+    fn display_precedence(&self) -> u8 {
+        match self {
+            NixValue::Function(_)
+            | NixValue::Let(_)
+            | NixValue::With(_)
+            | NixValue::Assert(_)
+            | NixValue::If(_) => 0,
+            NixValue::BinaryOp(binary_op) => binary_op.op.precedence(),
+            NixValue::UnaryOp(unary_op) => unary_op.op.precedence(),
+            NixValue::HasAttr(_) => NixValue::HAS_ATTR_PRECEDENCE,
+            NixValue::Apply(_) | NixValue::Import(_) => NixValue::APPLY_PRECEDENCE,
+            // `(a.b or c).d` et `f (a.b or c)` : le défaut s'arrêterait avant `.d`, ou
+            // l'argument avant `or`
+            NixValue::Select(select) if select.default.is_some() => NixValue::APPLY_PRECEDENCE,
+            // `f -1` serait lu comme une soustraction
+            NixValue::Int(i) if *i < 0 => NixValue::APPLY_PRECEDENCE,
+            NixValue::Float(fl) if *fl < 0.0 => NixValue::APPLY_PRECEDENCE,
+            _ => u8::MAX,
+        }
+    }
+
+    // Écrit une sous-expression, entre parenthèses si elle lie moins fort que `min_prec`
+    fn write_operand(
+        &self,
+        f: &mut fmt::Formatter,
+        opts: &FormatOptions,
+        indent: usize,
+        min_prec: u8,
+    ) -> fmt::Result {
+        if self.display_precedence() < min_prec {
+            write!(f, "(")?;
+            self.write_with_indent(f, opts, indent)?;
+            write!(f, ")")
+        } else {
+            self.write_with_indent(f, opts, indent)
+        }
+    }
+}
+
+// Rendu sur une ligne d'une liste ou d'un ensemble, pour en mesurer la largeur
+struct InlineFormatted<'a> {
+    value: &'a NixValue,
+    opts: &'a FormatOptions,
+    indent: usize,
+}
+
+impl fmt::Display for InlineFormatted<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.value.write_inline(f, self.opts, self.indent)
+    }
+}
+
+// `inherit (src) a "b";`
+fn write_inherit(
+    f: &mut fmt::Formatter,
+    inherit: &NixInherit,
+    opts: &FormatOptions,
+    indent: usize,
+) -> fmt::Result {
+    write!(f, "inherit")?;
+    if let Some(from) = &inherit.from {
+        write!(f, " (")?;
+        from.write_with_indent(f, opts, indent)?;
+        write!(f, ")")?;
+    }
+    for name in &inherit.names {
+        write!(f, " {}", quote_attr_name(name))?;
+    }
+    write!(f, ";")
+}
+
+// Largeur de clé à utiliser pour aligner les `=` (0 : pas d'alignement). Une clé plus
+// large que `max_align_width` est écrite sans remplissage
+fn aligned_key_width(keys: &[String], opts: &FormatOptions) -> usize {
+    if !opts.align_equals {
+        return 0;
+    }
+    let widths = keys.iter().map(|key| key.chars().count());
+    let max = opts.max_align_width.unwrap_or(usize::MAX);
+    widths.filter(|&width| width <= max).max().unwrap_or(0)
+}
+
+// Un nom utilisable sans guillemets comme attribut ou variable
+fn is_valid_nix_identifier(s: &str) -> bool {
+    let mut chars = s.chars();
+    match chars.next() {
+        Some(c) if c.is_alphabetic() || c == '_' => {}
+        _ => return false,
+    }
+    chars.all(|c| c.is_alphanumeric() || c == '_' || c == '-' || c == '\'')
+}
+
+// Nom d'attribut tel qu'il doit être écrit : nu si possible, entre guillemets sinon.
+// Nix n'accepte nus que les noms ASCII, et parmi les mots-clés seulement `or`
+fn quote_attr_name(name: &str) -> String {
+    let keyword = name != "or" && NIX_KEYWORDS.contains(&name);
+    if is_valid_nix_identifier(name) && name.is_ascii() && !keyword {
+        name.to_string()
+    } else {
+        format!("\"{}\"", escape_nix_string(name))
+    }
+}
+
+// Nombre de colonnes occupées dans un terminal : 0 pour les marques combinantes et
+// jointures, 2 pour les caractères pleine chasse (CJK, emoji), 1 sinon
+fn display_width(c: char) -> usize {
+    match c as u32 {
+        0x0300..=0x036F
+        | 0x1AB0..=0x1AFF
+        | 0x1DC0..=0x1DFF
+        | 0x200B..=0x200D
+        | 0x20D0..=0x20FF
+        | 0xFE00..=0xFE0F
+        | 0xFE20..=0xFE2F => 0,
+        0x1100..=0x115F
+        | 0x2E80..=0x303E
+        | 0x3041..=0x33FF
+        | 0x3400..=0x4DBF
+        | 0x4E00..=0x9FFF
+        | 0xA000..=0xA4CF
+        | 0xAC00..=0xD7A3
+        | 0xF900..=0xFAFF
+        | 0xFE30..=0xFE4F
+        | 0xFF00..=0xFF60
+        | 0xFFE0..=0xFFE6
+        | 0x1F300..=0x1F64F
+        | 0x1F900..=0x1F9FF
+        | 0x20000..=0x3FFFD => 2,
+        _ => 1,
+    }
+}
+
+// Séquences d'échappement après '\' (string normale, ou `''\` dans une string multi-lignes).
+// Comme Nix, seuls `\n`, `\t` et `\r` sont interprétés : tout autre caractère est
+// conservé tel quel (`\\`, `\"`, `\$` mais aussi `\d` -> `d`). Nix n'a pas de `\u` :
+// `"\u00e9"` vaut `u00e9`, les caractères non ASCII s'écrivent directement.
+// Pas d'échappements à la C non plus (`\0`, `\x41`, `\101`) : ne pas en ajouter ici,
+// `"\x41"` doit rester `x41` comme dans Nix
+fn unescape_char(c: char) -> char {
+    match c {
+        'n' => '\n',
+        't' => '\t',
+        'r' => '\r',
+        _ => c,
+    }
+}
+
+// Morceau d'une string multi-lignes avant le retrait de l'indentation : seul le texte
+// brut compte pour l'indentation, pas les échappements ni les interpolations
+enum IndentedPiece {
+    Text(String),
+    Escaped(String),
+    Interpolation(NixValue),
+}
+
+impl IndentedPiece {
+    // Morceau d'une `RawString`, dont l'indentation n'est pas retirée
+    fn into_raw_part(self) -> StringPart {
+        match self {
+            IndentedPiece::Text(s) | IndentedPiece::Escaped(s) => StringPart::Literal(s),
+            IndentedPiece::Interpolation(expr) => StringPart::Interpolation(expr),
+        }
+    }
+}
+
+// Décode les littéraux d'une `RawString` en relisant son texte source ; les
+// interpolations, remplacées par `${_}`, gardent leur place et leur ordre
+fn decode_raw_string(kind: StringKind, parts: &[StringPart]) -> Vec<StringPart> {
+    let delimiter = match kind {
+        StringKind::DoubleQuoted => "\"",
+        StringKind::Indented => "''",
+    };
+    let mut source = delimiter.to_string();
+    for part in parts {
+        match part {
+            StringPart::Literal(s) => source.push_str(s),
+            StringPart::Interpolation(_) => source.push_str("${_}"),
+        }
+    }
+    source.push_str(delimiter);
+    NixParser::new(&source)
+        .parse_string_parts(false)
+        .unwrap_or_default()
+}
+
+// Retire l'indentation commune des lignes d'une string `''...''`, comme Nix : les lignes
+// faites seulement d'espaces n'entrent pas dans le calcul, et une dernière ligne
+// d'espaces (avant le `''` fermant) disparaît
+fn strip_indentation(pieces: Vec<IndentedPiece>) -> Vec<StringPart> {
+    let mut min_indent = usize::MAX;
+    let mut at_line_start = true;
+    let mut current = 0;
+    for piece in &pieces {
+        match piece {
+            IndentedPiece::Text(text) => {
+                for c in text.chars() {
+                    if at_line_start {
+                        match c {
+                            ' ' => current += 1,
+                            '\n' => current = 0,
+                            _ => {
+                                at_line_start = false;
+                                min_indent = min_indent.min(current);
+                            }
+                        }
+                    } else if c == '\n' {
+                        at_line_start = true;
+                        current = 0;
+                    }
+                }
+            }
+            _ if at_line_start => {
+                at_line_start = false;
+                min_indent = min_indent.min(current);
+            }
+            _ => {}
+        }
+    }
+
+    let mut parts = Vec::new();
+    let mut literal = String::new();
+    let mut at_line_start = true;
+    let mut dropped = 0;
+    let count = pieces.len();
+    for (i, piece) in pieces.into_iter().enumerate() {
+        match piece {
+            IndentedPiece::Text(text) => {
+                let start = literal.len();
+                for c in text.chars() {
+                    if at_line_start {
+                        match c {
+                            ' ' => {
+                                if dropped >= min_indent {
+                                    literal.push(' ');
+                                }
+                                dropped += 1;
+                            }
+                            '\n' => {
+                                dropped = 0;
+                                literal.push('\n');
+                            }
+                            _ => {
+                                at_line_start = false;
+                                dropped = 0;
+                                literal.push(c);
+                            }
+                        }
+                    } else {
+                        literal.push(c);
+                        at_line_start = c == '\n';
+                    }
+                }
+                if i + 1 == count
+                    && let Some(newline) = literal[start..].rfind('\n')
+                    && literal[start + newline + 1..].chars().all(|c| c == ' ')
+                {
+                    literal.truncate(start + newline + 1);
+                }
+            }
+            IndentedPiece::Escaped(text) => {
+                at_line_start = false;
+                dropped = 0;
+                literal.push_str(&text);
+            }
+            IndentedPiece::Interpolation(expr) => {
+                at_line_start = false;
+                dropped = 0;
+                if !literal.is_empty() {
+                    parts.push(StringPart::Literal(core::mem::take(&mut literal)));
+                }
+                parts.push(StringPart::Interpolation(expr));
+            }
+        }
+    }
+    if !literal.is_empty() {
+        parts.push(StringPart::Literal(literal));
+    }
+    parts
+}
+
+// Une string multi-lignes n'est réécrite en `''...''` que si la relecture retire
+// exactement l'indentation ajoutée : aucune ligne ne doit être indentée par rapport aux
+// autres, et la fin ne doit être ni une ligne d'espaces ni un `'` collé au `''` fermant
+fn indented_string_round_trips(parts: &[StringPart]) -> bool {
+    let mut min_indent = usize::MAX;
+    let mut has_blank_spaces = false;
+    let mut at_line_start = true;
+    let mut current = 0;
+    for part in parts {
+        match part {
+            StringPart::Literal(s) => {
+                for c in s.chars() {
+                    if at_line_start {
+                        match c {
+                            ' ' => current += 1,
+                            '\n' => {
+                                has_blank_spaces |= current > 0;
+                                current = 0;
+                            }
+                            _ => {
+                                at_line_start = false;
+                                min_indent = min_indent.min(current);
+                            }
+                        }
+                    } else if c == '\n' {
+                        at_line_start = true;
+                        current = 0;
+                    }
+                }
+            }
+            StringPart::Interpolation(_) => {
+                if at_line_start {
+                    at_line_start = false;
+                    min_indent = min_indent.min(current);
+                }
+            }
+        }
+    }
+    let trailing_spaces = at_line_start && current > 0;
+    let ends_with_quote = matches!(parts.last(), Some(StringPart::Literal(s)) if s.ends_with('\''));
+    let indent_ok = min_indent == 0 || (min_indent == usize::MAX && !has_blank_spaces);
+    indent_ok && !trailing_spaces && !ends_with_quote
+}
+
+// Échappe le contenu d'une string multi-lignes : `''` et `${` y sont les seules séquences
+// à protéger
+fn escape_indented_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' if chars.peek() == Some(&'\'') => {
+                chars.next();
+                out.push_str("'''");
+            }
+            '$' if chars.peek() == Some(&'{') => out.push_str("''$"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+// Échappe le contenu d'une string normale (y compris `${`, qui ouvrirait une interpolation)
+fn escape_nix_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '"' => out.push_str("\\\""),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            '\r' => out.push_str("\\r"),
+            '$' if chars.peek() == Some(&'{') => out.push_str("\\$"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+// Fonction pour écrire un agrégat Nix dans un fichier
+#[cfg(feature = "std")]
+pub fn write_nix_file<P: AsRef<Path>>(path: P, value: &NixValue) -> Result<()> {
+    write_nix_file_with_options(path, value, &WriteOptions::default())
+}
+
+// Options d'écriture d'un fichier Nix
+#[cfg(feature = "std")]
+#[derive(Debug, Clone)]
+pub struct WriteOptions {
+    // Terminer le fichier par exactement un saut de ligne (activé par défaut)
+    pub trailing_newline: bool,
+}
+
+#[cfg(feature = "std")]
+impl Default for WriteOptions {
+    fn default() -> Self {
+        Self {
+            trailing_newline: true,
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+pub fn write_nix_file_with_options<P: AsRef<Path>>(
+    path: P,
+    value: &NixValue,
+    options: &WriteOptions,
+) -> Result<()> {
+    let mut content = value.to_string();
+    if options.trailing_newline {
+        content.truncate(content.trim_end_matches('\n').len());
+        content.push('\n');
+    }
+    write_atomically(path.as_ref(), &content)?;
+    Ok(())
+}
+
+// Écrit dans un fichier temporaire du même répertoire puis le renomme sur la cible :
+// un processus interrompu laisse l'ancien fichier intact, jamais un fichier tronqué
+#[cfg(feature = "std")]
+fn write_atomically(path: &Path, content: &str) -> std::io::Result<()> {
+    // Un lien symbolique (`/etc/nixos/configuration.nix` vers un dépôt) reste un lien
+    let target = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    let temp = match write_temp_file(&target, content) {
+        Ok(temp) => temp,
+        // Répertoire non inscriptible alors que le fichier l'est : écriture directe. Toute
+        // autre erreur (disque plein, E/S) est rendue telle quelle, la cible intacte
+        Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+            return fs::write(&target, content);
+        }
+        Err(e) => return Err(e),
+    };
+    fs::rename(&temp, &target).inspect_err(|_| {
+        let _ = fs::remove_file(&temp);
+    })
+}
+
+// Fichier temporaire complet et synchronisé, avec les permissions de la cible existante
+#[cfg(feature = "std")]
+fn write_temp_file(target: &Path, content: &str) -> std::io::Result<std::path::PathBuf> {
+    use std::io::Write;
+
+    let (mut file, temp) = create_temp_file(target)?;
+    let result = (|| {
+        file.write_all(content.as_bytes())?;
+        if let Ok(metadata) = fs::metadata(target) {
+            file.set_permissions(metadata.permissions())?;
+        }
+        file.sync_all()
+    })();
+    match result {
+        Ok(()) => Ok(temp),
+        Err(e) => {
+            let _ = fs::remove_file(&temp);
+            Err(e)
+        }
+    }
+}
+
+// Nouveau fichier à côté de `target`, au nom propre à cet appel : deux écritures
+// simultanées du même fichier, même depuis un seul processus, ne partagent jamais leur
+// fichier temporaire, et un lien déjà présent sous ce nom n'est pas suivi
+#[cfg(feature = "std")]
+fn create_temp_file(target: &Path) -> std::io::Result<(fs::File, std::path::PathBuf)> {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let name = target.file_name().unwrap_or_default().to_string_lossy();
+    loop {
+        let unique = COUNTER.fetch_add(1, Ordering::Relaxed);
+        let suffix = format!("{}-{}", std::process::id(), unique);
+        let temp = target.with_file_name(format!(".{}.tmp-{}", name, suffix));
+        match fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&temp)
+        {
+            Ok(file) => return Ok((file, temp)),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// Visiteur de l'arbre syntaxique : chaque méthode est appelée sur le nœud
+// correspondant avant que `walk` ne descende dans ses enfants
+pub trait Visitor {
+    fn visit_null(&mut self) {}
+    fn visit_bool(&mut self, _value: bool) {}
+    fn visit_int(&mut self, _value: i64) {}
+    fn visit_float(&mut self, _value: f64) {}
+    fn visit_string(&mut self, _value: &str) {}
+    fn visit_interpolated_string(&mut self, _parts: &[StringPart]) {}
+    fn visit_raw_string(&mut self, _kind: StringKind, _parts: &[StringPart]) {}
+    fn visit_indented_string(&mut self, _parts: &[StringPart]) {}
+    fn visit_path(&mut self, _kind: PathKind, _path: &str) {}
+    fn visit_interpolated_path(&mut self, _parts: &[StringPart]) {}
+    fn visit_list(&mut self, _items: &[NixValue]) {}
+    fn visit_attrset(&mut self, _attrs: &AttrSetMap) {}
+    fn visit_rec_attrset(&mut self, _attrs: &AttrSetMap) {}
+    fn visit_function(&mut self, _func: &NixFunction) {}
+    fn visit_let(&mut self, _let_expr: &NixLet) {}
+    fn visit_with(&mut self, _with_expr: &NixWith) {}
+    fn visit_assert(&mut self, _assert_expr: &NixAssert) {}
+    fn visit_if(&mut self, _if_expr: &NixIf) {}
+    fn visit_binary_op(&mut self, _binary_op: &NixBinaryOp) {}
+    fn visit_unary_op(&mut self, _unary_op: &NixUnaryOp) {}
+    fn visit_select(&mut self, _select: &NixSelect) {}
+    fn visit_has_attr(&mut self, _has_attr: &NixHasAttr) {}
+    fn visit_apply(&mut self, _apply: &NixApply) {}
+    fn visit_paren(&mut self, _inner: &NixValue) {}
+    fn visit_inherit(&mut self, _inherit: &NixInherit) {}
+    fn visit_import(&mut self, _arg: &NixValue) {}
+    fn visit_variable(&mut self, _name: &str) {}
+}
+
+// Lignes `inherit` d'un ensemble ou d'un `let`, et leurs sources `(src)`
+fn walk_inherits<V: Visitor + ?Sized>(attrs: &AttrSetMap, visitor: &mut V) {
+    for inherit in &attrs.inherits {
+        visitor.visit_inherit(inherit);
+        if let Some(from) = &inherit.from {
+            walk(from, visitor);
+        }
+    }
+}
+
+// Parcourt l'arbre en profondeur (préfixe) en appelant le visiteur sur chaque nœud
+pub fn walk<V: Visitor + ?Sized>(value: &NixValue, visitor: &mut V) {
+    match value {
+        NixValue::Null => visitor.visit_null(),
+        NixValue::Bool(b) => visitor.visit_bool(*b),
+        NixValue::Int(i) => visitor.visit_int(*i),
+        NixValue::Float(fl) => visitor.visit_float(*fl),
+        NixValue::String(s) => visitor.visit_string(s),
+
+        NixValue::InterpolatedString(parts) | NixValue::IndentedString(parts) => {
+            if matches!(value, NixValue::IndentedString(_)) {
+                visitor.visit_indented_string(parts);
+            } else {
+                visitor.visit_interpolated_string(parts);
+            }
+            for part in parts {
+                if let StringPart::Interpolation(expr) = part {
+                    walk(expr, visitor);
+                }
+            }
+        }
+        NixValue::RawString(kind, parts) => {
+            visitor.visit_raw_string(*kind, parts);
+            for part in parts {
+                if let StringPart::Interpolation(expr) = part {
+                    walk(expr, visitor);
+                }
+            }
+        }
+        NixValue::Path(kind, p) => visitor.visit_path(*kind, p),
+        NixValue::InterpolatedPath(parts) => {
+            visitor.visit_interpolated_path(parts);
+            for part in parts {
+                if let StringPart::Interpolation(expr) = part {
+                    walk(expr, visitor);
+                }
+            }
+        }
+        NixValue::Variable(v) => visitor.visit_variable(v),
+        NixValue::Import(arg) => {
+            visitor.visit_import(arg);
+            walk(arg, visitor);
+        }
+
+        NixValue::List(items) => {
+            visitor.visit_list(items);
+            for item in items {
+                walk(item, visitor);
+            }
+        }
+
+        NixValue::AttrSet(attrs) | NixValue::RecAttrSet(attrs) => {
+            if matches!(value, NixValue::RecAttrSet(_)) {
+                visitor.visit_rec_attrset(attrs);
+            } else {
+                visitor.visit_attrset(attrs);
+            }
+            walk_inherits(attrs, visitor);
+            for entry in attrs.entries() {
+                for key in entry.key.segments() {
+                    if let AttrKey::Dynamic(expr) = key {
+                        walk(expr, visitor);
+                    }
+                }
+                walk(&entry.value, visitor);
+            }
+        }
+
+        NixValue::Function(func) => {
+            visitor.visit_function(func);
+            walk(&func.body, visitor);
+        }
+
+        NixValue::Let(let_expr) => {
+            visitor.visit_let(let_expr);
+            walk_inherits(&let_expr.bindings, visitor);
+            for value in let_expr.bindings.values() {
+                walk(value, visitor);
+            }
+            walk(&let_expr.body, visitor);
+        }
+
+        NixValue::With(with_expr) => {
+            visitor.visit_with(with_expr);
+            walk(&with_expr.expr, visitor);
+            walk(&with_expr.body, visitor);
+        }
+
+        NixValue::Assert(assert_expr) => {
+            visitor.visit_assert(assert_expr);
+            walk(&assert_expr.cond, visitor);
+            walk(&assert_expr.body, visitor);
+        }
+
+        NixValue::If(if_expr) => {
+            visitor.visit_if(if_expr);
+            walk(&if_expr.cond, visitor);
+            walk(&if_expr.then_branch, visitor);
+            walk(&if_expr.else_branch, visitor);
+        }
+
+        NixValue::BinaryOp(binary_op) => {
+            visitor.visit_binary_op(binary_op);
+            walk(&binary_op.lhs, visitor);
+            walk(&binary_op.rhs, visitor);
+        }
+
+        NixValue::UnaryOp(unary_op) => {
+            visitor.visit_unary_op(unary_op);
+            walk(&unary_op.operand, visitor);
+        }
+
+        NixValue::Select(select) => {
+            visitor.visit_select(select);
+            walk(&select.expr, visitor);
+            for key in &select.path {
+                if let AttrKey::Dynamic(expr) = key {
+                    walk(expr, visitor);
+                }
+            }
+            if let Some(default) = &select.default {
+                walk(default, visitor);
+            }
+        }
+
+        NixValue::HasAttr(has_attr) => {
+            visitor.visit_has_attr(has_attr);
+            walk(&has_attr.expr, visitor);
+            for key in &has_attr.path {
+                if let AttrKey::Dynamic(expr) = key {
+                    walk(expr, visitor);
+                }
+            }
+        }
+
+        NixValue::Apply(apply) => {
+            visitor.visit_apply(apply);
+            walk(&apply.func, visitor);
+            walk(&apply.arg, visitor);
+        }
+
+        NixValue::Paren(inner) => {
+            visitor.visit_paren(inner);
+            walk(inner, visitor);
+        }
+    }
+}
+
+// Mots-clés du langage, inutilisables comme noms de variables
+const NIX_KEYWORDS: &[&str] = &[
+    "assert", "else", "if", "in", "inherit", "let", "or", "rec", "then", "with",
+];
+
+// Avertissement d'un lint (le code est valide mais discutable) ou d'un mode tolérant
+// du parseur (le code n'est pas du Nix valide mais a pu être lu)
+#[derive(Debug, Clone, PartialEq)]
+pub struct Warning {
+    pub kind: WarningKind,
+    pub name: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WarningKind {
+    // Nom d'attribut qui doit être écrit entre guillemets
+    NeedsQuoting,
+    // Nom identique à un mot-clé
+    KeywordName,
+    // Liaison terminée par `,` au lieu de `;` (`NixParser::with_comma_separators`)
+    CommaSeparator,
+    // Liaison sans séparateur, toléré par le même mode
+    MissingSeparator,
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            WarningKind::NeedsQuoting => {
+                write!(
+                    f,
+                    "L'attribut \"{}\" doit être écrit entre guillemets",
+                    self.name
+                )
+            }
+            WarningKind::KeywordName => write!(f, "Le nom '{}' est un mot-clé de Nix", self.name),
+            WarningKind::CommaSeparator => {
+                write!(
+                    f,
+                    "La liaison '{}' se termine par ',' au lieu de ';'",
+                    self.name
+                )
+            }
+            WarningKind::MissingSeparator => {
+                write!(f, "La liaison '{}' n'est pas terminée par ';'", self.name)
+            }
+        }
+    }
+}
+
+impl NixValue {
+    // Signale les clés d'attributs à mettre entre guillemets et les noms
+    // (attributs, liaisons, paramètres, variables) identiques à un mot-clé
+    pub fn validate_identifiers(&self) -> Vec<Warning> {
+        let mut lint = IdentifierLint {
+            warnings: Vec::new(),
+        };
+        walk(self, &mut lint);
+        lint.warnings
+    }
+}
+
+struct IdentifierLint {
+    warnings: Vec<Warning>,
+}
+
+impl IdentifierLint {
+    fn check_name(&mut self, name: &str) {
+        if NIX_KEYWORDS.contains(&name) {
+            self.warnings.push(Warning {
+                kind: WarningKind::KeywordName,
+                name: name.to_string(),
+            });
+        }
+    }
+
+    fn check_bindings(&mut self, attrs: &AttrSetMap) {
+        let names = attrs
+            .keys()
+            .flat_map(AttrPath::segments)
+            .filter_map(AttrKey::as_static);
+        for name in names {
+            if !is_valid_nix_identifier(name) {
+                let name = name.to_string();
+                self.warnings.push(Warning {
+                    kind: WarningKind::NeedsQuoting,
+                    name,
+                });
+            } else {
+                self.check_name(name);
+            }
+        }
+    }
+}
+
+impl Visitor for IdentifierLint {
+    fn visit_attrset(&mut self, attrs: &AttrSetMap) {
+        self.check_bindings(attrs);
+    }
+
+    fn visit_rec_attrset(&mut self, attrs: &AttrSetMap) {
+        self.check_bindings(attrs);
+    }
+
+    fn visit_let(&mut self, let_expr: &NixLet) {
+        self.check_bindings(&let_expr.bindings);
+    }
+
+    fn visit_function(&mut self, func: &NixFunction) {
+        for param in &func.params {
+            self.check_name(param);
+        }
+    }
+
+    fn visit_variable(&mut self, name: &str) {
+        self.check_name(name);
+    }
+}
+
+// Construction acceptée par le parseur mais rejetée par Nix
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValidationError {
+    pub kind: ValidationErrorKind,
+    pub name: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValidationErrorKind {
+    // Paramètre répété dans un motif `{ a, a }:`
+    DuplicateParam,
+    // Attribut ou liaison `let` définis deux fois (`inherit` compris)
+    DuplicateBinding,
+    // `import` d'un texte qui ne s'écrit pas comme un chemin
+    InvalidImport,
+    // Attribut à la fois valeur et ensemble parent (`a = 1; a.b = 2;`)
+    ConflictingAttribute,
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            ValidationErrorKind::DuplicateParam => {
+                write!(f, "Le paramètre '{}' est déclaré plusieurs fois", self.name)
+            }
+            ValidationErrorKind::DuplicateBinding => {
+                write!(f, "L'attribut '{}' est défini plusieurs fois", self.name)
+            }
+            ValidationErrorKind::InvalidImport => {
+                write!(f, "'{}' n'est pas un chemin importable", self.name)
+            }
+            ValidationErrorKind::ConflictingAttribute => {
+                write!(
+                    f,
+                    "L'attribut '{}' est à la fois une valeur et un ensemble",
+                    self.name
+                )
+            }
+        }
+    }
+}
+
+impl NixValue {
+    // Vérifie toutes les constructions de l'arbre et renvoie l'ensemble des problèmes
+    // trouvés, dans l'ordre du parcours
+    pub fn validate(&self) -> Result<(), Vec<ValidationError>> {
+        let mut validator = Validator { errors: Vec::new() };
+        walk(self, &mut validator);
+        if validator.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(validator.errors)
+        }
+    }
+}
+
+struct Validator {
+    errors: Vec<ValidationError>,
+}
+
+impl Validator {
+    fn report(&mut self, kind: ValidationErrorKind, name: &str) {
+        self.errors.push(ValidationError {
+            kind,
+            name: name.to_string(),
+        });
+    }
+
+    // `a.b = 1; a.b = 2;` et `inherit a; a = 1;` ; `a.b = 1; a.c = 2;` reste valide
+    fn check_bindings(&mut self, attrs: &AttrSetMap) {
+        let mut seen = BTreeSet::new();
+        let names = attrs.inherited_names().map(str::to_string);
+        let is_static = |key: &&AttrPath| key.segments().iter().all(|k| k.as_static().is_some());
+        let keys = attrs.keys().filter(is_static);
+        for name in names.chain(keys.map(AttrPath::to_string)) {
+            if !seen.insert(name.clone()) {
+                self.report(ValidationErrorKind::DuplicateBinding, &name);
+            }
+        }
+    }
+}
+
+impl Visitor for Validator {
+    fn visit_attrset(&mut self, attrs: &AttrSetMap) {
+        self.check_bindings(attrs);
+    }
+
+    fn visit_rec_attrset(&mut self, attrs: &AttrSetMap) {
+        self.check_bindings(attrs);
+    }
+
+    fn visit_let(&mut self, let_expr: &NixLet) {
+        self.check_bindings(&let_expr.bindings);
+    }
+
+    fn visit_function(&mut self, func: &NixFunction) {
+        for (i, param) in func.params.iter().enumerate() {
+            if func.params[..i].contains(param) {
+                self.report(ValidationErrorKind::DuplicateParam, param);
+            }
+        }
+    }
+
+    // Un chemin construit par programme doit se relire comme le même chemin
+    fn visit_import(&mut self, arg: &NixValue) {
+        let NixValue::Path(..) = arg else {
+            return;
+        };
+        let path = arg.to_string();
+        if parse_nix_string(&path).ok().as_ref() != Some(arg) {
+            self.report(ValidationErrorKind::InvalidImport, &path);
+        }
+    }
+}
+
+// Passage entre clés pointées et ensembles imbriqués
+impl NixValue {
+    // `{ services = { nginx.enable = true; }; }` devient `{ services.nginx.enable = true; }`.
+    // Les ensembles vides, `rec` ou contenant des `inherit` restent des valeurs
+    pub fn flatten_attrs(&self) -> Result<NixValue, ValidationError> {
+        let NixValue::AttrSet(attrs) = self else {
+            return Ok(self.clone());
+        };
+        let mut flat = AttrSetMap::new();
+        flat.inherits = attrs.inherits.clone();
+        flat.trailing_comments = attrs.trailing_comments.clone();
+        flatten_into(&mut flat, &[], attrs);
+
+        let paths: Vec<&[AttrKey]> = flat.keys().map(AttrPath::segments).collect();
+        for (i, path) in paths.iter().enumerate() {
+            let is_parent =
+                |other: &&[AttrKey]| other.len() > path.len() && other.starts_with(path);
+            if paths[..i].contains(path) || paths.iter().any(is_parent) {
+                let kind = ValidationErrorKind::ConflictingAttribute;
+                return Err(ValidationError {
+                    kind,
+                    name: AttrPath(path.to_vec()).to_string(),
+                });
+            }
+        }
+        Ok(NixValue::AttrSet(flat))
+    }
+
+    // Inverse de `flatten_attrs` : `{ a.b = 1; a.c = 2; }` devient `{ a = { b = 1; c = 2; }; }`.
+    // Les définitions d'un même ensemble se complètent, à tous les niveaux
+    pub fn unflatten_attrs(&self) -> Result<NixValue, ValidationError> {
+        let NixValue::AttrSet(attrs) = self else {
+            return Ok(self.clone());
+        };
+        let mut nested = AttrSetMap::new();
+        nested.inherits = attrs.inherits.clone();
+        nested.trailing_comments = attrs.trailing_comments.clone();
+        for entry in attrs.entries() {
+            unflatten_into(&mut nested, entry.key.segments(), entry)?;
+        }
+        Ok(NixValue::AttrSet(nested))
+    }
+}
+
+fn flatten_into(flat: &mut AttrSetMap, prefix: &[AttrKey], attrs: &AttrSetMap) {
+    for entry in attrs.entries() {
+        let key = [prefix, entry.key.segments()].concat();
+        match &entry.value {
+            NixValue::AttrSet(nested)
+                if !nested.entries().is_empty() && nested.inherits.is_empty() =>
+            {
+                flatten_into(flat, &key, nested)
+            }
+            _ => flat.push_entry(AttrEntry {
+                key: AttrPath(key),
+                ..entry.clone()
+            }),
+        }
+    }
+}
+
+// Place `entry` sous `path` dans `attrs` ; l'imbrication s'arrête au premier segment
+// calculé (`a.${x}.b` donne `a = { ${x}.b = ...; }`)
+fn unflatten_into(
+    attrs: &mut AttrSetMap,
+    path: &[AttrKey],
+    entry: &AttrEntry,
+) -> Result<(), ValidationError> {
+    let conflict = || ValidationError {
+        kind: ValidationErrorKind::ConflictingAttribute,
+        name: entry.key.to_string(),
+    };
+    let Some((AttrKey::Static(name), rest)) = path.split_first() else {
+        let value = entry.value.unflatten_attrs()?;
+        attrs.push_entry(AttrEntry {
+            key: AttrPath(path.to_vec()),
+            value,
+            ..entry.clone()
+        });
+        return Ok(());
+    };
+    if rest.is_empty() {
+        let value = entry.value.unflatten_attrs()?;
+        match (attrs.get_mut(name), value) {
+            (None, value) => attrs.push_entry(AttrEntry {
+                key: name.as_str().into(),
+                value,
+                ..entry.clone()
+            }),
+            (Some(NixValue::AttrSet(existing)), NixValue::AttrSet(new)) => {
+                for new_entry in new.entries() {
+                    unflatten_into(existing, new_entry.key.segments(), new_entry)?;
+                }
+            }
+            _ => return Err(conflict()),
+        }
+        return Ok(());
+    }
+    if attrs.get(name).is_none() {
+        attrs.insert(name.as_str(), NixValue::AttrSet(AttrSetMap::new()));
+    }
+    match attrs.get_mut(name) {
+        Some(NixValue::AttrSet(nested)) => unflatten_into(nested, rest, entry),
+        _ => Err(conflict()),
+    }
+}
+
+// Renommage d'attributs, pour les migrations d'options
+impl NixValue {
+    // Déplace l'attribut `from` sous le chemin `to`, que ses clés soient imbriquées ou
+    // pointées (`services.old.enable = true;` devient `services.new.enable = true;`).
+    // Le chemin manquant est créé par une clé pointée, les commentaires suivent leur
+    // liaison. Renvoie `false` sans rien changer si `from` n'existe pas ou si `to` existe
+    pub fn rename_attr(&mut self, from: &[&str], to: &[&str]) -> bool {
+        if from.is_empty() || to.is_empty() || to.starts_with(from) || self.get_path(to).is_some() {
+            return false;
+        }
+        let (NixValue::AttrSet(attrs) | NixValue::RecAttrSet(attrs)) = self else {
+            return false;
+        };
+        let taken = take_attr(attrs, from);
+        let renamed = !taken.is_empty();
+        for (rest, entry) in taken {
+            let path: Vec<AttrKey> = to.iter().map(|&name| name.into()).chain(rest).collect();
+            place_entry(attrs, &path, entry);
+        }
+        renamed
+    }
+}
+
+// Retire de `attrs` les liaisons `path = ...` et `path.x = ...`, en cherchant aussi dans
+// les ensembles imbriqués, avec pour chacune la suite `x` de sa clé après `path`.
+// Un ensemble imbriqué vidé par le retrait disparaît avec lui
+fn take_attr(attrs: &mut AttrSetMap, path: &[&str]) -> Vec<(Vec<AttrKey>, AttrEntry)> {
+    let mut taken = Vec::new();
+    let mut i = 0;
+    while i < attrs.entries.len() {
+        let key = attrs.entries[i].key.segments();
+        let common = key.iter().zip(path).take_while(|(k, p)| *k == **p).count();
+        if common == path.len() {
+            let entry = attrs.entries.remove(i);
+            taken.push((entry.key.segments()[common..].to_vec(), entry));
+            continue;
+        }
+        if common == key.len()
+            && let NixValue::AttrSet(nested) = &mut attrs.entries[i].value
+        {
+            let before = taken.len();
+            taken.extend(take_attr(nested, &path[common..]));
+            if taken.len() > before && nested.is_empty() {
+                attrs.entries.remove(i);
+                continue;
+            }
+        }
+        i += 1;
+    }
+    taken
+}
+
+// Place `entry` sous `path` : dans l'ensemble imbriqué qui couvre déjà un début du
+// chemin s'il y en a un, sinon sous une clé pointée portant le reste du chemin
+fn place_entry(attrs: &mut AttrSetMap, path: &[AttrKey], entry: AttrEntry) {
+    let parent = attrs.entries_mut().iter_mut().find(|e| {
+        let key = e.key.segments();
+        key.len() < path.len() && path.starts_with(key) && matches!(e.value, NixValue::AttrSet(_))
+    });
+    match parent {
+        Some(AttrEntry {
+            key,
+            value: NixValue::AttrSet(nested),
+            ..
+        }) => place_entry(nested, &path[key.segments().len()..], entry),
+        _ => attrs.push_entry(AttrEntry {
+            key: AttrPath(path.to_vec()),
+            ..entry
+        }),
+    }
+}
+
+// Différence entre deux arbres (`NixValue::diff`). Le chemin joint les clés par des
+// points, les indices de liste y figurent nus : `ports.0` (une clé `0` s'écrit `"0"`)
+#[derive(Debug, Clone, PartialEq)]
+pub enum Change {
+    Added {
+        path: String,
+        value: NixValue,
+    },
+    Removed {
+        path: String,
+        value: NixValue,
+    },
+    Modified {
+        path: String,
+        old: NixValue,
+        new: NixValue,
+    },
+}
+
+impl Change {
+    pub fn path(&self) -> &str {
+        match self {
+            Change::Added { path, .. }
+            | Change::Removed { path, .. }
+            | Change::Modified { path, .. } => path,
+        }
+    }
+}
+
+// Une ligne par changement, à la manière d'un diff : `+`, `-` ou `~`
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Change::Added { path, value } => {
+                write!(f, "+ {} = {}", path, value.to_compact_string())
+            }
+            Change::Removed { path, value } => {
+                write!(f, "- {} = {}", path, value.to_compact_string())
+            }
+            Change::Modified { path, old, new } => {
+                write!(
+                    f,
+                    "~ {}: {} -> {}",
+                    path,
+                    old.to_compact_string(),
+                    new.to_compact_string()
+                )
+            }
+        }
+    }
+}
+
+// Comparaison de deux arbres
+impl NixValue {
+    // Changements qui mènent de `self` à `other`, en parcourant les deux arbres en
+    // parallèle : les ensembles sont comparés clé par clé (ordre et forme pointée
+    // indifférents), les listes indice par indice. Tout autre nœud qui diffère au sens
+    // de `semantic_eq` est un `Modified` entier
+    pub fn diff(&self, other: &NixValue) -> Vec<Change> {
+        let mut changes = Vec::new();
+        diff_into(String::new(), self, other, &mut changes);
+        changes
+    }
+}
+
+// Chemin d'un enfant au format de `Change::path`
+fn child_path(path: &str, segment: &str) -> String {
+    if path.is_empty() {
+        segment.to_string()
+    } else {
+        format!("{}.{}", path, segment)
+    }
+}
+
+fn diff_into(path: String, old: &NixValue, new: &NixValue, changes: &mut Vec<Change>) {
+    let child_path = |segment: &str| child_path(&path, segment);
+    match (old, new) {
+        (NixValue::AttrSet(_), NixValue::AttrSet(_))
+        | (NixValue::RecAttrSet(_), NixValue::RecAttrSet(_)) => {
+            let old_bindings = diff_bindings(old);
+            let new_bindings = diff_bindings(new);
+            for (key, old_value) in &old_bindings {
+                match new_bindings.iter().find(|(k, _)| k == key) {
+                    Some((_, new_value)) => {
+                        diff_into(child_path(key), old_value, new_value, changes)
+                    }
+                    None => changes.push(Change::Removed {
+                        path: child_path(key),
+                        value: old_value.clone(),
+                    }),
+                }
+            }
+            for (key, new_value) in &new_bindings {
+                if !old_bindings.iter().any(|(k, _)| k == key) {
+                    let (path, value) = (child_path(key), new_value.clone());
+                    changes.push(Change::Added { path, value });
+                }
+            }
+        }
+        (NixValue::List(old_items), NixValue::List(new_items)) => {
+            for (i, (a, b)) in old_items.iter().zip(new_items).enumerate() {
+                diff_into(child_path(&i.to_string()), a, b, changes);
+            }
+            for (i, value) in old_items.iter().enumerate().skip(new_items.len()) {
+                let (path, value) = (child_path(&i.to_string()), value.clone());
+                changes.push(Change::Removed { path, value });
+            }
+            for (i, value) in new_items.iter().enumerate().skip(old_items.len()) {
+                let (path, value) = (child_path(&i.to_string()), value.clone());
+                changes.push(Change::Added { path, value });
+            }
+        }
+        _ if old.semantic_eq(new) => {}
+        _ => changes.push(Change::Modified {
+            path,
+            old: old.clone(),
+            new: new.clone(),
+        }),
+    }
+}
+
+// Liaisons d'un ensemble sous forme imbriquée, `inherit` compris : `inherit x;` vaut
+// `x = x;` et `inherit (src) x;` vaut `x = src.x;`
+fn diff_bindings(value: &NixValue) -> Vec<(String, NixValue)> {
+    let value = value.unflatten_attrs().unwrap_or_else(|_| value.clone());
+    let (NixValue::AttrSet(attrs) | NixValue::RecAttrSet(attrs)) = value else {
+        return Vec::new();
+    };
+    let mut bindings = Vec::new();
+    for inherit in &attrs.inherits {
+        for name in &inherit.names {
+            let value = match &inherit.from {
+                None => NixValue::Variable(name.clone()),
+                Some(from) => NixValue::Select(Box::new(NixSelect {
+                    expr: from.clone(),
+                    path: vec![name.as_str().into()],
+                    default: None,
+                })),
+            };
+            bindings.push((quote_attr_name(name), value));
+        }
+    }
+    for (key, value) in attrs.iter() {
+        bindings.push((key.to_string(), value.clone()));
+    }
+    bindings
+}
+
+// Valeur sans équivalent dans le format d'export (`NixValue::to_toml_string`) : `null`,
+// fonction, chemin ou expression non évaluée. `path` suit la convention de `Change`,
+// vide pour la racine
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConvertError {
+    pub path: String,
+    pub type_name: &'static str,
+}
+
+impl fmt::Display for ConvertError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.path.is_empty() {
+            write!(
+                f,
+                "Une valeur de type {} n'a pas d'équivalent TOML",
+                self.type_name
+            )
+        } else {
+            write!(
+                f,
+                "'{}' (de type {}) n'a pas d'équivalent TOML",
+                self.path, self.type_name
+            )
+        }
+    }
+}
+
+impl core::error::Error for ConvertError {}
+
+// Export TOML
+impl NixValue {
+    // Document TOML d'un ensemble déjà évalué (voir `eval`) : les ensembles imbriqués
+    // deviennent des tables `[a.b]`, ceux contenus dans une liste des tables en ligne.
+    // Les clés pointées sont regroupées comme par `unflatten_attrs`. Export provisoire
+    // écrit à la main, faute de dépendance : le nom `to_toml` reste libre pour une
+    // conversion vers le crate `toml` derrière une feature
+    pub fn to_toml_string(&self) -> Result<String, ConvertError> {
+        let attrs = self.as_attrset().ok_or_else(|| ConvertError {
+            path: String::new(),
+            type_name: self.type_name(),
+        })?;
+        let mut out = String::new();
+        write_toml_table(&mut out, "", "", attrs)?;
+        Ok(out)
+    }
+}
+
+// Écrit les valeurs de la table puis ses sous-tables ; `header` est le nom TOML de la
+// table, `path` son chemin Nix pour les erreurs
+fn write_toml_table(
+    out: &mut String,
+    header: &str,
+    path: &str,
+    attrs: &AttrSetMap,
+) -> Result<(), ConvertError> {
+    let (tables, values): (Vec<_>, Vec<_>) = toml_bindings(path, attrs)?
+        .into_iter()
+        .partition(|(_, v)| v.as_attrset().is_some());
+    if !header.is_empty() {
+        if !out.is_empty() {
+            out.push('\n');
+        }
+        out.push_str(&format!("[{}]\n", header));
+    }
+    for (key, value) in &values {
+        let inline = toml_inline(&child_path(path, &quote_attr_name(key)), value)?;
+        out.push_str(&format!("{} = {}\n", toml_key(key), inline));
+    }
+    for (key, value) in &tables {
+        let header = match header {
+            "" => toml_key(key),
+            _ => format!("{}.{}", header, toml_key(key)),
+        };
+        let child = child_path(path, &quote_attr_name(key));
+        let Some(attrs) = value.as_attrset() else {
+            return Err(ConvertError {
+                path: child,
+                type_name: value.type_name(),
+            });
+        };
+        write_toml_table(out, &header, &child, attrs)?;
+    }
+    Ok(())
+}
+
+// Liaisons d'un ensemble sous forme imbriquée ; un `inherit` ou une clé calculée
+// restent des expressions et n'ont pas d'équivalent
+fn toml_bindings(path: &str, attrs: &AttrSetMap) -> Result<Vec<(String, NixValue)>, ConvertError> {
+    let unevaluated = |name: &str| ConvertError {
+        path: child_path(path, &quote_attr_name(name)),
+        type_name: "expression",
+    };
+    if let Some(name) = attrs
+        .inherits
+        .iter()
+        .flat_map(|inherit| &inherit.names)
+        .next()
+    {
+        return Err(unevaluated(name));
+    }
+    let nested = match NixValue::AttrSet(attrs.clone()).unflatten_attrs() {
+        Ok(NixValue::AttrSet(nested)) => nested,
+        Ok(_) => unreachable!("unflatten_attrs keeps an attrset"),
+        Err(err) => {
+            return Err(ConvertError {
+                path: err.name,
+                type_name: "set",
+            });
+        }
+    };
+    let mut bindings = Vec::new();
+    for (key, value) in nested.iter() {
+        match key.segments() {
+            [AttrKey::Static(name)] => bindings.push((name.clone(), value.clone())),
+            _ => return Err(unevaluated(&key.to_string())),
+        }
+    }
+    Ok(bindings)
+}
+
+fn toml_inline(path: &str, value: &NixValue) -> Result<String, ConvertError> {
+    let unsupported = || ConvertError {
+        path: path.to_string(),
+        type_name: value.type_name(),
+    };
+    Ok(match value {
+        NixValue::Bool(b) => b.to_string(),
+        NixValue::Int(i) => i.to_string(),
+        NixValue::Float(fl) if fl.is_nan() => "nan".to_string(),
+        NixValue::Float(fl) if fl.is_infinite() => {
+            (if *fl > 0.0 { "inf" } else { "-inf" }).to_string()
+        }
+        // `{:?}` garde toujours un point ou un exposant : `1.0`, `1e300`
+        NixValue::Float(fl) => format!("{:?}", fl),
+        NixValue::List(items) => {
+            let items = items
+                .iter()
+                .enumerate()
+                .map(|(i, item)| toml_inline(&child_path(path, &i.to_string()), item))
+                .collect::<Result<Vec<_>, _>>()?;
+            format!("[{}]", items.join(", "))
+        }
+        NixValue::AttrSet(attrs) | NixValue::RecAttrSet(attrs) => {
+            let mut fields = Vec::new();
+            for (key, value) in toml_bindings(path, attrs)? {
+                let inline = toml_inline(&child_path(path, &quote_attr_name(&key)), &value)?;
+                fields.push(format!("{} = {}", toml_key(&key), inline));
+            }
+            match fields.is_empty() {
+                true => "{}".to_string(),
+                false => format!("{{ {} }}", fields.join(", ")),
+            }
+        }
+        _ => toml_string(value.as_str().ok_or_else(unsupported)?),
+    })
+}
+
+// Clé nue quand TOML l'accepte, sinon entre guillemets
+fn toml_key(key: &str) -> String {
+    let bare = !key.is_empty()
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    if bare {
+        key.to_string()
+    } else {
+        toml_string(key)
+    }
+}
+
+fn toml_string(s: &str) -> String {
+    let mut out = String::from('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            '\r' => out.push_str("\\r"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04X}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+// Évaluation des expressions constantes
+impl NixValue {
+    // Calcule la valeur d'une expression sans fonctions : littéraux, opérateurs,
+    // `let`, `rec`, sélections, `if` et `with` sur un ensemble connu.
+    // Le résultat est entièrement évalué, listes et ensembles compris
+    pub fn eval(&self) -> Result<NixValue, EvalError> {
+        Evaluator::default().eval(self, &Rc::new(Scope::default()))
+    }
+
+    // Nom du type tel que l'affiche Nix dans ses erreurs
+    fn type_name(&self) -> &'static str {
+        match self {
+            NixValue::Null => "null",
+            NixValue::Bool(_) => "bool",
+            NixValue::Int(_) => "int",
+            NixValue::Float(_) => "float",
+            NixValue::String(_)
+            | NixValue::InterpolatedString(_)
+            | NixValue::IndentedString(_)
+            | NixValue::RawString(..) => "string",
+            NixValue::Path(..) | NixValue::InterpolatedPath(_) => "path",
+            NixValue::List(_) => "list",
+            NixValue::AttrSet(_) | NixValue::RecAttrSet(_) => "set",
+            NixValue::Function(_) => "lambda",
+            _ => "expression",
+        }
+    }
+}
+
+// Liaison d'une portée : expression évaluée à la demande dans la portée qui la
+// définit (`let`, `rec`), ou valeur déjà calculée (`with`)
+enum Binding<'a> {
+    Thunk(&'a NixValue),
+    Value(NixValue),
+    // Nom d'une ligne `inherit` d'un ensemble récursif ou d'un `let`
+    Inherited(&'a NixInherit),
+}
+
+#[derive(Default)]
+struct Scope<'a> {
+    bindings: Vec<(String, Binding<'a>)>,
+    // Les noms d'un `with` ne masquent jamais une liaison lexicale
+    is_with: bool,
+    parent: Option<Rc<Scope<'a>>>,
+}
+
+impl<'a> Scope<'a> {
+    fn child(
+        parent: &Rc<Scope<'a>>,
+        bindings: Vec<(String, Binding<'a>)>,
+        is_with: bool,
+    ) -> Rc<Self> {
+        Rc::new(Scope {
+            bindings,
+            is_with,
+            parent: Some(Rc::clone(parent)),
+        })
+    }
+
+    // Portées récursives : chaque valeur voit toutes les liaisons de l'ensemble
+    fn binds(&self, name: &str) -> bool {
+        self.bindings.iter().any(|(n, _)| n == name)
+            || self
+                .parent
+                .as_ref()
+                .is_some_and(|parent| parent.binds(name))
+    }
+
+    fn recursive(parent: &Rc<Scope<'a>>, attrs: &'a AttrSetMap) -> Result<Rc<Self>, EvalError> {
+        let mut bindings = Vec::new();
+        for inherit in &attrs.inherits {
+            for name in &inherit.names {
+                bindings.push((name.clone(), Binding::Inherited(inherit)));
+            }
+        }
+        for entry in attrs.entries() {
+            match entry.key.as_static() {
+                Some(name) => bindings.push((name.to_string(), Binding::Thunk(&entry.value))),
+                None => {
+                    return Err(EvalError::Unsupported(format!(
+                        "liaison récursive `{}`",
+                        entry.key
+                    )));
+                }
+            }
+        }
+        Ok(Scope::child(parent, bindings, false))
+    }
+}
+
+#[derive(Default)]
+struct Evaluator<'a> {
+    // Liaisons en cours d'évaluation : les retrouver signale une boucle
+    in_progress: Vec<&'a NixValue>,
+}
+
+impl<'a> Evaluator<'a> {
+    fn eval(&mut self, expr: &'a NixValue, scope: &Rc<Scope<'a>>) -> Result<NixValue, EvalError> {
+        match expr {
+            NixValue::Null
+            | NixValue::Bool(_)
+            | NixValue::Int(_)
+            | NixValue::Float(_)
+            | NixValue::String(_) => Ok(expr.clone()),
+
+            // `<nixpkgs>` dépend de NIX_PATH, inconnu ici
+            NixValue::Path(PathKind::SearchPath, p) => Err(EvalError::Unsupported(format!(
+                "chemin de recherche <{}>",
+                p
+            ))),
+            NixValue::Path(..) => Ok(expr.clone()),
+
+            NixValue::InterpolatedString(parts)
+            | NixValue::IndentedString(parts)
+            | NixValue::InterpolatedPath(parts) => {
+                let mut result = String::new();
+                for part in parts {
+                    match part {
+                        StringPart::Literal(s) => result.push_str(s),
+                        StringPart::Interpolation(e) => self.interpolate(e, scope, &mut result)?,
+                    }
+                }
+                if matches!(expr, NixValue::InterpolatedPath(_)) {
+                    Ok(NixValue::Path(PathKind::of(&result), result))
+                } else {
+                    Ok(NixValue::String(result))
+                }
+            }
+
+            NixValue::RawString(kind, parts) => {
+                // Littéraux décodés, mais interpolations évaluées depuis l'arbre d'origine
+                let mut interpolations = parts.iter().filter_map(|part| match part {
+                    StringPart::Interpolation(e) => Some(e),
+                    StringPart::Literal(_) => None,
+                });
+                let mut result = String::new();
+                for part in decode_raw_string(*kind, parts) {
+                    match part {
+                        StringPart::Literal(s) => result.push_str(&s),
+                        StringPart::Interpolation(_) => {
+                            if let Some(e) = interpolations.next() {
+                                self.interpolate(e, scope, &mut result)?;
+                            }
+                        }
+                    }
+                }
+                Ok(NixValue::String(result))
+            }
+
+            NixValue::List(items) => items
+                .iter()
+                .map(|item| self.eval(item, scope))
+                .collect::<Result<_, _>>()
+                .map(NixValue::List),
+
+            NixValue::AttrSet(attrs) => self.eval_attrset(attrs, scope),
+            NixValue::RecAttrSet(attrs) => {
+                let inner = Scope::recursive(scope, attrs)?;
+                self.eval_attrset(attrs, &inner)
+            }
+
+            NixValue::Let(let_expr) => {
+                let inner = Scope::recursive(scope, &let_expr.bindings)?;
+                self.eval(&let_expr.body, &inner)
+            }
+
+            NixValue::With(with_expr) => {
+                // Une portée inconnue (`with pkgs;`) ne peut pas être résolue ici
+                let attrs = match self.eval(&with_expr.expr, scope) {
+                    Ok(NixValue::AttrSet(attrs)) => attrs,
+                    Ok(other) => {
+                        return Err(EvalError::TypeMismatch(format!(
+                            "`with` attend un ensemble, pas {}",
+                            other.type_name()
+                        )));
+                    }
+                    Err(EvalError::UndefinedVariable(name)) => {
+                        return Err(EvalError::Unsupported(format!(
+                            "`with` sur la variable inconnue {}",
+                            name
+                        )));
+                    }
+                    Err(e) => return Err(e),
+                };
+                // Les ensembles évalués n'ont que des clés statiques d'un segment
+                let bindings = attrs
+                    .into_iter()
+                    .filter_map(|(key, value)| {
+                        Some((key.as_static()?.to_string(), Binding::Value(value)))
+                    })
+                    .collect();
+                let inner = Scope::child(scope, bindings, true);
+                self.eval(&with_expr.body, &inner)
+            }
+
+            NixValue::Assert(assert_expr) => {
+                if self.eval_bool(&assert_expr.cond, scope)? {
+                    self.eval(&assert_expr.body, scope)
+                } else {
+                    Err(EvalError::AssertionFailed)
+                }
+            }
+
+            NixValue::If(if_expr) => {
+                if self.eval_bool(&if_expr.cond, scope)? {
+                    self.eval(&if_expr.then_branch, scope)
+                } else {
+                    self.eval(&if_expr.else_branch, scope)
+                }
+            }
+
+            NixValue::BinaryOp(binary_op) => self.eval_binary(binary_op, scope),
+
+            NixValue::UnaryOp(unary_op) => {
+                match (unary_op.op, self.eval(&unary_op.operand, scope)?) {
+                    (UnOp::Not, NixValue::Bool(b)) => Ok(NixValue::Bool(!b)),
+                    (UnOp::Neg, NixValue::Int(i)) => i
+                        .checked_neg()
+                        .map(NixValue::Int)
+                        .ok_or(EvalError::IntegerOverflow),
+                    (UnOp::Neg, NixValue::Float(fl)) => Ok(NixValue::Float(-fl)),
+                    (op, other) => Err(EvalError::TypeMismatch(format!(
+                        "`{}` ne s'applique pas à une valeur de type {}",
+                        op,
+                        other.type_name()
+                    ))),
+                }
+            }
+
+            NixValue::Select(select) => {
+                let mut current = self.eval(&select.expr, scope)?;
+                for key in &select.path {
+                    let name = self.eval_key(key, scope)?;
+                    let found = match current {
+                        NixValue::AttrSet(mut attrs) => attrs.remove(&name),
+                        _ => None,
+                    };
+                    current = match (found, &select.default) {
+                        (Some(value), _) => value,
+                        (None, Some(default)) => return self.eval(default, scope),
+                        (None, None) => return Err(EvalError::MissingAttribute(name)),
+                    };
+                }
+                Ok(current)
+            }
+
+            NixValue::HasAttr(has_attr) => {
+                let mut current = self.eval(&has_attr.expr, scope)?;
+                for key in &has_attr.path {
+                    let name = self.eval_key(key, scope)?;
+                    // Un chemin qui traverse autre chose qu'un ensemble donne `false`
+                    current = match current {
+                        NixValue::AttrSet(mut attrs) => match attrs.remove(&name) {
+                            Some(value) => value,
+                            None => return Ok(NixValue::Bool(false)),
+                        },
+                        _ => return Ok(NixValue::Bool(false)),
+                    };
+                }
+                Ok(NixValue::Bool(true))
+            }
+
+            NixValue::Variable(name) => self.lookup(name, scope),
+            NixValue::Paren(inner) => self.eval(inner, scope),
+
+            NixValue::Function(_) => Err(EvalError::Unsupported("fonction".to_string())),
+            NixValue::Apply(_) => self.eval_apply(expr, scope),
+            NixValue::Import(_) => Err(EvalError::Unsupported(expr.to_compact_string())),
+        }
+    }
+
+    // Ajoute à `result` la valeur d'une antiquotation `${e}`
+    fn interpolate(
+        &mut self,
+        e: &'a NixValue,
+        scope: &Rc<Scope<'a>>,
+        result: &mut String,
+    ) -> Result<(), EvalError> {
+        match self.eval(e, scope)? {
+            NixValue::String(s) | NixValue::Path(_, s) => {
+                result.push_str(&s);
+                Ok(())
+            }
+            other => Err(EvalError::TypeMismatch(format!(
+                "impossible d'interpoler une valeur de type {}",
+                other.type_name()
+            ))),
+        }
+    }
+
+    fn eval_bool(&mut self, expr: &'a NixValue, scope: &Rc<Scope<'a>>) -> Result<bool, EvalError> {
+        match self.eval(expr, scope)? {
+            NixValue::Bool(b) => Ok(b),
+            other => Err(EvalError::TypeMismatch(format!(
+                "booléen attendu, pas {}",
+                other.type_name()
+            ))),
+        }
+    }
+
+    // Nom d'un segment de chemin, calculé pour `${expr}`
+    fn eval_key(&mut self, key: &'a AttrKey, scope: &Rc<Scope<'a>>) -> Result<String, EvalError> {
+        match key {
+            AttrKey::Static(name) => Ok(name.clone()),
+            AttrKey::Dynamic(expr) => match self.eval(expr, scope)? {
+                NixValue::String(name) => Ok(name),
+                other => Err(EvalError::TypeMismatch(format!(
+                    "un nom d'attribut doit être une chaîne, pas {}",
+                    other.type_name()
+                ))),
+            },
+        }
+    }
+
+    // Les chemins `a.b = 1; a.c = 2;` donnent des ensembles imbriqués fusionnés
+    fn eval_attrset(
+        &mut self,
+        attrs: &'a AttrSetMap,
+        scope: &Rc<Scope<'a>>,
+    ) -> Result<NixValue, EvalError> {
+        let mut result = AttrSetMap::new();
+        for inherit in &attrs.inherits {
+            for name in &inherit.names {
+                let value = self.eval_inherited(inherit, name, scope, scope)?;
+                result.insert(name.clone(), value);
+            }
+        }
+        for entry in attrs.entries() {
+            let mut names = Vec::new();
+            for key in entry.key.segments() {
+                names.push(self.eval_key(key, scope)?);
+            }
+            let value = self.eval(&entry.value, scope)?;
+            insert_at_path(&mut result, &names, value);
+        }
+        Ok(NixValue::AttrSet(result))
+    }
+
+    // `src.name` pour `inherit (src) name;`, sinon `name` cherché dans `outer`
+    fn eval_inherited(
+        &mut self,
+        inherit: &'a NixInherit,
+        name: &str,
+        scope: &Rc<Scope<'a>>,
+        outer: &Rc<Scope<'a>>,
+    ) -> Result<NixValue, EvalError> {
+        let Some(from) = &inherit.from else {
+            return self.lookup(name, outer);
+        };
+        match self.eval(from, scope)? {
+            NixValue::AttrSet(mut attrs) => attrs
+                .remove(name)
+                .ok_or_else(|| EvalError::MissingAttribute(name.to_string())),
+            other => Err(EvalError::TypeMismatch(format!(
+                "`inherit` attend un ensemble, pas {}",
+                other.type_name()
+            ))),
+        }
+    }
+
+    fn lookup(&mut self, name: &str, scope: &Rc<Scope<'a>>) -> Result<NixValue, EvalError> {
+        // Liaisons lexicales d'abord, puis les `with` du plus proche au plus lointain
+        for with_pass in [false, true] {
+            let mut current = Some(scope);
+            while let Some(s) = current {
+                if s.is_with == with_pass
+                    && let Some((_, binding)) = s.bindings.iter().find(|(n, _)| n == name)
+                {
+                    return match binding {
+                        Binding::Value(value) => Ok(value.clone()),
+                        Binding::Thunk(expr) => self.force(expr, s),
+                        // `inherit a;` désigne le `a` de la portée englobante
+                        Binding::Inherited(inherit) => {
+                            let outer = s.parent.as_ref().unwrap_or(s);
+                            self.eval_inherited(inherit, name, s, outer)
+                        }
+                    };
+                }
+                current = s.parent.as_ref();
+            }
+        }
+        if name == "builtins" {
+            return Err(EvalError::Unsupported("builtins".to_string()));
+        }
+        Err(EvalError::UndefinedVariable(name.to_string()))
+    }
+
+    // Seuls les appels complets de builtins purs sont calculés : `builtins.length [ 1 2 ]`
+    fn eval_apply(
+        &mut self,
+        expr: &'a NixValue,
+        scope: &Rc<Scope<'a>>,
+    ) -> Result<NixValue, EvalError> {
+        let mut func = expr;
+        let mut args = Vec::new();
+        while let NixValue::Apply(apply) = func {
+            args.push(&apply.arg);
+            func = &apply.func;
+        }
+        args.reverse();
+
+        let name = match func {
+            NixValue::Select(select)
+                if select.path.len() == 1
+                    && select.default.is_none()
+                    && !scope.binds("builtins") =>
+            {
+                func.is_builtins_access()
+            }
+            _ => None,
+        };
+        let Some(name) = name else {
+            return Err(EvalError::Unsupported(
+                "application de fonction".to_string(),
+            ));
+        };
+        if builtin_arity(name) != Some(args.len()) {
+            return Err(EvalError::Unsupported(format!(
+                "builtins.{} avec {} argument(s)",
+                name,
+                args.len()
+            )));
+        }
+
+        let mut values = Vec::with_capacity(args.len());
+        for arg in args {
+            values.push(self.eval(arg, scope)?);
+        }
+        call_builtin(name, values)
+    }
+
+    fn force(&mut self, expr: &'a NixValue, scope: &Rc<Scope<'a>>) -> Result<NixValue, EvalError> {
+        if self.in_progress.iter().any(|e| core::ptr::eq(*e, expr)) {
+            return Err(EvalError::InfiniteRecursion);
+        }
+        self.in_progress.push(expr);
+        let result = self.eval(expr, scope);
+        self.in_progress.pop();
+        result
+    }
+
+    fn eval_binary(
+        &mut self,
+        binary_op: &'a NixBinaryOp,
+        scope: &Rc<Scope<'a>>,
+    ) -> Result<NixValue, EvalError> {
+        // Opérateurs logiques : l'opérande droit n'est évalué que si nécessaire
+        match binary_op.op {
+            BinOp::And => {
+                let result = self.eval_bool(&binary_op.lhs, scope)?
+                    && self.eval_bool(&binary_op.rhs, scope)?;
+                return Ok(NixValue::Bool(result));
+            }
+            BinOp::Or => {
+                let result = self.eval_bool(&binary_op.lhs, scope)?
+                    || self.eval_bool(&binary_op.rhs, scope)?;
+                return Ok(NixValue::Bool(result));
+            }
+            BinOp::Implies => {
+                let result = !self.eval_bool(&binary_op.lhs, scope)?
+                    || self.eval_bool(&binary_op.rhs, scope)?;
+                return Ok(NixValue::Bool(result));
+            }
+            _ => {}
+        }
+
+        let lhs = self.eval(&binary_op.lhs, scope)?;
+        let rhs = self.eval(&binary_op.rhs, scope)?;
+        let mismatch = |lhs: &NixValue, rhs: &NixValue| {
+            EvalError::TypeMismatch(format!(
+                "`{}` entre {} et {}",
+                binary_op.op,
+                lhs.type_name(),
+                rhs.type_name()
+            ))
+        };
+
+        match binary_op.op {
+            BinOp::Eq => Ok(NixValue::Bool(values_equal(&lhs, &rhs))),
+            BinOp::NotEq => Ok(NixValue::Bool(!values_equal(&lhs, &rhs))),
+
+            BinOp::Less | BinOp::LessEq | BinOp::Greater | BinOp::GreaterEq => {
+                let ordering = match (&lhs, &rhs) {
+                    (NixValue::String(a), NixValue::String(b))
+                    | (NixValue::Path(_, a), NixValue::Path(_, b)) => Some(a.cmp(b)),
+                    _ => match (as_number(&lhs), as_number(&rhs)) {
+                        (Some(a), Some(b)) => a.partial_cmp(&b),
+                        _ => return Err(mismatch(&lhs, &rhs)),
+                    },
+                };
+                let result = match ordering {
+                    // NaN : toute comparaison est fausse
+                    None => false,
+                    Some(ordering) => match binary_op.op {
+                        BinOp::Less => ordering.is_lt(),
+                        BinOp::LessEq => ordering.is_le(),
+                        BinOp::Greater => ordering.is_gt(),
+                        _ => ordering.is_ge(),
+                    },
+                };
+                Ok(NixValue::Bool(result))
+            }
+
+            BinOp::Update => match (lhs, rhs) {
+                (NixValue::AttrSet(mut merged), NixValue::AttrSet(right)) => {
+                    for (key, value) in right {
+                        merged.insert(key, value);
+                    }
+                    Ok(NixValue::AttrSet(merged))
+                }
+                (lhs, rhs) => Err(mismatch(&lhs, &rhs)),
+            },
+
+            BinOp::Concat => match (lhs, rhs) {
+                (NixValue::List(mut items), NixValue::List(right)) => {
+                    items.extend(right);
+                    Ok(NixValue::List(items))
+                }
+                (lhs, rhs) => Err(mismatch(&lhs, &rhs)),
+            },
+
+            BinOp::Add => match (&lhs, &rhs) {
+                (NixValue::String(a), NixValue::String(b)) => {
+                    Ok(NixValue::String(format!("{}{}", a, b)))
+                }
+                (NixValue::Path(kind, a), NixValue::String(b))
+                | (NixValue::Path(kind, a), NixValue::Path(_, b)) => {
+                    Ok(NixValue::Path(*kind, format!("{}{}", a, b)))
+                }
+                _ => arithmetic(binary_op.op, &lhs, &rhs).ok_or_else(|| mismatch(&lhs, &rhs))?,
+            },
+
+            BinOp::Sub | BinOp::Mul | BinOp::Div => {
+                arithmetic(binary_op.op, &lhs, &rhs).ok_or_else(|| mismatch(&lhs, &rhs))?
+            }
+
+            BinOp::And | BinOp::Or | BinOp::Implies => unreachable!(),
+        }
+    }
+}
+
+// Nombre d'arguments des builtins que `eval` sait calculer
+fn builtin_arity(name: &str) -> Option<usize> {
+    match name {
+        "toString" | "length" | "attrNames" => Some(1),
+        "elemAt" => Some(2),
+        _ => None,
+    }
+}
+
+fn call_builtin(name: &str, args: Vec<NixValue>) -> Result<NixValue, EvalError> {
+    let mismatch = |value: &NixValue| {
+        EvalError::TypeMismatch(format!(
+            "builtins.{} sur une valeur de type {}",
+            name,
+            value.type_name()
+        ))
+    };
+    match (name, args.as_slice()) {
+        ("toString", [value]) => coerce_to_string(value)
+            .map(NixValue::String)
+            .ok_or_else(|| mismatch(value)),
+        ("length", [NixValue::List(items)]) => Ok(NixValue::Int(items.len() as i64)),
+        ("elemAt", [NixValue::List(items), NixValue::Int(index)]) => usize::try_from(*index)
+            .ok()
+            .and_then(|i| items.get(i))
+            .cloned()
+            .ok_or(EvalError::IndexOutOfBounds(*index)),
+        ("attrNames", [NixValue::AttrSet(attrs)]) => {
+            let mut names: Vec<String> = attrs
+                .keys()
+                .filter_map(|key| key.as_static().map(str::to_string))
+                .collect();
+            names.sort();
+            Ok(NixValue::List(
+                names.into_iter().map(NixValue::String).collect(),
+            ))
+        }
+        (_, [.., value]) => Err(mismatch(value)),
+        _ => Err(EvalError::Unsupported(format!("builtins.{}", name))),
+    }
+}
+
+// Conversion de `toString` : les flottants s'écrivent avec six décimales et les
+// listes séparent leurs éléments par des espaces, comme dans Nix
+fn coerce_to_string(value: &NixValue) -> Option<String> {
+    match value {
+        NixValue::String(s) | NixValue::Path(_, s) => Some(s.clone()),
+        NixValue::Int(i) => Some(i.to_string()),
+        NixValue::Float(fl) => Some(format!("{:.6}", fl)),
+        NixValue::Bool(true) => Some("1".to_string()),
+        NixValue::Bool(false) | NixValue::Null => Some(String::new()),
+        NixValue::List(items) => {
+            let parts: Option<Vec<String>> = items.iter().map(coerce_to_string).collect();
+            Some(parts?.join(" "))
+        }
+        _ => None,
+    }
+}
+
+fn as_number(value: &NixValue) -> Option<f64> {
+    match value {
+        NixValue::Int(i) => Some(*i as f64),
+        NixValue::Float(fl) => Some(*fl),
+        _ => None,
+    }
+}
+
+// Opérations numériques : entières si les deux opérandes le sont, flottantes sinon.
+// `None` si les opérandes ne sont pas des nombres
+fn arithmetic(op: BinOp, lhs: &NixValue, rhs: &NixValue) -> Option<Result<NixValue, EvalError>> {
+    if let (NixValue::Int(a), NixValue::Int(b)) = (lhs, rhs) {
+        let result = match op {
+            BinOp::Add => a.checked_add(*b),
+            BinOp::Sub => a.checked_sub(*b),
+            BinOp::Mul => a.checked_mul(*b),
+            _ if *b == 0 => return Some(Err(EvalError::DivisionByZero)),
+            _ => a.checked_div(*b),
+        };
+        return Some(result.map(NixValue::Int).ok_or(EvalError::IntegerOverflow));
+    }
+
+    let (a, b) = (as_number(lhs)?, as_number(rhs)?);
+    Some(match op {
+        BinOp::Add => Ok(NixValue::Float(a + b)),
+        BinOp::Sub => Ok(NixValue::Float(a - b)),
+        BinOp::Mul => Ok(NixValue::Float(a * b)),
+        _ if b == 0.0 => Err(EvalError::DivisionByZero),
+        _ => Ok(NixValue::Float(a / b)),
+    })
+}
+
+// Égalité de Nix : `1 == 1.0`, et l'ordre des attributs ne compte pas
+fn values_equal(lhs: &NixValue, rhs: &NixValue) -> bool {
+    match (lhs, rhs) {
+        (NixValue::List(a), NixValue::List(b)) => {
+            a.len() == b.len() && a.iter().zip(b).all(|(x, y)| values_equal(x, y))
+        }
+        (NixValue::AttrSet(a), NixValue::AttrSet(b)) => {
+            a.len() == b.len()
+                && a.iter().all(|(key, x)| {
+                    key.as_static()
+                        .and_then(|name| b.get(name))
+                        .is_some_and(|y| values_equal(x, y))
+                })
+        }
+        _ => match (as_number(lhs), as_number(rhs)) {
+            (Some(a), Some(b)) => a == b,
+            _ => lhs == rhs,
+        },
+    }
+}
+
+// Insère une valeur sous `a.b.c`, en créant ou complétant les ensembles intermédiaires
+fn insert_at_path(attrs: &mut AttrSetMap, names: &[String], value: NixValue) {
+    let (name, rest) = match names.split_first() {
+        Some(split) => split,
+        None => return,
+    };
+    if rest.is_empty() {
+        // `a = { x = 1; }; a.y = 2;` : les deux définitions se complètent
+        if let (Some(NixValue::AttrSet(existing)), NixValue::AttrSet(new)) =
+            (attrs.get_mut(name), &value)
+        {
+            for (key, v) in new.clone() {
+                existing.insert(key, v);
+            }
+        } else {
+            attrs.insert(name.as_str(), value);
+        }
+        return;
+    }
+    if !matches!(attrs.get(name), Some(NixValue::AttrSet(_))) {
+        attrs.insert(name.as_str(), NixValue::AttrSet(AttrSetMap::new()));
+    }
+    if let Some(NixValue::AttrSet(nested)) = attrs.get_mut(name) {
+        insert_at_path(nested, rest, value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_simple_attrset() {
+        let input = r#"{ name = "test"; version = 1; }"#;
+        let result = parse_nix_string(input).unwrap();
+
+        if let NixValue::AttrSet(attrs) = result {
+            assert_eq!(attrs.len(), 2);
+            assert_eq!(
+                attrs.get("name"),
+                Some(&NixValue::String("test".to_string()))
+            );
+            assert_eq!(attrs.get("version"), Some(&NixValue::Int(1)));
+        } else {
+            panic!("Expected AttrSet");
+        }
+    }
+
+    #[test]
+    fn test_parse_list() {
+        let input = r#"[1 2 3 "test"]"#;
+        let result = parse_nix_string(input).unwrap();
+
+        if let NixValue::List(items) = result {
+            assert_eq!(items.len(), 4);
+        } else {
+            panic!("Expected List");
+        }
+    }
+
+    #[test]
+    fn test_parse_let() {
+        let input = r#"let x = 5; y = 10; in { result = x; }"#;
+        let result = parse_nix_string(input).unwrap();
+
+        if let NixValue::Let(let_expr) = result {
+            assert_eq!(let_expr.bindings.len(), 2);
+        } else {
+            panic!("Expected Let");
+        }
+    }
+
+    #[test]
+    fn test_parse_function_pattern() {
+        let input = r#"{ self, config, inputs, pkgs, pkgs-unstable, ... }: { name = "test"; }"#;
+        let result = parse_nix_string(input).unwrap();
+
+        if let NixValue::Function(func) = result {
+            assert!(func.params.contains(&"self".to_string()));
+            assert!(func.params.contains(&"config".to_string()));
+            assert!(func.params.contains(&"pkgs".to_string()));
+        } else {
+            panic!("Expected Function, got {:?}", result);
+        }
+    }
+
+    #[test]
+    fn test_parse_simple_function() {
+        let input = r#"x: x + 1"#;
+        let result = parse_nix_string(input).unwrap();
+
+        if let NixValue::Function(func) = result {
+            assert_eq!(func.params.len(), 1);
+            assert_eq!(func.params[0], "x");
+        } else {
+            panic!("Expected Function");
+        }
+    }
+
+    #[test]
+    fn test_visitor_counts_variables() {
+        struct VariableCounter {
+            count: usize,
+        }
+
+        impl Visitor for VariableCounter {
+            fn visit_variable(&mut self, _name: &str) {
+                self.count += 1;
+            }
+        }
+
+        let input = r#"{ pkgs, ... }: let x = pkgs; in { a = [ x y ]; b = { c = z; d = 1; }; }"#;
+        let result = parse_nix_string(input).unwrap();
+
+        let mut counter = VariableCounter { count: 0 };
+        walk(&result, &mut counter);
+        assert_eq!(counter.count, 4);
+    }
+
+    #[test]
+    fn test_scalar_accessors() {
+        assert_eq!(NixValue::Bool(true).as_bool(), Some(true));
+        assert_eq!(NixValue::Int(42).as_int(), Some(42));
+        assert_eq!(NixValue::Float(1.5).as_float(), Some(1.5));
+        assert_eq!(NixValue::String("abc".to_string()).as_str(), Some("abc"));
+
+        assert_eq!(NixValue::Int(1).as_bool(), None);
+        assert_eq!(NixValue::String("1".to_string()).as_int(), None);
+        assert_eq!(NixValue::Int(1).as_float(), None);
+        assert_eq!(
+            NixValue::Path(PathKind::Relative, "./a".to_string()).as_str(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_list_accessor() {
+        let result = parse_nix_string("[ 1 2 ]").unwrap();
+        assert_eq!(
+            result.as_list(),
+            Some(&[NixValue::Int(1), NixValue::Int(2)][..])
+        );
+        assert_eq!(NixValue::Null.as_list(), None);
+    }
+
+    #[test]
+    fn test_get_accessor() {
+        let result = parse_nix_string(r#"{ name = "test"; enable = true; }"#).unwrap();
+        assert_eq!(result.get("name").and_then(NixValue::as_str), Some("test"));
+        assert_eq!(result.get("enable").and_then(NixValue::as_bool), Some(true));
+        assert_eq!(result.get("missing"), None);
+        assert_eq!(NixValue::Int(1).get("name"), None);
+
+        let rec = parse_nix_string(r#"rec { a = 1; b = a; }"#).unwrap();
+        assert!(matches!(rec, NixValue::RecAttrSet(_)));
+        assert_eq!(rec.get("a").and_then(NixValue::as_int), Some(1));
+        assert_eq!(rec.get("b"), Some(&NixValue::Variable("a".to_string())));
+    }
+
+    fn var(name: &str) -> NixValue {
+        NixValue::Variable(name.to_string())
+    }
+
+    fn binop(op: BinOp, lhs: NixValue, rhs: NixValue) -> NixValue {
+        NixValue::BinaryOp(Box::new(NixBinaryOp { op, lhs, rhs }))
+    }
+
+    #[test]
+    fn test_with_body_includes_update() {
+        let result = parse_nix_string("with p; a // b").unwrap();
+        let expected = NixValue::With(Box::new(NixWith {
+            expr: var("p"),
+            body: binop(BinOp::Update, var("a"), var("b")),
+        }));
+        assert_eq!(result, expected);
+    }
+
+    #[test]
+    fn test_with_body_includes_concat() {
+        let result = parse_nix_string("with pkgs; [ git ] ++ extra ++ more").unwrap();
+        let expected = NixValue::With(Box::new(NixWith {
+            expr: var("pkgs"),
+            body: binop(
+                BinOp::Concat,
+                NixValue::List(vec![var("git")]),
+                binop(BinOp::Concat, var("extra"), var("more")),
+            ),
+        }));
+        assert_eq!(result, expected);
+    }
+
+    #[test]
+    fn test_is_empty() {
+        assert_eq!(parse_nix_string("[ ]").unwrap().is_empty(), Some(true));
+        assert_eq!(parse_nix_string("[ 1 ]").unwrap().is_empty(), Some(false));
+        assert_eq!(parse_nix_string("{ }").unwrap().is_empty(), Some(true));
+        assert_eq!(
+            parse_nix_string("{ a = 1; }").unwrap().is_empty(),
+            Some(false)
+        );
+        assert_eq!(
+            parse_nix_string("{ inherit a; }").unwrap().is_empty(),
+            Some(false)
+        );
+        assert_eq!(parse_nix_string("rec { }").unwrap().is_empty(), Some(true));
+        assert_eq!(parse_nix_string(r#""""#).unwrap().is_empty(), Some(true));
+        assert_eq!(parse_nix_string(r#""a""#).unwrap().is_empty(), Some(false));
+        assert_eq!(parse_nix_string("''''").unwrap().is_empty(), Some(true));
+        assert_eq!(parse_nix_string("''abc''").unwrap().is_empty(), Some(false));
+        assert_eq!(parse_nix_string(r#""${x}""#).unwrap().is_empty(), None);
+        assert_eq!(NixValue::Null.is_empty(), None);
+        assert_eq!(NixValue::Int(0).is_empty(), None);
+
+        assert_eq!(parse_nix_string("[ ]").unwrap().len(), Some(0));
+        assert_eq!(parse_nix_string("[ 1 2 3 ]").unwrap().len(), Some(3));
+        assert_eq!(
+            parse_nix_string("{ a = 1; inherit b c; }").unwrap().len(),
+            Some(3)
+        );
+        assert_eq!(parse_nix_string(r#""été""#).unwrap().len(), Some(3));
+        assert_eq!(parse_nix_string("''abc''").unwrap().len(), Some(3));
+        assert_eq!(parse_nix_string("''\n  ab\n''").unwrap().len(), Some(3));
+        assert_eq!(parse_nix_string(r#""a${x}""#).unwrap().len(), None);
+        assert_eq!(NixValue::Int(3).len(), None);
+        assert_eq!(parse_nix_string("x: x").unwrap().len(), None);
+    }
+
+    #[test]
+    fn test_parse_large_attrset() {
+        let mut input = String::from("{\n");
+        let mut count = 0;
+        while input.len() < 1024 * 1024 {
+            input.push_str(&format!("  attr{} = \"valeur-{}\";\n", count, count));
+            count += 1;
+        }
+        input.push('}');
+
+        let result = parse_nix_string(&input).unwrap();
+        assert_eq!(result.as_attrset().map(AttrSetMap::len), Some(count));
+        assert_eq!(
+            result.get("attr0").and_then(NixValue::as_str),
+            Some("valeur-0")
+        );
+    }
+
+    #[test]
+    fn test_parse_multibyte_input() {
+        let result = parse_nix_string(r#"{ nom = "Éloïse ☃"; }"#).unwrap();
+        assert_eq!(
+            result.get("nom").and_then(NixValue::as_str),
+            Some("Éloïse ☃")
+        );
+
+        let err = parse_nix_string("{ é = ☃ ; }").unwrap_err();
+        assert_eq!((err.line, err.col), (1, 7));
+    }
+
+    #[test]
+    fn test_attrset_keeps_source_order() {
+        let result = parse_nix_string("{ zeta = 1; alpha = 2; mid = 3; }").unwrap();
+        let keys: Vec<&AttrPath> = result.as_attrset().unwrap().keys().collect();
+        assert_eq!(keys, ["zeta", "alpha", "mid"]);
+    }
+
+    #[test]
+    fn test_comments_preserved_on_bindings() {
+        let input = "{\n  # Nom d'hôte\n  # de la machine\n  hostName = \"nixos\"; # fixé\n  /* pare-feu */\n  firewall = true;\n  # fin\n}";
+        let result = NixParser::new(input).with_comments(true).parse().unwrap();
+
+        let attrs = result.as_attrset().unwrap();
+        let host = &attrs.entries()[0];
+        assert_eq!(host.comments, ["# Nom d'hôte", "# de la machine"]);
+        assert_eq!(host.inline_comment.as_deref(), Some("# fixé"));
+        assert_eq!(attrs.entries()[1].comments, ["/* pare-feu */"]);
+        assert_eq!(attrs.trailing_comments, ["# fin"]);
+
+        assert_eq!(result.to_string(), input);
+    }
+
+    #[test]
+    fn test_comments_dropped_by_default() {
+        let input = "{\n  # commentaire\n  a = 1; # inline\n}";
+        let result = parse_nix_string(input).unwrap();
+        assert_eq!(result.to_string(), "{\n  a = 1;\n}");
+    }
+
+    #[test]
+    fn test_builtins_is_a_variable() {
+        assert_eq!(parse_nix_string("builtins").unwrap(), var("builtins"));
+
+        let result = parse_nix_string("builtins.toPath").unwrap();
+        let expected = NixValue::Select(Box::new(NixSelect {
+            expr: var("builtins"),
+            path: vec![AttrKey::from("toPath")],
+            default: None,
+        }));
+        assert_eq!(result, expected);
+        assert_eq!(result.is_builtins_access(), Some("toPath"));
+        assert_eq!(result.to_string(), "builtins.toPath");
+    }
+
+    #[test]
+    fn test_is_builtins_access_other_values() {
+        assert_eq!(var("builtins").is_builtins_access(), None);
+        assert_eq!(
+            parse_nix_string("lib.toPath").unwrap().is_builtins_access(),
+            None
+        );
+        assert_eq!(
+            parse_nix_string(r#"config."a.b".c"#).unwrap().to_string(),
+            r#"config."a.b".c"#
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_write_nix_file_trailing_newline() {
+        let value = parse_nix_string("{ a = 1; }").unwrap();
+        let path =
+            std::env::temp_dir().join(format!("nix-parser-newline-{}.nix", std::process::id()));
+
+        write_nix_file(&path, &value).unwrap();
+        let content = fs::read_to_string(&path).unwrap();
+        assert!(content.ends_with("}\n"));
+        assert!(!content.ends_with("\n\n"));
+
+        let options = WriteOptions {
+            trailing_newline: false,
+        };
+        write_nix_file_with_options(&path, &value, &options).unwrap();
+        let content = fs::read_to_string(&path).unwrap();
+        assert!(content.ends_with('}'));
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    fn apply(func: NixValue, arg: NixValue) -> NixValue {
+        NixValue::Apply(Box::new(NixApply { func, arg }))
+    }
+
+    fn import(kind: PathKind, path: &str) -> NixValue {
+        NixValue::Import(Box::new(NixValue::Path(kind, path.to_string())))
+    }
+
+    #[test]
+    fn test_parens_change_precedence() {
+        let grouped = parse_nix_string("(a + b) * c").unwrap();
+        assert_eq!(
+            grouped,
+            binop(BinOp::Mul, binop(BinOp::Add, var("a"), var("b")), var("c"))
+        );
+
+        let plain = parse_nix_string("a + b * c").unwrap();
+        assert_eq!(
+            plain,
+            binop(BinOp::Add, var("a"), binop(BinOp::Mul, var("b"), var("c")))
+        );
+
+        let nested = parse_nix_string("((a))").unwrap();
+        assert_eq!(nested, var("a"));
+    }
+
+    #[test]
+    fn test_parens_with_application_and_select() {
+        let result = parse_nix_string("(f x).y").unwrap();
+        let expected = NixValue::Select(Box::new(NixSelect {
+            expr: apply(var("f"), var("x")),
+            path: vec![AttrKey::from("y")],
+            default: None,
+        }));
+        assert_eq!(result, expected);
+
+        let result = parse_nix_string("f a (g b) + 1").unwrap();
+        let expected = binop(
+            BinOp::Add,
+            apply(apply(var("f"), var("a")), apply(var("g"), var("b"))),
+            NixValue::Int(1),
+        );
+        assert_eq!(result, expected);
+
+        let result = parse_nix_string("(import ./x.nix)").unwrap();
+        assert_eq!(result, import(PathKind::Relative, "./x.nix"));
+
+        let result = parse_nix_string("[ (f x) y ]").unwrap();
+        assert_eq!(
+            result,
+            NixValue::List(vec![apply(var("f"), var("x")), var("y")])
+        );
+    }
+
+    #[test]
+    fn test_unclosed_paren_is_an_error() {
+        let err = parse_nix_string("(a + b").unwrap_err();
+        assert!(err.message.contains("Expected ')'"));
+    }
+
+    #[test]
+    fn test_dynamic_attribute_keys() {
+        let input = r#"{ a = 1; ${name} = 2; "${prefix}-suffix" = 3; }"#;
+        let result = parse_nix_string(input).unwrap();
+
+        let attrs = result.as_attrset().unwrap();
+        assert_eq!(attrs.len(), 3);
+        assert_eq!(attrs.get("a"), Some(&NixValue::Int(1)));
+        assert_eq!(
+            attrs.entries()[1].key.segments(),
+            [AttrKey::Dynamic(var("name"))]
+        );
+        assert_eq!(
+            attrs.entries()[2].key.segments(),
+            [AttrKey::Dynamic(NixValue::InterpolatedString(vec![
+                StringPart::Interpolation(var("prefix")),
+                StringPart::Literal("-suffix".to_string()),
+            ]))]
+        );
+
+        let formatted = result.to_string();
+        assert!(formatted.contains("  ${name} = 2;\n"));
+        assert!(formatted.contains("  \"${prefix}-suffix\" = 3;\n"));
+        assert_eq!(parse_nix_string(&formatted).unwrap(), result);
+    }
+
+    #[test]
+    fn test_string_interpolation() {
+        let result = parse_nix_string(r#""${pkgs.hello}/bin/hello --name ${name}""#).unwrap();
+        let expected = NixValue::InterpolatedString(vec![
+            StringPart::Interpolation(NixValue::Select(Box::new(NixSelect {
+                expr: var("pkgs"),
+                path: vec![AttrKey::from("hello")],
+                default: None,
+            }))),
+            StringPart::Literal("/bin/hello --name ".to_string()),
+            StringPart::Interpolation(var("name")),
+        ]);
+        assert_eq!(result, expected);
+        assert_eq!(
+            result.to_string(),
+            r#""${pkgs.hello}/bin/hello --name ${name}""#
+        );
+
+        let plain = parse_nix_string(r#""a\"b\\c""#).unwrap();
+        assert_eq!(plain, NixValue::String("a\"b\\c".to_string()));
+        assert_eq!(plain.to_string(), r#""a\"b\\c""#);
+    }
+
+    #[test]
+    fn test_select_dynamic_component_with_default() {
+        let result = parse_nix_string("x.${k} or {}").unwrap();
+        let expected = NixValue::Select(Box::new(NixSelect {
+            expr: var("x"),
+            path: vec![AttrKey::Dynamic(var("k"))],
+            default: Some(NixValue::AttrSet(AttrSetMap::new())),
+        }));
+        assert_eq!(result, expected);
+
+        let result = parse_nix_string("config.${name}.enable or false").unwrap();
+        if let NixValue::Select(select) = &result {
+            assert_eq!(
+                select.path,
+                [AttrKey::Dynamic(var("name")), AttrKey::from("enable")]
+            );
+            assert_eq!(select.default, Some(NixValue::Bool(false)));
+        } else {
+            panic!("Expected Select, got {:?}", result);
+        }
+        assert_eq!(result.to_string(), "config.${name}.enable or false");
+    }
+
+    #[test]
+    fn test_unterminated_list_error() {
+        let err = parse_nix_string("{\n  ports = [ 80 443").unwrap_err();
+        assert_eq!(err.message, "Unterminated list, expected ']'");
+        assert_eq!((err.line, err.col), (2, 11));
+    }
+
+    #[test]
+    fn test_unterminated_attrset_error() {
+        let err = parse_nix_string("{ a = 1;\n  b = { c = 2; };\n").unwrap_err();
+        assert_eq!(err.message, "Unterminated attribute set, expected '}'");
+        assert_eq!((err.line, err.col), (1, 1));
+
+        let err = parse_nix_string("rec { a = 1;").unwrap_err();
+        assert_eq!((err.line, err.col), (1, 5));
+    }
+
+    #[test]
+    fn test_sort_attrs_recursively() {
+        let input = r#"{ zeta = { b = 1; a = 2; }; alpha = [ { y = 1; x = 2; } 3 1 ]; mid = f { d = 1; c = 2; }; }"#;
+        let mut result = parse_nix_string(input).unwrap();
+        result.sort_attrs_recursively();
+
+        let keys = |value: &NixValue| -> Vec<String> {
+            value
+                .as_attrset()
+                .unwrap()
+                .keys()
+                .map(|k| k.to_string())
+                .collect()
+        };
+        assert_eq!(keys(&result), ["alpha", "mid", "zeta"]);
+        assert_eq!(keys(result.get("zeta").unwrap()), ["a", "b"]);
+
+        let list = result.get("alpha").and_then(NixValue::as_list).unwrap();
+        assert_eq!(keys(&list[0]), ["x", "y"]);
+        assert_eq!(&list[1..], [NixValue::Int(3), NixValue::Int(1)]);
+
+        if let Some(NixValue::Apply(apply)) = result.get("mid") {
+            assert_eq!(keys(&apply.arg), ["c", "d"]);
+        } else {
+            panic!("Expected Apply");
+        }
+    }
+
+    #[test]
+    fn test_concat_and_update_operators() {
+        let concat = parse_nix_string("[1] ++ [2]").unwrap();
+        assert_eq!(
+            concat,
+            binop(
+                BinOp::Concat,
+                NixValue::List(vec![NixValue::Int(1)]),
+                NixValue::List(vec![NixValue::Int(2)])
+            )
+        );
+        assert_eq!(parse_nix_string(&concat.to_string()).unwrap(), concat);
+
+        let update = parse_nix_string("{a=1;} // {b=2;}").unwrap();
+        match &update {
+            NixValue::BinaryOp(op) => {
+                assert_eq!(op.op, BinOp::Update);
+                assert_eq!(op.lhs.get("a"), Some(&NixValue::Int(1)));
+                assert_eq!(op.rhs.get("b"), Some(&NixValue::Int(2)));
+            }
+            _ => panic!("Expected BinaryOp"),
+        }
+        assert!(update.to_string().contains("} // {"));
+        assert_eq!(parse_nix_string(&update.to_string()).unwrap(), update);
+
+        // `++` lie plus fort que `+`, qui lie plus fort que `//`
+        let mixed = parse_nix_string("a // b ++ c + d").unwrap();
+        assert_eq!(mixed.to_string(), "a // b ++ c + d");
+        let grouped = parse_nix_string("(a // b) ++ (c + d)").unwrap();
+        assert_eq!(grouped.to_string(), "(a // b) ++ (c + d)");
+        assert_eq!(parse_nix_string(&grouped.to_string()).unwrap(), grouped);
+    }
+
+    #[test]
+    fn test_cr_line_endings() {
+        let input = "{\r  # commentaire\r  a = 1;\r  b = 2; # fin\r}\r";
+        let result = NixParser::new(input).with_comments(true).parse().unwrap();
+        let attrs = result.as_attrset().unwrap();
+        assert_eq!(attrs.get("a"), Some(&NixValue::Int(1)));
+        assert_eq!(attrs.get("b"), Some(&NixValue::Int(2)));
+        assert_eq!(attrs.entries()[0].comments, vec!["# commentaire"]);
+        assert_eq!(attrs.entries()[1].inline_comment.as_deref(), Some("# fin"));
+
+        // Les lignes sont comptées sur `\r` seul comme sur `\r\n`
+        let err = parse_nix_string("{\r  a = 1;\r  b = ;\r}").unwrap_err();
+        assert_eq!((err.line, err.col), (3, 7));
+        let err = parse_nix_string("{\r\n  a = 1;\r\n  b = ;\r\n}").unwrap_err();
+        assert_eq!((err.line, err.col), (3, 7));
+    }
+
+    #[test]
+    fn test_apply_formatting_round_trip() {
+        let inputs = [
+            "f x",
+            "f x y",
+            "f (g x)",
+            "f (a + b)",
+            "(f x) + y",
+            "f (-1)",
+            "(f x).a",
+        ];
+        for input in inputs {
+            let parsed = parse_nix_string(input).unwrap();
+            let formatted = parsed.to_string();
+            assert_eq!(
+                parse_nix_string(&formatted).unwrap(),
+                parsed,
+                "{}",
+                formatted
+            );
+        }
+
+        assert_eq!(parse_nix_string("f (g x)").unwrap().to_string(), "f (g x)");
+        assert_eq!(
+            parse_nix_string("f (a + b)").unwrap().to_string(),
+            "f (a + b)"
+        );
+        assert_eq!(parse_nix_string("(f x) y").unwrap().to_string(), "f x y");
+        assert_eq!(
+            parse_nix_string("(f x) + y").unwrap().to_string(),
+            "f x + y"
+        );
+        assert_eq!(parse_nix_string("(f x).a").unwrap().to_string(), "(f x).a");
+    }
+
+    #[test]
+    fn test_assert_wraps_attrset() {
+        let input = r#"assert config.enable; { port = 80; }"#;
+        let result = parse_nix_string(input).unwrap();
+
+        if let NixValue::Assert(assert_expr) = &result {
+            assert!(matches!(assert_expr.cond, NixValue::Select(_)));
+            assert_eq!(assert_expr.body.get("port"), Some(&NixValue::Int(80)));
+        } else {
+            panic!("Expected Assert");
+        }
+        assert!(result.to_string().starts_with("assert config.enable; {"));
+        assert_eq!(parse_nix_string(&result.to_string()).unwrap(), result);
+
+        // `assertion` reste un simple identifiant
+        assert_eq!(parse_nix_string("assertion").unwrap(), var("assertion"));
+    }
+
+    #[test]
+    fn test_comparisons_are_non_assoc() {
+        assert!(parse_nix_string("a < b < c").is_err());
+        assert!(parse_nix_string("a == b == c").is_err());
+        assert!(parse_nix_string("a == b != c").is_err());
+        assert!(parse_nix_string("a <= b > c").is_err());
+        let err = parse_nix_string("1 < 2 < 3").unwrap_err();
+        assert_eq!(
+            err.message,
+            "Operator '<' cannot follow '<' without parentheses"
+        );
+
+        // Des niveaux différents ou des parenthèses lèvent l'ambiguïté
+        let result = parse_nix_string("a < b == c < d").unwrap();
+        assert_eq!(
+            result,
+            binop(
+                BinOp::Eq,
+                binop(BinOp::Less, var("a"), var("b")),
+                binop(BinOp::Less, var("c"), var("d"))
+            )
+        );
+        let result = parse_nix_string("(a < b) < c").unwrap();
+        assert_eq!(result.to_string(), "(a < b) < c");
+        assert_eq!(parse_nix_string(&result.to_string()).unwrap(), result);
+        assert!(parse_nix_string("a == (b == c)").is_ok());
+    }
+
+    #[test]
+    fn test_implies_is_right_assoc() {
+        let result = parse_nix_string("a -> b -> c").unwrap();
+        assert_eq!(
+            result,
+            binop(
+                BinOp::Implies,
+                var("a"),
+                binop(BinOp::Implies, var("b"), var("c"))
+            )
+        );
+        assert_eq!(result.to_string(), "a -> b -> c");
+
+        // `->` lie moins fort que `||`
+        let result = parse_nix_string("a || b -> c").unwrap();
+        assert_eq!(
+            result,
+            binop(
+                BinOp::Implies,
+                binop(BinOp::Or, var("a"), var("b")),
+                var("c")
+            )
+        );
+
+        let grouped = parse_nix_string("(a -> b) -> c").unwrap();
+        assert_eq!(grouped.to_string(), "(a -> b) -> c");
+
+        let result = parse_nix_string("assert cfg.enable -> cfg.port != null; cfg").unwrap();
+        if let NixValue::Assert(assert_expr) = result {
+            assert!(matches!(&assert_expr.cond, NixValue::BinaryOp(op) if op.op == BinOp::Implies));
+        } else {
+            panic!("Expected Assert");
+        }
+    }
+
+    #[test]
+    fn test_string_with_literal_braces() {
+        let cases = [
+            (r#""a { b } c""#, "a { b } c"),
+            (r#""}""#, "}"),
+            (r#""{""#, "{"),
+            (r#""$}{""#, "$}{"),
+            ("''a { b } c''", "a { b } c"),
+        ];
+        for (input, expected) in cases {
+            let result = parse_nix_string(input).unwrap();
+            assert_eq!(result.as_str(), Some(expected), "{}", input);
+            assert_eq!(parse_nix_string(&result.to_string()).unwrap(), result);
+        }
+
+        // Un `}` après une interpolation fermée reste littéral
+        let result = parse_nix_string(r#""${a} }""#).unwrap();
+        assert_eq!(
+            result,
+            NixValue::InterpolatedString(vec![
+                StringPart::Interpolation(var("a")),
+                StringPart::Literal(" }".to_string()),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_into_attrset_and_list() {
+        let attrs = parse_nix_string("{ a = 1; }")
+            .unwrap()
+            .into_attrset()
+            .unwrap();
+        assert_eq!(attrs.get("a"), Some(&NixValue::Int(1)));
+
+        let items = parse_nix_string("[ 1 2 ]").unwrap().into_list().unwrap();
+        assert_eq!(items, vec![NixValue::Int(1), NixValue::Int(2)]);
+
+        // En cas d'erreur, la valeur d'origine est rendue telle quelle
+        let list = parse_nix_string("[ 1 ]").unwrap();
+        assert_eq!(list.clone().into_attrset(), Err(list));
+        let attrset = parse_nix_string("{ a = 1; }").unwrap();
+        assert_eq!(attrset.clone().into_list(), Err(attrset));
+    }
+
+    fn unop(op: UnOp, operand: NixValue) -> NixValue {
+        NixValue::UnaryOp(Box::new(NixUnaryOp { op, operand }))
+    }
+
+    #[test]
+    fn test_unary_operators() {
+        let result = parse_nix_string("!a && b").unwrap();
+        assert_eq!(
+            result,
+            binop(BinOp::And, unop(UnOp::Not, var("a")), var("b"))
+        );
+        assert_eq!(result.to_string(), "!a && b");
+
+        // Comme dans Nix, `!` englobe l'addition mais pas `//`
+        let result = parse_nix_string("!a + b").unwrap();
+        assert_eq!(
+            result,
+            unop(UnOp::Not, binop(BinOp::Add, var("a"), var("b")))
+        );
+
+        assert_eq!(parse_nix_string("-x").unwrap(), unop(UnOp::Neg, var("x")));
+        assert_eq!(
+            parse_nix_string("- 3").unwrap(),
+            unop(UnOp::Neg, NixValue::Int(3))
+        );
+        assert_eq!(parse_nix_string("-3").unwrap(), NixValue::Int(-3));
+        assert_eq!(
+            parse_nix_string("-f x").unwrap(),
+            unop(UnOp::Neg, apply(var("f"), var("x")))
+        );
+        assert_eq!(
+            parse_nix_string("a - -b").unwrap(),
+            binop(BinOp::Sub, var("a"), unop(UnOp::Neg, var("b")))
+        );
+
+        for input in [
+            "!a && b",
+            "!(a && b)",
+            "- 3",
+            "-x * y",
+            "(!a) + b",
+            "!a // b",
+        ] {
+            let parsed = parse_nix_string(input).unwrap();
+            assert_eq!(
+                parse_nix_string(&parsed.to_string()).unwrap(),
+                parsed,
+                "{}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_file_errors_distinguish_io_and_parse() {
+        let dir = std::env::temp_dir();
+        let missing = dir.join(format!("nix-parser-missing-{}.nix", std::process::id()));
+        match parse_nix_file(&missing) {
+            Err(NixError::Io(e)) => assert_eq!(e.kind(), std::io::ErrorKind::NotFound),
+            other => panic!("Expected Io error, got {:?}", other),
+        }
+
+        let invalid = dir.join(format!("nix-parser-invalid-{}.nix", std::process::id()));
+        fs::write(&invalid, "{ a = ; }").unwrap();
+        let result = parse_nix_file(&invalid);
+        fs::remove_file(&invalid).unwrap();
+        match result {
+            Err(NixError::Parse(e)) => assert_eq!((e.line, e.col), (1, 7)),
+            other => panic!("Expected Parse error, got {:?}", other),
+        }
+
+        let unwritable = dir.join("nix-parser-no-such-dir").join("out.nix");
+        assert!(matches!(
+            write_nix_file(&unwritable, &NixValue::Null),
+            Err(NixError::Io(_))
+        ));
+    }
+
+    #[test]
+    fn test_implies_associativity_and_precedence() {
+        // `->` est associatif à droite, contrairement à `-` et `+`
+        let result = parse_nix_string("a -> b -> c").unwrap();
+        assert_eq!(
+            result,
+            binop(
+                BinOp::Implies,
+                var("a"),
+                binop(BinOp::Implies, var("b"), var("c"))
+            )
+        );
+        let result = parse_nix_string("a - b - c").unwrap();
+        assert_eq!(
+            result,
+            binop(BinOp::Sub, binop(BinOp::Sub, var("a"), var("b")), var("c"))
+        );
+
+        // Tous les autres opérateurs lient plus fort que `->`
+        let result = parse_nix_string("a && b -> c || d -> e == f").unwrap();
+        assert_eq!(
+            result,
+            binop(
+                BinOp::Implies,
+                binop(BinOp::And, var("a"), var("b")),
+                binop(
+                    BinOp::Implies,
+                    binop(BinOp::Or, var("c"), var("d")),
+                    binop(BinOp::Eq, var("e"), var("f"))
+                )
+            )
+        );
+        assert_eq!(parse_nix_string(&result.to_string()).unwrap(), result);
+    }
+
+    #[test]
+    fn test_free_variables() {
+        let free = |input: &str| {
+            let mut names: Vec<String> = parse_nix_string(input)
+                .unwrap()
+                .free_variables()
+                .into_iter()
+                .collect();
+            names.sort();
+            names
+        };
+
+        assert_eq!(free("let x = 1; in x + y"), ["y"]);
+        // Les liaisons d'un let se voient mutuellement
+        assert_eq!(free("let a = b; b = c; in a"), ["c"]);
+        assert_eq!(free("x: x y"), ["y"]);
+        assert_eq!(
+            free("{ pkgs, lib, ... }: pkgs.hello // lib.foo // other"),
+            ["other"]
+        );
+        assert_eq!(free("rec { a = 1; b.c = a + d; }"), ["d"]);
+        assert_eq!(free("{ a = 1; b = a; }"), ["a"]);
+        // La portée d'un paramètre s'arrête au corps de la fonction
+        assert_eq!(free("[ (x: x) x ]"), ["x"]);
+        // `with` ne lie rien statiquement
+        assert_eq!(free("with pkgs; [ git ]"), ["git", "pkgs"]);
+        assert_eq!(free(r#""${a}-${let b = 1; in b}""#), ["a"]);
+    }
+
+    #[test]
+    fn test_format_options() {
+        let input = "{ short = [1 2 3]; long = [ 1 2 3 4 5 6 7 8 9 10 11 12 ]; }";
+        let value = parse_nix_string(input).unwrap();
+        let opts = FormatOptions {
+            max_width: 30,
+            ..FormatOptions::default()
+        };
+        let formatted = value.format(&opts);
+        assert!(formatted.contains("short = [ 1 2 3 ];"));
+        assert!(formatted.contains("long = [\n    1\n"));
+        assert_eq!(parse_nix_string(&formatted).unwrap(), value);
+
+        // Les options par défaut reproduisent `Display`
+        assert_eq!(value.format(&FormatOptions::default()), value.to_string());
+
+        let value = parse_nix_string("{ a = 1; long = { x = 1; }; }").unwrap();
+        let opts = FormatOptions {
+            indent_width: 4,
+            align_equals: true,
+            ..FormatOptions::default()
+        };
+        assert_eq!(
+            value.format(&opts),
+            "{\n    a    = 1;\n    long = {\n        x = 1;\n    };\n}"
+        );
+
+        let value = parse_nix_string("{ a = { b = 1; }; }").unwrap();
+        let opts = FormatOptions {
+            max_width: 80,
+            ..FormatOptions::default()
+        };
+        assert_eq!(value.format(&opts), "{ a = { b = 1; }; }");
+
+        // Un élément de liste qui est une application garde ses parenthèses
+        let value = parse_nix_string("[ (f x) (-1) ]").unwrap();
+        assert_eq!(value.format(&opts), "[ (f x) -1 ]");
+        assert_eq!(parse_nix_string(&value.to_string()).unwrap(), value);
+    }
+
+    #[test]
+    fn test_dash_in_identifiers_vs_subtraction() {
+        assert_eq!(parse_nix_string("a-b").unwrap(), var("a-b"));
+        let select = parse_nix_string("pkgs-unstable.hello").unwrap();
+        assert_eq!(select.to_string(), "pkgs-unstable.hello");
+        assert_eq!(
+            parse_nix_string("a - b").unwrap(),
+            binop(BinOp::Sub, var("a"), var("b"))
+        );
+        assert_eq!(
+            parse_nix_string("a -b").unwrap(),
+            binop(BinOp::Sub, var("a"), var("b"))
+        );
+        // `a-` est lui-même un identifiant valide
+        assert_eq!(
+            parse_nix_string("a- b").unwrap(),
+            apply(var("a-"), var("b"))
+        );
+        assert_eq!(
+            parse_nix_string("1-2").unwrap(),
+            binop(BinOp::Sub, NixValue::Int(1), NixValue::Int(2))
+        );
+    }
+
+    #[test]
+    fn test_assert_with_application_condition() {
+        let result = parse_nix_string(r#"assert lib.assertMsg c "m"; x"#).unwrap();
+        let assert_msg = NixValue::Select(Box::new(NixSelect {
+            expr: var("lib"),
+            path: vec![AttrKey::from("assertMsg")],
+            default: None,
+        }));
+        let expected = NixValue::Assert(Box::new(NixAssert {
+            cond: apply(
+                apply(assert_msg, var("c")),
+                NixValue::String("m".to_string()),
+            ),
+            body: var("x"),
+        }));
+        assert_eq!(result, expected);
+        assert_eq!(result.to_string(), r#"assert lib.assertMsg c "m"; x"#);
+    }
+
+    #[test]
+    fn test_sorted_and_sort_keys_option() {
+        let input = "{ b = let z = 1; y = 2; in { d = z; c = y; }; a = [ { f = 1; e = 2; } ]; }";
+        let value = parse_nix_string(input).unwrap();
+
+        let opts = FormatOptions {
+            max_width: 40,
+            sort_keys: true,
+            ..FormatOptions::default()
+        };
+        let expected = concat!(
+            "{\n",
+            "  a = [ { e = 2; f = 1; } ];\n",
+            "  b = let\n",
+            "    y = 2;\n",
+            "    z = 1;\n",
+            "  in { c = y; d = z; };\n",
+            "}"
+        );
+        assert_eq!(value.format(&opts), expected);
+
+        let opts = FormatOptions {
+            sort_keys: false,
+            ..opts
+        };
+        assert!(value.format(&opts).starts_with("{\n  b = let\n    z = 1;"));
+
+        // La valeur d'origine garde l'ordre du source
+        let sorted = value.sorted();
+        assert_eq!(value.as_attrset().unwrap().keys().next().unwrap(), "b");
+        assert_eq!(sorted.as_attrset().unwrap().keys().next().unwrap(), "a");
+        assert_eq!(parse_nix_string(&sorted.to_string()).unwrap(), sorted);
+    }
+
+    #[test]
+    fn test_inline_leaf_lists() {
+        let input = r#"{ ports = [ 80 443 ]; hosts = [ { name = "a"; } ]; }"#;
+        let value = parse_nix_string(input).unwrap();
+        let opts = FormatOptions {
+            inline_leaf_lists: true,
+            ..FormatOptions::default()
+        };
+        assert_eq!(
+            value.format(&opts),
+            "{\n  ports = [ 80 443 ];\n  hosts = [\n    {\n      name = \"a\";\n    }\n  ];\n}"
+        );
+        assert_eq!(parse_nix_string(&value.format(&opts)).unwrap(), value);
+    }
+
+    #[test]
+    fn test_uri_literals() {
+        let result = parse_nix_string("{ url = https://a.b/c; }").unwrap();
+        assert_eq!(
+            result.get("url"),
+            Some(&NixValue::String("https://a.b/c".to_string()))
+        );
+
+        let result = parse_nix_string("[ git+ssh://git@host/repo?ref=main x ]").unwrap();
+        assert_eq!(
+            result.as_list().unwrap(),
+            [
+                NixValue::String("git+ssh://git@host/repo?ref=main".to_string()),
+                var("x")
+            ]
+        );
+
+        // Avec un espace après `:`, c'est une fonction ; sans, une URI
+        assert!(matches!(
+            parse_nix_string("x: x").unwrap(),
+            NixValue::Function(_)
+        ));
+        assert_eq!(
+            parse_nix_string("x:x").unwrap(),
+            NixValue::String("x:x".to_string())
+        );
+        assert!(matches!(
+            parse_nix_string("a.b").unwrap(),
+            NixValue::Select(_)
+        ));
+    }
+
+    #[test]
+    fn test_nested_rec_attrsets() {
+        let result =
+            parse_nix_string("rec { a = rec { b = 1; c = b; }; d = { e = a; }; }").unwrap();
+        let NixValue::RecAttrSet(outer) = &result else {
+            panic!("Expected RecAttrSet");
+        };
+        assert!(matches!(outer.get("a"), Some(NixValue::RecAttrSet(_))));
+        assert!(matches!(outer.get("d"), Some(NixValue::AttrSet(_))));
+
+        let formatted = result.to_string();
+        assert!(formatted.starts_with("rec {"));
+        assert!(formatted.contains("a = rec {"));
+        assert!(formatted.contains("d = {"));
+        assert_eq!(parse_nix_string(&formatted).unwrap(), result);
+    }
+
+    #[test]
+    fn test_validate_identifiers() {
+        let input = r#"{ "foo.bar" = 1; services."my app".enable = then; ok = x: x; }"#;
+        let warnings = parse_nix_string(input).unwrap().validate_identifiers();
+        assert_eq!(
+            warnings,
+            vec![
+                Warning {
+                    kind: WarningKind::NeedsQuoting,
+                    name: "foo.bar".to_string()
+                },
+                Warning {
+                    kind: WarningKind::NeedsQuoting,
+                    name: "my app".to_string()
+                },
+                Warning {
+                    kind: WarningKind::KeywordName,
+                    name: "then".to_string()
+                },
+            ]
+        );
+        assert_eq!(
+            warnings[2].to_string(),
+            "Le nom 'then' est un mot-clé de Nix"
+        );
+
+        assert!(
+            parse_nix_string("{ a.b = c; }")
+                .unwrap()
+                .validate_identifiers()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_parse_consecutive_expressions() {
+        // Sans mot-clé entre les deux, `{ ... } [ 2 ]` serait une application
+        let mut parser = NixParser::new("{ a = 1; }\nwith x; [ 2 ] # fin\n");
+        assert_eq!(parser.position(), (0, 1, 1));
+
+        let first = parser.parse_expression().unwrap();
+        assert_eq!(first.get("a"), Some(&NixValue::Int(1)));
+        assert_eq!(parser.position(), (11, 2, 1));
+        assert!(!parser.is_at_end());
+
+        let second = parser.parse_expression().unwrap();
+        assert!(matches!(second, NixValue::With(_)));
+        assert_eq!(parser.position(), (31, 3, 1));
+        assert!(parser.is_at_end());
+    }
+
+    #[test]
+    fn test_from_conversions() {
+        let value: NixValue = [
+            ("name".to_string(), "demo".into()),
+            ("port".to_string(), 8080.into()),
+            ("ratio".to_string(), 0.5.into()),
+            ("enable".to_string(), true.into()),
+            (
+                "tags".to_string(),
+                NixValue::from(vec![1.into(), String::from("x").into()]),
+            ),
+        ]
+        .into_iter()
+        .collect();
+
+        assert_eq!(value.get("port"), Some(&NixValue::Int(8080)));
+        assert_eq!(
+            value.to_string(),
+            concat!(
+                "{\n",
+                "  name = \"demo\";\n",
+                "  port = 8080;\n",
+                "  ratio = 0.5;\n",
+                "  enable = true;\n",
+                "  tags = [\n",
+                "    1\n",
+                "    \"x\"\n",
+                "  ];\n",
+                "}"
+            )
+        );
+        assert_eq!(parse_nix_string(&value.to_string()).unwrap(), value);
+    }
+
+    #[test]
+    fn test_redundant_parens() {
+        // Par défaut, les parenthèses ne laissent pas de trace dans l'arbre
+        let result = parse_nix_string("((a))").unwrap();
+        assert_eq!(result, var("a"));
+        assert_eq!(result.to_string(), "a");
+
+        // En mode sans perte, chaque paire devient un nœud `Paren`
+        let result = NixParser::new("((a))").with_parens(true).parse().unwrap();
+        assert_eq!(
+            result,
+            NixValue::Paren(Box::new(NixValue::Paren(Box::new(var("a")))))
+        );
+        assert_eq!(result.to_string(), "((a))");
+
+        let result = NixParser::new("f (a + b) (c)")
+            .with_parens(true)
+            .parse()
+            .unwrap();
+        assert_eq!(result.to_string(), "f (a + b) (c)");
+    }
+
+    #[test]
+    fn test_dotted_keys_are_stored_by_segment() {
+        let input = r#"{ "a.b".c = 1; a."x.y" = 2; fileSystems."/".options = 3; a.${b}.c = 4; }"#;
+        let result = parse_nix_string(input).unwrap();
+        let attrs = result.as_attrset().unwrap();
+
+        let segments: Vec<&[AttrKey]> = attrs.keys().map(AttrPath::segments).collect();
+        assert_eq!(segments[0], [AttrKey::from("a.b"), AttrKey::from("c")]);
+        assert_eq!(segments[1], [AttrKey::from("a"), AttrKey::from("x.y")]);
+        assert_eq!(
+            segments[2],
+            [
+                AttrKey::from("fileSystems"),
+                AttrKey::from("/"),
+                AttrKey::from("options")
+            ]
+        );
+        assert_eq!(
+            segments[3],
+            [
+                AttrKey::from("a"),
+                AttrKey::Dynamic(var("b")),
+                AttrKey::from("c")
+            ]
+        );
+
+        let formatted = result.to_string();
+        assert!(formatted.contains(r#"  "a.b".c = 1;"#));
+        assert!(formatted.contains(r#"  a."x.y" = 2;"#));
+        assert!(formatted.contains(r#"  fileSystems."/".options = 3;"#));
+        assert!(formatted.contains("  a.${b}.c = 4;"));
+        assert_eq!(parse_nix_string(&formatted).unwrap(), result);
+
+        // Une clé construite par programme n'est jamais découpée sur les points
+        let mut attrs = AttrSetMap::new();
+        attrs.insert("a.b", NixValue::Int(1));
+        assert_eq!(NixValue::AttrSet(attrs).to_string(), "{\n  \"a.b\" = 1;\n}");
+    }
+
+    #[test]
+    fn test_apply_args() {
+        let result = var("f").apply_args(&[var("a"), var("b"), var("c")]);
+        assert_eq!(
+            result,
+            apply(apply(apply(var("f"), var("a")), var("b")), var("c"))
+        );
+        assert_eq!(result.to_string(), "f a b c");
+        assert_eq!(parse_nix_string("f a b c").unwrap(), result);
+
+        assert_eq!(var("f").apply_args(&[]), var("f"));
+    }
+
+    #[test]
+    fn test_empty_braces_and_pattern_lookahead() {
+        assert_eq!(
+            parse_nix_string("{ }").unwrap(),
+            NixValue::AttrSet(AttrSetMap::new())
+        );
+        assert_eq!(
+            parse_nix_string("{}").unwrap(),
+            NixValue::AttrSet(AttrSetMap::new())
+        );
+        assert_eq!(parse_nix_string("[ ]").unwrap(), NixValue::List(Vec::new()));
+
+        let result = parse_nix_string("{ }: 1").unwrap();
+        assert!(matches!(&result, NixValue::Function(func) if func.params.is_empty()));
+        let result = parse_nix_string("{ ... }: 1").unwrap();
+        assert!(matches!(&result, NixValue::Function(func) if func.params.is_empty()));
+        assert_eq!(result.to_string(), "{ ... }: 1");
+        assert_eq!(parse_nix_string(&result.to_string()).unwrap(), result);
+
+        // Un motif sans ':' est une erreur, signalée après le motif
+        let err = parse_nix_string("{ ... }").unwrap_err();
+        assert_eq!(err.message, "Expected ':' after function pattern");
+        assert_eq!((err.line, err.col), (1, 8));
+        let err = parse_nix_string("{ a, b }").unwrap_err();
+        assert_eq!(err.message, "Expected ':' after function pattern");
+        assert!(parse_nix_string("{ /* a, b... */ }").is_ok());
+    }
+
+    #[test]
+    fn test_parse_flake_fixture() {
+        let flake = parse_nix_string(include_str!("../tests/fixtures/flake.nix")).unwrap();
+
+        assert_eq!(
+            flake.get("description").and_then(NixValue::as_str),
+            Some("Configuration NixOS du laptop")
+        );
+        let home_manager = flake
+            .get("inputs")
+            .and_then(|i| i.get("home-manager"))
+            .unwrap();
+        let follows_path = AttrPath(vec!["inputs".into(), "nixpkgs".into(), "follows".into()]);
+        let follows = home_manager
+            .as_attrset()
+            .unwrap()
+            .iter()
+            .find(|(key, _)| **key == follows_path)
+            .map(|(_, value)| value);
+        assert_eq!(follows, Some(&NixValue::String("nixpkgs".to_string())));
+
+        let Some(NixValue::Function(outputs)) = flake.get("outputs") else {
+            panic!("Expected outputs to be a function");
+        };
+        assert_eq!(outputs.params, ["self", "nixpkgs", "home-manager"]);
+        assert!(matches!(outputs.body, NixValue::Let(_)));
+
+        // L'aller-retour conserve la structure, à l'ordre des clés près
+        let reparsed = parse_nix_string(&flake.to_string()).unwrap();
+        assert_eq!(reparsed.sorted(), flake.sorted());
+    }
+
+    #[test]
+    fn test_parse_if() {
+        let result = parse_nix_string("if a > 1 then \"big\" else \"small\"").unwrap();
+        let NixValue::If(if_expr) = &result else {
+            panic!("Expected an if expression");
+        };
+        assert_eq!(
+            if_expr.cond,
+            binop(BinOp::Greater, var("a"), NixValue::Int(1))
+        );
+        assert_eq!(if_expr.else_branch, NixValue::String("small".to_string()));
+        assert_eq!(result.to_string(), "if a > 1 then \"big\" else \"small\"");
+    }
+
+    #[test]
+    fn test_eval() {
+        let eval = |input: &str| parse_nix_string(input).unwrap().eval();
+
+        assert_eq!(eval("1 + 2"), Ok(NixValue::Int(3)));
+        assert_eq!(eval("7 / 2 * 1.5"), Ok(NixValue::Float(4.5)));
+        assert_eq!(
+            eval("let x = 1; y = x + 2; in y * 10"),
+            Ok(NixValue::Int(30))
+        );
+        // Les liaisons d'un `let` sont récursives, dans n'importe quel ordre
+        assert_eq!(eval("let a = b; b = 4; in a"), Ok(NixValue::Int(4)));
+
+        let merged: NixValue = [("a", 1), ("b", 3), ("c", 4)]
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), NixValue::Int(v)))
+            .collect();
+        assert_eq!(eval("{ a = 1; b = 2; } // { b = 3; c = 4; }"), Ok(merged));
+        assert_eq!(
+            eval("[ 1 ] ++ [ (2 + 3) ]"),
+            Ok(NixValue::List(vec![NixValue::Int(1), NixValue::Int(5)]))
+        );
+
+        assert_eq!(eval("rec { a = 2; b = a; }.b"), Ok(NixValue::Int(2)));
+        assert_eq!(eval("{ a.b.c = 5; }.a.b.c"), Ok(NixValue::Int(5)));
+        assert_eq!(eval("{ a = 1; }.b or 0"), Ok(NixValue::Int(0)));
+        assert_eq!(
+            eval("if 1 < 2 && !false then \"oui\" else \"non\""),
+            Ok("oui".into())
+        );
+        assert_eq!(
+            eval("{ a = 1; b = [ 2 ]; } == { b = [ 2 ]; a = 1.0; }"),
+            Ok(true.into())
+        );
+        assert_eq!(
+            eval("let x = 2; in with { x = 1; y = 3; }; x + y"),
+            Ok(NixValue::Int(5))
+        );
+        assert_eq!(
+            eval("let n = \"monde\"; in \"bonjour ${n}\""),
+            Ok("bonjour monde".into())
+        );
+    }
+
+    #[test]
+    fn test_eval_errors() {
+        let eval = |input: &str| parse_nix_string(input).unwrap().eval();
+
+        assert!(matches!(eval("(x: x) 1"), Err(EvalError::Unsupported(_))));
+        assert!(matches!(
+            eval("import ./a.nix"),
+            Err(EvalError::Unsupported(_))
+        ));
+        assert!(matches!(
+            eval("with pkgs; hello"),
+            Err(EvalError::Unsupported(_))
+        ));
+        assert_eq!(
+            eval("x + 1"),
+            Err(EvalError::UndefinedVariable("x".to_string()))
+        );
+        assert_eq!(
+            eval("{ a = 1; }.b"),
+            Err(EvalError::MissingAttribute("b".to_string()))
+        );
+        assert_eq!(eval("1 / 0"), Err(EvalError::DivisionByZero));
+        assert_eq!(eval("let x = x; in x"), Err(EvalError::InfiniteRecursion));
+        assert!(matches!(eval("1 + \"a\""), Err(EvalError::TypeMismatch(_))));
+        assert_eq!(eval("assert 1 == 2; 3"), Err(EvalError::AssertionFailed));
+    }
+
+    #[test]
+    fn test_string_escapes() {
+        let parse = |input: &str| parse_nix_string(input).unwrap();
+
+        assert_eq!(
+            parse(r#""a\nb\tc\rd""#),
+            NixValue::String("a\nb\tc\rd".to_string())
+        );
+        assert_eq!(
+            parse(r#""\\ \" \$ \'""#),
+            NixValue::String("\\ \" $ '".to_string())
+        );
+        // Échappement inconnu : le caractère seul, sans l'antislash
+        assert_eq!(parse(r#""\d\e\0""#), NixValue::String("de0".to_string()));
+        // Pas de `\u` en Nix ; l'Unicode s'écrit tel quel
+        assert_eq!(
+            parse(r#""\u00e9 é""#),
+            NixValue::String("u00e9 é".to_string())
+        );
+        // `\${` empêche l'interpolation
+        assert_eq!(parse(r#""\${x}""#), NixValue::String("${x}".to_string()));
+        // Mêmes règles après `''\` dans une string multi-lignes
+        assert_eq!(parse("''a''\\nb''\\d''").as_str(), Some("a\nbd"));
+        assert_eq!(parse("''a''' ''${x}''").as_str(), Some("a'' ${x}"));
+        assert!(parse_nix_string(r#""abc\"#).is_err());
+
+        // L'échappement à l'écriture est relu à l'identique
+        let original = NixValue::String("\\ \" \n\t\r ${x} $y é".to_string());
+        assert_eq!(parse(&original.to_string()), original);
+    }
+
+    #[test]
+    fn test_eval_builtins() {
+        let eval = |input: &str| parse_nix_string(input).unwrap().eval();
+
+        assert_eq!(eval("builtins.length [1 2 3]"), Ok(NixValue::Int(3)));
+        assert_eq!(
+            eval("builtins.attrNames { b = 2; a = 1; \"c d\" = 3; }"),
+            Ok(NixValue::List(vec!["a".into(), "b".into(), "c d".into()]))
+        );
+        assert_eq!(
+            eval("builtins.elemAt [ 1 (2 + 3) ] 1"),
+            Ok(NixValue::Int(5))
+        );
+        assert_eq!(
+            eval("builtins.elemAt [ 1 ] 4"),
+            Err(EvalError::IndexOutOfBounds(4))
+        );
+        assert_eq!(
+            eval("builtins.toString [ 1 true null 2.5 ]"),
+            Ok("1 1  2.500000".into())
+        );
+
+        // Application partielle, builtin impur ou `builtins` redéfini : non calculés
+        assert!(matches!(
+            eval("builtins.elemAt [ 1 ]"),
+            Err(EvalError::Unsupported(_))
+        ));
+        assert!(matches!(
+            eval("builtins.readFile ./x"),
+            Err(EvalError::Unsupported(_))
+        ));
+        assert!(matches!(
+            eval("let builtins = { }; in builtins.length [ ]"),
+            Err(EvalError::Unsupported(_))
+        ));
+        assert!(matches!(
+            eval("builtins.length 1"),
+            Err(EvalError::TypeMismatch(_))
+        ));
+    }
+
+    #[test]
+    fn test_max_depth() {
+        let deep_list = format!("{}{}", "[".repeat(10_000), "]".repeat(10_000));
+        let err = parse_nix_string(&deep_list).unwrap_err();
+        assert_eq!(err.message, "Maximum nesting depth exceeded");
+
+        // Limite réduite : en debug, un niveau d'ensemble ou de parenthèses occupe
+        // bien plus de pile qu'un niveau de liste
+        let parse_shallow = |input: &str| {
+            NixParser::new(input)
+                .with_max_depth(64)
+                .parse()
+                .map_err(|e| e.message)
+        };
+        let depth_error = Err("Maximum nesting depth exceeded".to_string());
+        let deep_attrs = format!("{}1{}", "{ a = ".repeat(10_000), "; }".repeat(10_000));
+        assert_eq!(parse_shallow(&deep_attrs), depth_error);
+        assert_eq!(
+            parse_shallow(&format!("{}true", "!".repeat(10_000))),
+            depth_error
+        );
+        let deep_parens = format!("{}1{}", "(".repeat(10_000), ")".repeat(10_000));
+        assert_eq!(parse_shallow(&deep_parens), depth_error);
+
+        // Sous la limite par défaut, l'imbrication reste acceptée
+        let nested = format!("{}1{}", "[ ".repeat(100), " ]".repeat(100));
+        assert!(parse_nix_string(&nested).is_ok());
+        let nested = format!("{}1{}", "(".repeat(100), ")".repeat(100));
+        assert!(parse_nix_string(&nested).is_ok());
+
+        let mut parser = NixParser::new("[ [ [ 1 ] ] ]").with_max_depth(3);
+        assert!(parser.parse().is_err());
+        let mut parser = NixParser::new("[ [ [ 1 ] ] ]").with_max_depth(4);
+        assert!(parser.parse().is_ok());
+    }
+
+    #[test]
+    fn test_parse_many() {
+        let input = "{ a = 1; } { b = 2; }\n# suivant\n{ c = [ 3 ]; }";
+        let values = parse_nix_string_many(input).unwrap();
+        assert_eq!(values.len(), 3);
+        assert_eq!(values[1].get("b"), Some(&NixValue::Int(2)));
+        assert_eq!(
+            values[2].get("c"),
+            Some(&NixValue::List(vec![NixValue::Int(3)]))
+        );
+
+        // Une variable en tête reste une application
+        let values = parse_nix_string_many("1 + 2 \"x\"\nf { } [ ]").unwrap();
+        assert_eq!(values.len(), 3);
+        assert_eq!(values[1], NixValue::String("x".to_string()));
+        let empty_set = NixValue::AttrSet(AttrSetMap::new());
+        assert_eq!(
+            values[2],
+            apply(apply(var("f"), empty_set), NixValue::List(vec![]))
+        );
+
+        assert_eq!(parse_nix_string_many("  \n").unwrap(), []);
+
+        let err = parse_nix_string_many("{ a = 1; }\n{ b = ; }").unwrap_err();
+        assert!(err.message.starts_with("In fragment 1: "));
+        assert_eq!(err.line, 2);
+    }
+
+    #[test]
+    fn test_error_columns() {
+        let err = parse_nix_string("{\n  a = [ \"é\"\t) ];\n}").unwrap_err();
+        // `é` occupe deux octets, la tabulation une colonne
+        assert_eq!((err.line, err.col, err.byte_col), (2, 13, 14));
+        assert_eq!(err.context, "  a = [ \"é\"\t) ];\n           \t^");
+
+        // Le `^` est sous le caractère fautif, ligne de contexte comprise
+        let (code, caret) = err.context.split_once('\n').unwrap();
+        assert_eq!(code.chars().nth(caret.chars().count() - 1), Some(')'));
+        assert!(
+            err.to_string()
+                .ends_with(&format!("Contexte:\n{}", err.context))
+        );
+    }
+
+    #[test]
+    fn test_parse_let_edge_cases() {
+        let result = parse_nix_string("let in 1").unwrap();
+        let NixValue::Let(let_expr) = &result else {
+            panic!("Expected a let expression");
+        };
+        assert!(let_expr.bindings.is_empty());
+        assert_eq!(let_expr.body, NixValue::Int(1));
+
+        // `in` n'est reconnu que comme mot entier
+        let result = parse_nix_string("let index = 1; initial = index; in initial").unwrap();
+        let NixValue::Let(let_expr) = &result else {
+            panic!("Expected a let expression");
+        };
+        assert_eq!(let_expr.bindings.get("index"), Some(&NixValue::Int(1)));
+        assert_eq!(let_expr.bindings.get("initial"), Some(&var("index")));
+        assert_eq!(let_expr.body, var("initial"));
+
+        // Forme historique : l'attribut `body` devient le corps
+        let legacy = parse_nix_string("let { a = 1; body = a + 1; }").unwrap();
+        assert_eq!(legacy, parse_nix_string("let a = 1; in a + 1").unwrap());
+        assert_eq!(legacy.eval(), Ok(NixValue::Int(2)));
+
+        let err = parse_nix_string("let { a = 1; }").unwrap_err();
+        assert_eq!(
+            err.message,
+            "Legacy 'let { ... }' requires a 'body' attribute"
+        );
+        let err = parse_nix_string("let a = 1;").unwrap_err();
+        assert_eq!(err.message, "Expected 'in' after let bindings");
+    }
+
+    #[test]
+    fn test_keyword_prefixes_are_variables() {
+        for name in [
+            "trueish",
+            "falsey",
+            "nullable",
+            "imports",
+            "inherited",
+            "lettuce",
+            "record",
+        ] {
+            assert_eq!(parse_nix_string(name).unwrap(), var(name), "{}", name);
+        }
+        assert_eq!(parse_nix_string("true-ish").unwrap(), var("true-ish"));
+        assert_eq!(
+            parse_nix_string("[ nullable true ]").unwrap(),
+            NixValue::List(vec![var("nullable"), NixValue::Bool(true)])
+        );
+
+        let input = "{ inherited = 1; imports = [ ]; withFoo = recursive; }";
+        let attrs = parse_nix_string(input).unwrap();
+        assert_eq!(attrs.get("inherited"), Some(&NixValue::Int(1)));
+        assert_eq!(attrs.get("imports"), Some(&NixValue::List(vec![])));
+        assert_eq!(attrs.get("withFoo"), Some(&var("recursive")));
+    }
+
+    #[test]
+    fn test_compact_output() {
+        let mut attrs = AttrSetMap::new();
+        attrs.insert("name", NixValue::String("a \"test\"\n".to_string()));
+        attrs.insert("version", NixValue::Int(1));
+        attrs.insert(
+            "deps",
+            NixValue::List(vec![var("foo"), apply(var("f"), var("x"))]),
+        );
+        let nested: NixValue = [("enable".to_string(), NixValue::Bool(true))]
+            .into_iter()
+            .collect();
+        attrs.insert("services", nested);
+        let value = NixValue::AttrSet(attrs);
+
+        let compact = value.to_compact_string();
+        assert_eq!(
+            compact,
+            "{ name = \"a \\\"test\\\"\\n\"; version = 1; deps = [ foo (f x) ]; \
+             services = { enable = true; }; }"
+        );
+        assert_ne!(compact, value.to_string());
+        assert!(value.to_string().contains('\n'));
+        // Même arbre, seule la mise en page change
+        assert_eq!(parse_nix_string(&compact).unwrap(), value);
+        assert_eq!(parse_nix_string(&value.to_string()).unwrap(), value);
+
+        let with_let = parse_nix_string("let a = { b = 1; }; in [ a ]").unwrap();
+        assert_eq!(with_let.to_compact_string(), "let a = { b = 1; }; in [ a ]");
+    }
+
+    #[test]
+    fn test_indented_string_round_trip() {
+        let input = "{\n  extraConfig = ''\n    set -e\n      echo ${pkgs.hello}/bin\n    \
+                     echo '''a''' ''${HOME}\n\n    done\n  '';\n}";
+        let result = parse_nix_string(input).unwrap();
+        let script = result.get("extraConfig").unwrap();
+        assert_eq!(
+            script,
+            &NixValue::IndentedString(vec![
+                StringPart::Literal("set -e\n  echo ".to_string()),
+                StringPart::Interpolation(NixValue::Select(Box::new(NixSelect {
+                    expr: var("pkgs"),
+                    path: vec!["hello".into()],
+                    default: None,
+                }))),
+                StringPart::Literal("/bin\necho ''a'' ${HOME}\n\ndone\n".to_string()),
+            ])
+        );
+        // Réécrite en `''...''`, à l'identique
+        assert_eq!(result.to_string(), input);
+        assert_eq!(parse_nix_string(&result.to_string()).unwrap(), result);
+
+        // Première ligne vide ignorée, dernière ligne d'espaces retirée
+        let dedented = parse_nix_string("''\n    a\n      b\n  ''").unwrap();
+        assert_eq!(dedented.as_str(), Some("a\n  b\n"));
+        assert_eq!(parse_nix_string("''a''").unwrap().to_string(), "''a''");
+
+        // Contenu tout indenté : relu différemment en `''...''`, donc écrit entre guillemets
+        let shifted = NixValue::IndentedString(vec![StringPart::Literal("  a\n".to_string())]);
+        assert_eq!(shifted.to_string(), "\"  a\\n\"");
+        // Le mode compact n'a pas de retours à la ligne
+        assert_eq!(script.to_compact_string().lines().count(), 1);
+    }
+
+    #[test]
+    fn test_negative_numbers() {
+        let result = parse_nix_string("[ -1 -.5 - 3 ]").unwrap();
+        assert_eq!(
+            result,
+            NixValue::List(vec![
+                NixValue::Int(-1),
+                NixValue::Float(-0.5),
+                unop(UnOp::Neg, NixValue::Int(3)),
+            ])
+        );
+        assert_eq!(
+            result.eval(),
+            Ok(NixValue::List(vec![
+                NixValue::Int(-1),
+                NixValue::Float(-0.5),
+                NixValue::Int(-3)
+            ]))
+        );
+        assert_eq!(parse_nix_string(&result.to_string()).unwrap(), result);
+
+        // Mêmes règles hors des listes
+        let attrs = parse_nix_string("{ a = -3; b = -.5; c = - 3; d = .25; e = 1.5e3; }").unwrap();
+        assert_eq!(attrs.get("a"), Some(&NixValue::Int(-3)));
+        assert_eq!(attrs.get("b"), Some(&NixValue::Float(-0.5)));
+        assert_eq!(attrs.get("c"), Some(&unop(UnOp::Neg, NixValue::Int(3))));
+        assert_eq!(attrs.get("d"), Some(&NixValue::Float(0.25)));
+        assert_eq!(attrs.get("e"), Some(&NixValue::Float(1500.0)));
+        // Un flottant entier reste un flottant à la relecture
+        assert_eq!(NixValue::Float(1500.0).to_string(), "1500.0");
+        assert_eq!(parse_nix_string(&attrs.to_string()).unwrap(), attrs);
+
+        assert_eq!(
+            parse_nix_string("x - .5").unwrap(),
+            binop(BinOp::Sub, var("x"), NixValue::Float(0.5))
+        );
+    }
+
+    #[test]
+    fn test_children() {
+        let result = parse_nix_string("let a = 1; b = [ a ]; in a + 2").unwrap();
+        let children: Vec<&NixValue> = result.children().collect();
+        assert_eq!(
+            children,
+            [
+                &NixValue::Int(1),
+                &NixValue::List(vec![var("a")]),
+                &binop(BinOp::Add, var("a"), NixValue::Int(2)),
+            ]
+        );
+        assert_eq!(NixValue::Int(1).children().count(), 0);
+
+        // Parcours manuel par liste de travail : tous les imports de l'arbre
+        let tree = parse_nix_string("{ a = import ./a.nix; b = [ (import ./b.nix) ]; }").unwrap();
+        let mut imports = Vec::new();
+        let mut worklist = vec![&tree];
+        while let Some(node) = worklist.pop() {
+            if let NixValue::Import(arg) = node {
+                imports.push(arg.to_string());
+            }
+            worklist.extend(node.children());
+        }
+        imports.sort();
+        assert_eq!(imports, ["./a.nix", "./b.nix"]);
+    }
+
+    #[test]
+    fn test_has_attr() {
+        let result = parse_nix_string("x ? y").unwrap();
+        let NixValue::HasAttr(has_attr) = &result else {
+            panic!("Expected a has-attr expression");
+        };
+        assert_eq!(has_attr.expr, var("x"));
+        assert_eq!(has_attr.path, vec![AttrKey::Static("y".to_string())]);
+
+        let result = parse_nix_string("x ? a.b").unwrap();
+        let NixValue::HasAttr(has_attr) = &result else {
+            panic!("Expected a has-attr expression");
+        };
+        assert_eq!(has_attr.path.len(), 2);
+        assert_eq!(result.to_string(), "x ? a.b");
+
+        // `?` lie plus fort que `==` et `!`, moins fort que la négation
+        let result = parse_nix_string("!a ? b == c").unwrap();
+        assert_eq!(result.to_string(), "!a ? b == c");
+        assert!(matches!(&result, NixValue::BinaryOp(op) if op.op == BinOp::Eq));
+        assert!(matches!(
+            parse_nix_string("-a ? b").unwrap(),
+            NixValue::HasAttr(_)
+        ));
+
+        let eval = |input: &str| parse_nix_string(input).unwrap().eval();
+        assert_eq!(eval("{ a.b = 1; } ? a.b"), Ok(true.into()));
+        assert_eq!(eval("{ a = 1; } ? a.b"), Ok(false.into()));
+        assert_eq!(eval("{ a = 1; } ? b"), Ok(false.into()));
+    }
+
+    #[test]
+    fn test_mismatched_delimiters() {
+        let err = parse_nix_string("[ 1 2 }").unwrap_err();
+        assert_eq!(
+            err.message,
+            "Expected ']' to close list opened at line 1, col 1, found '}'"
+        );
+        assert_eq!((err.line, err.col), (1, 7));
+
+        let err = parse_nix_string("{\n  a = 1 ]").unwrap_err();
+        assert_eq!(
+            err.message,
+            "Expected '}' to close attribute set opened at line 1, col 1, found ']'"
+        );
+        assert_eq!((err.line, err.col), (2, 9));
+
+        // L'ouvrant signalé est le plus proche, pas le plus externe
+        let err = parse_nix_string("{ a = [ 1 }; }").unwrap_err();
+        assert!(err.message.contains("close list opened at line 1, col 7"));
+    }
+
+    #[test]
+    fn test_interpolated_path() {
+        let result = parse_nix_string("./${name}.nix").unwrap();
+        assert_eq!(
+            result,
+            NixValue::InterpolatedPath(vec![
+                StringPart::Literal("./".to_string()),
+                StringPart::Interpolation(var("name")),
+                StringPart::Literal(".nix".to_string()),
+            ])
+        );
+        assert_eq!(result.to_string(), "./${name}.nix");
+
+        // Sans antiquotation, le chemin reste un `Path` simple
+        assert_eq!(
+            parse_nix_string("./a/b.nix").unwrap(),
+            NixValue::Path(PathKind::Relative, "./a/b.nix".to_string())
+        );
+
+        let result = parse_nix_string("let n = \"foo\"; in ./${n}.nix").unwrap();
+        assert_eq!(
+            result.eval(),
+            Ok(NixValue::Path(PathKind::Relative, "./foo.nix".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_path_kinds() {
+        let cases = [
+            ("./a/b.nix", PathKind::Relative, "./a/b.nix"),
+            ("/etc/nixos", PathKind::Absolute, "/etc/nixos"),
+            ("~/config.nix", PathKind::Home, "~/config.nix"),
+            ("<nixpkgs/lib>", PathKind::SearchPath, "nixpkgs/lib"),
+        ];
+        for (input, kind, text) in cases {
+            let result = parse_nix_string(input).unwrap();
+            assert_eq!(result, NixValue::Path(kind, text.to_string()), "{}", input);
+            assert_eq!(result.to_string(), input);
+        }
+
+        // `<` n'ouvre un chemin que s'il est suivi d'un chemin et de `>`
+        let result = parse_nix_string("f <nixpkgs> (a <b)").unwrap();
+        assert_eq!(result.to_string(), "f <nixpkgs> (a < b)");
+        let result = parse_nix_string("import <nixpkgs> { }").unwrap();
+        let NixValue::Apply(apply) = &result else {
+            panic!("Expected an application");
+        };
+        assert_eq!(apply.func, import(PathKind::SearchPath, "nixpkgs"));
+    }
+
+    #[test]
+    fn test_from_json() {
+        let json = r#"{
+            "networking": { "hostName": "nixos", "firewall": { "allowedTCPPorts": [22, 80] } },
+            "boot.loader": { "timeout": 5, "ratio": 0.5, "enable": true },
+            "my-key": null,
+            "2fa": "\u00e9\ud83d\ude00\n"
+        }"#;
+        let value = NixValue::from_json(json).unwrap();
+
+        assert_eq!(value.get("my-key"), Some(&NixValue::Null));
+        assert_eq!(value.get("2fa").and_then(NixValue::as_str), Some("é😀\n"));
+        let boot = value.get("boot.loader").unwrap();
+        assert_eq!(boot.get("timeout"), Some(&NixValue::Int(5)));
+        assert_eq!(boot.get("ratio"), Some(&NixValue::Float(0.5)));
+
+        // Les clés qui ne sont pas des identifiants sont écrites entre guillemets
+        let nix = value.to_string();
+        assert!(nix.contains("\"boot.loader\" = {"));
+        assert!(nix.contains("\"2fa\" = \"é😀\\n\";"));
+        assert!(nix.contains("my-key = null;"));
+        assert_eq!(parse_nix_string(&nix).unwrap(), value);
+
+        assert!(NixValue::from_json("[1, 2").is_err());
+        assert!(NixValue::from_json("{\"a\": 1} x").is_err());
+
+        let number = |json: &str| NixValue::from_json(json);
+        assert_eq!(number("0").unwrap(), NixValue::Int(0));
+        assert_eq!(number("-0.5e+1").unwrap(), NixValue::Float(-5.0));
+        assert_eq!(number("10E2").unwrap(), NixValue::Float(1000.0));
+        for malformed in [
+            "01", "-01", "-", "1.", ".5", "1e", "1e+", "+1", "1.e5", "--1",
+        ] {
+            assert!(number(malformed).is_err(), "{}", malformed);
+        }
+
+        // Les demi-codets UTF-16 doivent aller par paires
+        for unpaired in [
+            r#""\ud800""#,
+            r#""\udc00""#,
+            r#""\ud800\u0041""#,
+            r#""\ude00\ud83d""#,
+        ] {
+            assert!(NixValue::from_json(unpaired).is_err(), "{}", unpaired);
+        }
+    }
+
+    #[test]
+    fn test_semantic_eq() {
+        let a = parse_nix_string("{ a = 1; b = { x = [ 1 2 ]; y = 2; }; }").unwrap();
+        let b = parse_nix_string("{ b = { y = 2; x = [ 1 2 ]; }; a = 1; }").unwrap();
+        assert_ne!(a, b);
+        assert!(a.semantic_eq(&b));
+
+        let a = parse_nix_string("let x = 1; y = 2; in [ x y ]").unwrap();
+        let b = parse_nix_string("let y = 2; x = 1; in [ x y ]").unwrap();
+        assert!(a.semantic_eq(&b));
+
+        // L'ordre des éléments d'une liste compte
+        let a = parse_nix_string("{ l = [ 1 2 ]; }").unwrap();
+        let b = parse_nix_string("{ l = [ 2 1 ]; }").unwrap();
+        assert!(!a.semantic_eq(&b));
+    }
+
+    #[test]
+    fn test_select_dynamic_component() {
+        let result = parse_nix_string("set.${key}").unwrap();
+        let expected = NixValue::Select(Box::new(NixSelect {
+            expr: var("set"),
+            path: vec![AttrKey::Dynamic(var("key"))],
+            default: None,
+        }));
+        assert_eq!(result, expected);
+        assert_eq!(result.to_string(), "set.${key}");
+
+        let result = parse_nix_string("a.${b}.c").unwrap();
+        assert_eq!(result.to_string(), "a.${b}.c");
+
+        let result = parse_nix_string("let key = \"k\"; in { k = 1; }.${key}").unwrap();
+        assert_eq!(result.eval(), Ok(NixValue::Int(1)));
+    }
+
+    #[test]
+    fn test_raw_strings() {
+        let parse_raw = |input: &str| {
+            NixParser::new(input)
+                .with_raw_strings(true)
+                .parse()
+                .unwrap()
+        };
+
+        let result = parse_raw(r#""a\nb""#);
+        assert_eq!(
+            result,
+            NixValue::RawString(
+                StringKind::DoubleQuoted,
+                vec![StringPart::Literal(r"a\nb".to_string())]
+            )
+        );
+        assert_eq!(result.to_string(), r#""a\nb""#);
+
+        // Texte recopié à l'octet près, échappements inconnus et indentation compris
+        let input = r#"{
+  a = "x\qy\$z ${name} \${lit}";
+  b = ''
+      deux''' espaces ''${x} ''\t
+    ${toString n}
+  '';
+  c = "vraie
+ligne";
+}"#;
+        let result = parse_raw(input);
+        assert_eq!(result.to_string(), input);
+
+        // Les clés restent décodées, et l'évaluation décode les littéraux
+        assert!(parse_raw(r#"{ "k\"" = 1; }"#).get("k\"").is_some());
+        let result = parse_raw(r#"let n = "x"; in "a\t${n}\q""#);
+        assert_eq!(result.eval(), Ok("a\txq".into()));
+        let result = parse_raw("''\n    a''$\n      ${\"b\"}\n  ''");
+        assert_eq!(result.eval(), Ok("a$\n  b\n".into()));
+    }
+
+    #[test]
+    fn test_display_with() {
+        let input = "{\n  environment.systemPackages = with pkgs; [\n    git\n    vim\n  ];\n}";
+        let result = parse_nix_string(input).unwrap();
+        assert_eq!(result.to_string(), input);
+        assert_eq!(parse_nix_string(&result.to_string()).unwrap(), result);
+
+        let result = parse_nix_string("(with a; b) c").unwrap();
+        assert_eq!(result.to_string(), "(with a; b) c");
+    }
+
+    #[test]
+    fn test_parse_inherit() {
+        let input = r#"{ inherit a "foo.bar"; inherit (pkgs) lib; b = 1; }"#;
+        let result = parse_nix_string(input).unwrap();
+        let attrs = result.as_attrset().unwrap();
+        assert_eq!(
+            attrs.inherits,
+            vec![
+                NixInherit::new(None, vec!["a".to_string(), "foo.bar".to_string()]),
+                NixInherit::new(Some(var("pkgs")), vec!["lib".to_string()]),
+            ]
+        );
+        assert_eq!(attrs.len(), 4);
+        assert_eq!(
+            result.to_string(),
+            "{\n  inherit a \"foo.bar\";\n  inherit (pkgs) lib;\n  b = 1;\n}"
+        );
+
+        let err = parse_nix_string("{ inherit a b").unwrap_err();
+        assert_eq!(err.message, "Unterminated inherit, expected ';'");
+        assert_eq!((err.line, err.col), (1, 3));
+
+        // `inherit a;` lit `a` hors de l'ensemble, même récursif
+        let result = parse_nix_string("rec { inherit a; b = a; }").unwrap();
+        assert_eq!(result.free_variables(), NameSet::from(["a".to_string()]));
+        let eval = |input: &str| parse_nix_string(input).unwrap().eval();
+        assert_eq!(
+            eval("let a = 1; in rec { inherit a; b = a + 1; }.b"),
+            Ok(NixValue::Int(2))
+        );
+        assert_eq!(
+            eval("let s = { x = 3; }; inherit (s) x; in x"),
+            Ok(NixValue::Int(3))
+        );
+    }
+
+    #[test]
+    fn test_parse_with_spans() {
+        let input = "{ a = 123; }";
+        let (value, tree) = NixParser::new(input).parse_with_spans().unwrap();
+        assert_eq!(value, parse_nix_string(input).unwrap());
+        assert_eq!(tree.span, Span { start: 0, end: 12 });
+        assert_eq!(tree.children[0].span, Span { start: 6, end: 9 });
+        assert_eq!(&input[6..9], "123");
+
+        // Chaque nœud se reparse, seul, à l'identique depuis le texte de sa position
+        fn check(input: &str, value: &NixValue, tree: &SpanTree) {
+            let text = &input[tree.span.start..tree.span.end];
+            assert_eq!(
+                &parse_nix_string(text).unwrap(),
+                value,
+                "span text {text:?}"
+            );
+            assert_eq!(
+                value.children().count(),
+                tree.children.len(),
+                "children of {text:?}"
+            );
+            for (child, child_tree) in value.children().zip(&tree.children) {
+                check(input, child, child_tree);
+            }
+        }
+        let input = r#"
+            # configuration
+            { pkgs, ... }:
+            let
+              inherit (pkgs) lib;
+              name = "web-${pkgs.version}"; # nom
+            in rec {
+              inherit name;
+              port = -8080 + 2 * 3;
+              ${name}.enable = !false && pkgs ? hello;
+              paths = [ ./a/${name}.nix ~/b <nixpkgs> (x: x.y or null) ];
+              value = if true then with lib; mkIf 1 2 else assert true; import ./c.nix;
+              legacy = let { x = 1; body = x; };
+            }
+        "#;
+        let (value, tree) = NixParser::new(input).parse_with_spans().unwrap();
+        check(input, &value, &tree);
+        assert_eq!(
+            &input[tree.span.start..tree.span.start + 13],
+            "{ pkgs, ... }"
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_write_nix_file_is_atomic() {
+        let dir = std::env::temp_dir().join(format!("nix-parser-atomic-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("configuration.nix");
+        fs::write(&path, "{ a = 1; }\n").unwrap();
+
+        // Écriture interrompue avant le renommage : la cible n'a pas bougé
+        let temp = write_temp_file(&path, "{ a = 2; }\n").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "{ a = 1; }\n");
+        assert_eq!(fs::read_to_string(&temp).unwrap(), "{ a = 2; }\n");
+        // Deux écritures du même fichier ont chacune leur fichier temporaire
+        let other = write_temp_file(&path, "{ a = 4; }\n").unwrap();
+        assert_ne!(other, temp);
+        assert_eq!(fs::read_to_string(&temp).unwrap(), "{ a = 2; }\n");
+        fs::remove_file(&temp).unwrap();
+        fs::remove_file(&other).unwrap();
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+        }
+        let value = parse_nix_string("{ a = 3; }").unwrap();
+        write_nix_file(&path, &value).unwrap();
+        assert_eq!(parse_nix_file(&path).unwrap(), value);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o640);
+        }
+
+        // Aucun fichier temporaire ne reste à côté de la cible
+        let files: Vec<_> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect();
+        assert_eq!(files, vec!["configuration.nix"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_comments_around_pattern_lookahead() {
+        let input = "{ # comment\n  a = 1; # inline\n  # between\n  b = 2;\n}";
+        for keep_comments in [false, true] {
+            let value = NixParser::new(input)
+                .with_comments(keep_comments)
+                .parse()
+                .unwrap();
+            let NixValue::AttrSet(attrs) = value else {
+                panic!("Expected attrset, got {:?}", value);
+            };
+            assert_eq!(attrs.get("b"), Some(&NixValue::Int(2)));
+            if keep_comments {
+                assert_eq!(attrs.entries()[0].comments, vec!["# comment"]);
+                assert_eq!(attrs.entries()[1].comments, vec!["# between"]);
+            }
+        }
+
+        let input =
+            "{ # comment\n  pkgs, # inline\n  # between\n  lib, /* block */\n  ... # end\n}:\npkgs";
+        for keep_comments in [false, true] {
+            let value = NixParser::new(input)
+                .with_comments(keep_comments)
+                .parse()
+                .unwrap();
+            let NixValue::Function(func) = value else {
+                panic!("Expected function, got {:?}", value);
+            };
+            assert_eq!(func.params, vec!["pkgs", "lib"]);
+            assert_eq!(func.body, var("pkgs"));
+        }
+
+        // Virgule finale et commentaire avant le ':'
+        let value = parse_nix_string("{ # comment\n  a, # inline\n} # after\n: a").unwrap();
+        assert!(matches!(value, NixValue::Function(f) if f.params == vec!["a"]));
+    }
+
+    #[test]
+    fn test_map_strings_and_transform() {
+        let value = parse_nix_string(
+            r#"{ a = "foo"; b = [ "foo" { c = "x-${"foo"}"; } ]; d = ''
+              foo
+            ''; foo = 1; }"#,
+        )
+        .unwrap();
+        let mapped = value.map_strings(|s| s.replace("foo", "bar"));
+        let expected = parse_nix_string(
+            r#"{ a = "bar"; b = [ "bar" { c = "x-${"bar"}"; } ]; d = ''
+              bar
+            ''; foo = 1; }"#,
+        )
+        .unwrap();
+        assert_eq!(mapped, expected);
+        // L'original n'est pas modifié
+        assert_eq!(value.get("a"), Some(&NixValue::String("foo".into())));
+
+        // Les enfants sont transformés avant leur parent
+        let value = parse_nix_string("1 + 2 * 3").unwrap();
+        let folded = value.transform(|node| match node {
+            NixValue::BinaryOp(op) => match (op.op, &op.lhs, &op.rhs) {
+                (BinOp::Add, NixValue::Int(a), NixValue::Int(b)) => NixValue::Int(a + b),
+                (BinOp::Mul, NixValue::Int(a), NixValue::Int(b)) => NixValue::Int(a * b),
+                _ => NixValue::BinaryOp(op),
+            },
+            other => other,
+        });
+        assert_eq!(folded, NixValue::Int(7));
+    }
+
+    #[test]
+    fn test_stats() {
+        let value = parse_nix_string(r#"{ a = { b = 1; }; c = [ "x" ]; }"#).unwrap();
+        let stats = value.stats();
+        assert_eq!(stats.attrsets, 2);
+        assert_eq!(stats.lists, 1);
+        assert_eq!(stats.strings, 1);
+        assert_eq!(stats.functions, 0);
+        assert_eq!(stats.nodes, 5);
+        assert_eq!(stats.max_depth, 2);
+
+        assert_eq!(
+            NixValue::Int(1).stats(),
+            NixStats {
+                nodes: 1,
+                ..NixStats::default()
+            }
+        );
+        let stats = parse_nix_string("x: let y = f x; in y").unwrap().stats();
+        assert_eq!((stats.functions, stats.lets, stats.applications), (1, 1, 1));
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_crlf_file_error_position() {
+        let path = std::env::temp_dir().join(format!("nix-parser-crlf-{}.nix", std::process::id()));
+        fs::write(&path, "{\r\n  a = 1;\r\n  b = \"x\";\r\n  c = ;\r\n}\r\n").unwrap();
+        let result = parse_nix_file(&path);
+        fs::remove_file(&path).unwrap();
+        match result {
+            Err(NixError::Parse(e)) => {
+                assert_eq!((e.line, e.col), (4, 7));
+                assert!(!e.context.contains('\r'));
+            }
+            other => panic!("Expected Parse error, got {:?}", other),
+        }
+
+        // Fins de ligne mélangées dans un même fichier
+        let err = parse_nix_string("{\r\n  a = 1;\n  b = 2;\r  c = ;\r\n}").unwrap_err();
+        assert_eq!((err.line, err.col), (4, 7));
+    }
+
+    #[test]
+    fn test_builders() {
+        let value = LetBuilder::new()
+            .bind("x", 1)
+            .body(AttrSetBuilder::new().set("y", var("x")).build())
+            .build();
+        assert_eq!(value, parse_nix_string("let x = 1; in { y = x; }").unwrap());
+        assert_eq!(parse_nix_string(&value.to_string()).unwrap(), value);
+        assert_eq!(value.eval().unwrap().get("y"), Some(&NixValue::Int(1)));
+
+        let path: Vec<AttrKey> = vec!["b".into(), "c".into()];
+        let value = AttrSetBuilder::new()
+            .rec()
+            .set("a", "x")
+            .set(path, true)
+            .set("a", 2)
+            .build();
+        assert_eq!(
+            value,
+            parse_nix_string("rec { a = 2; b.c = true; }").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_or_is_contextual() {
+        // Opérateur après une sélection
+        let value = parse_nix_string("a.b or c").unwrap();
+        assert!(matches!(&value, NixValue::Select(s) if s.default == Some(var("c"))));
+
+        // Identifiant partout ailleurs
+        let value = parse_nix_string("{ or = 1; }").unwrap();
+        assert_eq!(value.get("or"), Some(&NixValue::Int(1)));
+        assert_eq!(
+            parse_nix_string("[ or ]").unwrap(),
+            NixValue::List(vec![var("or")])
+        );
+        assert_eq!(
+            parse_nix_string("f or").unwrap(),
+            var("f").apply_args(&[var("or")])
+        );
+        let value = parse_nix_string("let or = 1; x = { }; in x.or or or").unwrap();
+        assert_eq!(value.eval().unwrap(), NixValue::Int(1));
+        for input in ["{ or = 1; }", "f or", "x.or or or"] {
+            let value = parse_nix_string(input).unwrap();
+            assert_eq!(
+                parse_nix_string(&value.to_string()).unwrap(),
+                value,
+                "{}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn test_collapse_below_depth() {
+        let input = "{ a = { b = 1; c = [ 2 3 ]; }; d = { e = true; }; f = 4; }";
+        let value = parse_nix_string(input).unwrap();
+        assert_eq!(value.depth(), 3);
+        assert_eq!(value.get("d").unwrap().depth(), 1);
+        assert_eq!(NixValue::Int(4).depth(), 0);
+
+        let opts = FormatOptions::default().collapse_below(1);
+        assert_eq!(
+            value.format(&opts),
+            "{\n  a = {\n    b = 1;\n    c = [ 2 3 ];\n  };\n  d = { e = true; };\n  f = 4;\n}"
+        );
+        let opts = FormatOptions::default().collapse_below(2);
+        assert_eq!(
+            value.format(&opts),
+            "{\n  a = { b = 1; c = [ 2 3 ]; };\n  d = { e = true; };\n  f = 4;\n}"
+        );
+        assert_eq!(parse_nix_string(&value.format(&opts)).unwrap(), value);
+    }
+
+    #[test]
+    fn test_escaped_dollar() {
+        assert_eq!(
+            parse_nix_string(r#""\${""#).unwrap(),
+            NixValue::String("${".into())
+        );
+        let value = parse_nix_string(r#""price: \$5""#).unwrap();
+        assert_eq!(value, NixValue::String("price: $5".into()));
+        assert_eq!(
+            parse_nix_string(r#""\${x}""#).unwrap(),
+            NixValue::String("${x}".into())
+        );
+
+        // `$${` est littéral, dans les deux formes de strings
+        assert_eq!(
+            parse_nix_string(r#""$${x}""#).unwrap(),
+            NixValue::String("$${x}".into())
+        );
+        let value = parse_nix_string("''$${x}''").unwrap();
+        assert_eq!(
+            value,
+            NixValue::IndentedString(vec![StringPart::Literal("$${x}".into())])
+        );
+        let value = parse_nix_string(r#""a$$${x}""#).unwrap();
+        let expected = vec![
+            StringPart::Literal("a$$".into()),
+            StringPart::Interpolation(var("x")),
+        ];
+        assert_eq!(value, NixValue::InterpolatedString(expected));
+
+        // Un `$` juste avant une interpolation est réécrit échappé
+        let value = NixValue::InterpolatedString(vec![
+            StringPart::Literal("$".into()),
+            StringPart::Interpolation(var("x")),
+        ]);
+        assert_eq!(value.to_string(), r#""\$${x}""#);
+        assert_eq!(parse_nix_string(&value.to_string()).unwrap(), value);
+    }
+
+    #[test]
+    fn test_validate() {
+        let value = parse_nix_string("{ a, b, a, ... }: a").unwrap();
+        let errors = value.validate().unwrap_err();
+        assert_eq!(
+            errors,
+            vec![ValidationError {
+                kind: ValidationErrorKind::DuplicateParam,
+                name: "a".into()
+            }]
+        );
+        assert_eq!(
+            errors[0].to_string(),
+            "Le paramètre 'a' est déclaré plusieurs fois"
+        );
+
+        let value = parse_nix_string(
+            "{ a.b = 1; a.c = 2; inherit x; x = 3; a.b = 4; l = let y = 1; y = 2; in y; }",
+        )
+        .unwrap();
+        let names: Vec<_> = value
+            .validate()
+            .unwrap_err()
+            .into_iter()
+            .map(|e| e.name)
+            .collect();
+        assert_eq!(names, vec!["x", "a.b", "y"]);
+
+        let value = parse_nix_string("import ./a.nix { x = import <nixpkgs>; }").unwrap();
+        assert_eq!(value.validate(), Ok(()));
+        let errors = import(PathKind::Relative, "./foo bar")
+            .validate()
+            .unwrap_err();
+        assert_eq!(errors[0].kind, ValidationErrorKind::InvalidImport);
+    }
+
+    #[test]
+    fn test_flatten_and_unflatten_attrs() {
+        let nested = parse_nix_string(
+            "{ services = { nginx = { enable = true; port = 80; }; ssh.enable = false; };
+               empty = { }; x = 1; }",
+        )
+        .unwrap();
+        let flat = parse_nix_string(
+            "{ services.nginx.enable = true; services.nginx.port = 80;
+               services.ssh.enable = false; empty = { }; x = 1; }",
+        )
+        .unwrap();
+        assert_eq!(nested.flatten_attrs().unwrap(), flat);
+        let unflattened = flat.unflatten_attrs().unwrap();
+        assert!(unflattened.semantic_eq(&nested.unflatten_attrs().unwrap()));
+        assert_eq!(
+            unflattened
+                .get("services")
+                .and_then(|s| s.get("nginx"))
+                .and_then(|n| n.get("port")),
+            Some(&NixValue::Int(80))
+        );
+        assert_eq!(unflattened.flatten_attrs().unwrap(), flat);
+
+        // `a = { ... }` et `a.c = ...` se complètent
+        let value = parse_nix_string("{ a = { b = 1; }; a.c = 2; }").unwrap();
+        let expected = parse_nix_string("{ a = { b = 1; c = 2; }; }").unwrap();
+        assert_eq!(value.unflatten_attrs().unwrap(), expected);
+
+        // Une clé qui est à la fois une valeur et un parent
+        let value = parse_nix_string("{ a = 1; a.b = 2; }").unwrap();
+        let err = value.unflatten_attrs().unwrap_err();
+        assert_eq!(err.kind, ValidationErrorKind::ConflictingAttribute);
+        assert_eq!(err.name, "a.b");
+        let err = value.flatten_attrs().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "L'attribut 'a' est à la fois une valeur et un ensemble"
+        );
+    }
+
+    #[test]
+    fn test_list_elements_are_selections() {
+        let f_x = var("f").apply_args(&[var("x")]);
+        assert_eq!(
+            parse_nix_string("[ f x ]").unwrap(),
+            NixValue::List(vec![var("f"), var("x")])
+        );
+        assert_eq!(
+            parse_nix_string("[ (f x) ]").unwrap(),
+            NixValue::List(vec![f_x.clone()])
+        );
+        let g_y = var("g").apply_args(&[var("y")]);
+        assert_eq!(
+            parse_nix_string("[(f x)(g y)]").unwrap(),
+            NixValue::List(vec![f_x, g_y])
+        );
+
+        // Une sélection, `or` compris, reste un seul élément
+        let value = parse_nix_string("[ pkgs.hello pkgs.vim or null ./a \"b\"\"c\" ]").unwrap();
+        assert_eq!(value.as_list().map(<[_]>::len), Some(5));
+
+        assert_eq!(
+            parse_nix_string("[ (f x) ]").unwrap().to_string(),
+            "[\n  (f x)\n]"
+        );
+    }
+
+    #[test]
+    fn test_unexpected_character() {
+        let cases = [
+            ("@foo", '@', 1),
+            (")", ')', 1),
+            ("1 + ;", ';', 5),
+            ("[ a @ ]", '@', 5),
+            // Après une expression complète, au lieu d'ignorer la suite
+            ("a @ b", '@', 3),
+            ("1 )", ')', 3),
+            ("a; b", ';', 2),
+            ("x: x @", '@', 6),
+        ];
+        for (input, c, col) in cases {
+            let err = parse_nix_string(input).unwrap_err();
+            assert_eq!(
+                err.message,
+                format!("Unexpected character '{}'", c),
+                "{}",
+                input
+            );
+            assert_eq!((err.line, err.col), (1, col), "{}", input);
+        }
+        let err = parse_nix_string("{\n  a = );\n}").unwrap_err();
+        assert_eq!(
+            (err.message.as_str(), err.line, err.col),
+            ("Unexpected character ')'", 2, 7)
+        );
+    }
+
+    #[test]
+    fn test_structural_hash() {
+        let a = parse_nix_string("{ a = [ 1 2.5 \"x${y}\" ]; b = { c = f x; }; }").unwrap();
+        let b = parse_nix_string("{ b = { c = f x; }; a = [ 1 2.5 \"x${y}\" ]; }").unwrap();
+        assert!(a.semantic_eq(&b));
+        assert_eq!(a.structural_hash(), b.structural_hash());
+        assert_eq!(a.structural_hash(), a.clone().structural_hash());
+
+        let c = parse_nix_string("{ a = [ 1 2.5 \"x${z}\" ]; b = { c = f x; }; }").unwrap();
+        assert_ne!(a.structural_hash(), c.structural_hash());
+        assert_eq!(
+            NixValue::Float(0.0).structural_hash(),
+            NixValue::Float(-0.0).structural_hash()
+        );
+
+        // Déduplication de sous-arbres identiques
+        let list = parse_nix_string("[ { x = 1; y = 2; } { y = 2; x = 1; } { x = 2; } ]").unwrap();
+        let unique: BTreeSet<u64> = list.children().map(NixValue::structural_hash).collect();
+        assert_eq!(unique.len(), 2);
+    }
+
+    #[test]
+    fn test_parse_with_recovery() {
+        let input = "{\n  a = 1;\n  b = ;\n  c = [ 1 @ 3 ];\n  d = { x = ); };\n  e = 2;\n}";
+        let (value, errors) = parse_with_recovery(input);
+        let positions: Vec<_> = errors.iter().map(|e| (e.line, e.col)).collect();
+        assert_eq!(positions, vec![(3, 7), (4, 11), (5, 13)]);
+        let expected = parse_nix_string("{ a = 1; c = [ 1 3 ]; d = { }; e = 2; }").unwrap();
+        assert_eq!(value, Some(expected));
+
+        // Comme dans un ensemble, `@` signale d'abord le `;` manquant après `b`
+        let (value, errors) = parse_with_recovery("let a = 1 +; b = \"x;y\" @; in a");
+        let messages: Vec<_> = errors.iter().map(|e| e.message.as_str()).collect();
+        assert_eq!(
+            messages,
+            [
+                "Unexpected character ';'",
+                "Expected ';' after let binding 'b'",
+                "Expected identifier",
+            ]
+        );
+        assert_eq!(
+            value,
+            Some(parse_nix_string("let b = \"x;y\"; in a").unwrap())
+        );
+
+        // Une erreur hors d'une liaison ou d'un élément reste fatale
+        let (value, errors) = parse_with_recovery("{ a = 1; } )");
+        assert!(value.is_some() && errors.is_empty());
+        let (value, errors) = parse_with_recovery("[ 1 2");
+        assert_eq!((value, errors.len()), (None, 1));
+
+        // Sans reprise, la première erreur arrête le parsing
+        assert_eq!(parse_nix_string(input).unwrap_err().line, 3);
+    }
+
+    #[test]
+    fn test_lone_dollar_and_empty_interpolation() {
+        // Un `$` qui n'ouvre pas d'interpolation reste littéral
+        assert_eq!(
+            parse_nix_string(r#""$""#).unwrap(),
+            NixValue::String("$".into())
+        );
+        assert_eq!(
+            parse_nix_string(r#""$x""#).unwrap(),
+            NixValue::String("$x".into())
+        );
+        assert_eq!(
+            parse_nix_string(r#""a$ b""#).unwrap(),
+            NixValue::String("a$ b".into())
+        );
+        assert_eq!(
+            parse_nix_string(r#""\$""#).unwrap(),
+            NixValue::String("$".into())
+        );
+        let value = parse_nix_string("''$''").unwrap();
+        assert_eq!(
+            value,
+            NixValue::IndentedString(vec![StringPart::Literal("$".into())])
+        );
+
+        for input in [r#""${}""#, r#""a${ }b""#, "''${}''"] {
+            let err = parse_nix_string(input).unwrap_err();
+            assert_eq!(err.message, "Empty interpolation", "{input}");
+        }
+        let err = parse_nix_string(r#""${}""#).unwrap_err();
+        assert_eq!((err.line, err.col), (1, 4));
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_parse_nix_reader() {
+        let value = parse_nix_reader(std::io::Cursor::new("{ a = 1; }")).unwrap();
+        assert_eq!(value, parse_nix_string("{ a = 1; }").unwrap());
+
+        match parse_nix_reader(std::io::Cursor::new("{ a = ; }")) {
+            Err(NixError::Parse(e)) => assert_eq!((e.line, e.col), (1, 7)),
+            other => panic!("Expected Parse error, got {:?}", other),
+        }
+        // Un flux qui n'est pas de l'UTF-8 est une erreur d'entrée/sortie
+        match parse_nix_reader(&[0xff, 0xfe][..]) {
+            Err(NixError::Io(e)) => assert_eq!(e.kind(), std::io::ErrorKind::InvalidData),
+            other => panic!("Expected Io error, got {:?}", other),
         }
-        Ok(NixValue::AttrSet(attrs))
     }
 
-    fn parse_function_params(&mut self) -> Result<Vec<String>, ParseError> {
-        self.advance(); // '{'
-        self.skip_whitespace();
-
-        let mut params = Vec::new();
+    #[test]
+    fn test_error_context_display_width() {
+        // `e` + accent combinant : deux caractères, une seule colonne à l'écran
+        let err = parse_nix_string("{\n  a = \"e\u{301}\" );\n}").unwrap_err();
+        assert_eq!(err.line, 2);
+        assert_eq!(err.context, "  a = \"e\u{301}\" );\n          ^");
 
-        while self.current() != Some('}') && self.current().is_some() {
-            // Gérer le '...' qui termine les paramètres
-            if self.peek_string("...") {
-                self.advance();
-                self.advance();
-                self.advance();
-                self.skip_whitespace();
+        // Un idéogramme occupe deux colonnes
+        let err = parse_nix_string("[\n  \"日本\" ) ]").unwrap_err();
+        assert_eq!(err.context, "  \"日本\" ) ]\n         ^");
+    }
 
-                // Peut avoir une virgule après ...
-                if self.current() == Some(',') {
-                    self.advance();
-                    self.skip_whitespace();
-                }
+    #[test]
+    fn test_diff() {
+        let old = parse_nix_string(
+            "{ services.nginx = { enable = true; port = 80; }; users = [ \"a\" ]; inherit x; }",
+        )
+        .unwrap();
+        // Même contenu réordonné et en partie pointé, seul `port` change
+        let new = parse_nix_string(concat!(
+            "{ inherit x; users = [ \"a\" ]; ",
+            "services = { nginx.port = 8080; nginx.enable = true; }; }",
+        ))
+        .unwrap();
+        let changes = old.diff(&new);
+        assert_eq!(
+            changes,
+            vec![Change::Modified {
+                path: "services.nginx.port".into(),
+                old: NixValue::Int(80),
+                new: NixValue::Int(8080),
+            }]
+        );
+        assert_eq!(changes[0].to_string(), "~ services.nginx.port: 80 -> 8080");
+        assert_eq!(old.diff(&old), []);
 
-                // Si on trouve '}', on sort
-                if self.current() == Some('}') {
-                    break;
-                }
-                continue;
-            }
+        let old = parse_nix_string("{ a = [ 1 2 ]; b = 1; \"0\" = 1; }").unwrap();
+        let new = parse_nix_string("{ a = [ 1 3 4 ]; c = 1; \"0\" = 2; }").unwrap();
+        let changes: Vec<String> = old.diff(&new).iter().map(ToString::to_string).collect();
+        let expected = [
+            "~ a.1: 2 -> 3",
+            "+ a.2 = 4",
+            "- b = 1",
+            "~ \"0\": 1 -> 2",
+            "+ c = 1",
+        ];
+        assert_eq!(changes, expected);
 
-            let param = self.parse_identifier()?;
-            params.push(param);
-            self.skip_whitespace();
+        // Nœuds de natures différentes : remplacement entier
+        let old = parse_nix_string("{ a = { }; }").unwrap();
+        let changes = old.diff(&parse_nix_string("{ a = [ ]; }").unwrap());
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].path(), "a");
+    }
 
-            // Gérer la virgule
-            if self.current() == Some(',') {
-                self.advance();
-                self.skip_whitespace();
-            }
+    #[test]
+    fn test_pattern_ellipsis_round_trip() {
+        // Un motif strict ne devient pas permissif, et inversement
+        let inputs = [
+            "{ pkgs }: pkgs",
+            "{ pkgs, ... }: pkgs",
+            "{ a, b }: a",
+            "{ }: 1",
+            "{ ... }: 1",
+        ];
+        for input in inputs {
+            let value = parse_nix_string(input).unwrap();
+            assert_eq!(value.to_string(), input);
+            assert_eq!(parse_nix_string(&value.to_string()).unwrap(), value);
         }
+        assert_eq!(parse_nix_string("x: x").unwrap().to_string(), "x: x");
 
-        if self.current() == Some('}') {
-            self.advance(); // '}'
+        let NixValue::Function(strict) = parse_nix_string("{ pkgs }: pkgs").unwrap() else {
+            panic!("Expected function");
+        };
+        assert!(strict.pattern && !strict.ellipsis);
+        let NixValue::Function(simple) = parse_nix_string("pkgs: pkgs").unwrap() else {
+            panic!("Expected function");
+        };
+        assert!(!simple.pattern && !simple.ellipsis);
+        let NixValue::Function(open) = parse_nix_string("{ pkgs, ... }: pkgs").unwrap() else {
+            panic!("Expected function");
+        };
+        assert!(open.pattern && open.ellipsis);
+        assert_eq!(open.params, ["pkgs"]);
+    }
+
+    #[test]
+    fn test_get_path() {
+        let nested = parse_nix_string("{ services = { nginx = { enable = true; }; }; }").unwrap();
+        let dotted = parse_nix_string("{ services.nginx.enable = true; }").unwrap();
+        let mixed =
+            parse_nix_string("{ services.nginx = { port = 80; }; services.nginx.enable = true; }")
+                .unwrap();
+        for value in [&nested, &dotted, &mixed] {
+            let path = ["services", "nginx", "enable"];
+            assert_eq!(
+                value.get_path(&path),
+                Some(&NixValue::Bool(true)),
+                "{}",
+                value
+            );
         }
-        Ok(params)
+        assert!(
+            mixed
+                .get_path(&["services", "nginx"])
+                .and_then(NixValue::as_attrset)
+                .is_some()
+        );
+        // Aucun ensemble n'existe au milieu d'une clé pointée
+        assert_eq!(dotted.get_path(&["services", "nginx"]), None);
+        assert_eq!(
+            mixed.get_path(&["services", "nginx", "port"]),
+            Some(&NixValue::Int(80))
+        );
+        assert_eq!(nested.get_path(&[]), Some(&nested));
+
+        // Segment absent, ou valeur intermédiaire qui n'est pas un ensemble
+        assert_eq!(dotted.get_path(&["services", "httpd", "enable"]), None);
+        assert_eq!(dotted.get_path(&["services", "nginx", "enable", "x"]), None);
+        assert_eq!(NixValue::Int(1).get_path(&["a"]), None);
+
+        // Un nom entre guillemets n'est pas un chemin
+        let quoted = parse_nix_string("{ \"a.b\" = 1; }").unwrap();
+        assert_eq!(quoted.get_path(&["a", "b"]), None);
+        assert_eq!(quoted.get_path(&["a.b"]), Some(&NixValue::Int(1)));
     }
 
-    fn parse_let(&mut self) -> Result<NixValue, ParseError> {
-        for _ in 0..3 {
-            self.advance();
-        } // "let"
-        self.skip_whitespace();
+    #[test]
+    fn test_parse_fragment() {
+        let NixFragment::PartialLet(bindings) = parse_nix_fragment("let x = 1;").unwrap() else {
+            panic!("Expected a partial let");
+        };
+        assert_eq!(bindings.get("x"), Some(&NixValue::Int(1)));
+        let fragment = parse_nix_fragment("  # editing\n  let inherit a; y = [ 1 ];\n").unwrap();
+        assert!(!fragment.is_complete());
 
-        let mut bindings = HashMap::new();
+        // Le mode strict reste celui par défaut
+        assert!(parse_nix_string("let x = 1;").is_err());
 
-        while !self.peek_string("in") && self.current().is_some() {
-            let key = self.parse_identifier()?;
-            self.skip_whitespace();
+        let fragment = parse_nix_fragment("let x = 1; in x").unwrap();
+        assert_eq!(
+            fragment,
+            NixFragment::Complete(parse_nix_string("let x = 1; in x").unwrap())
+        );
+        assert!(fragment.is_complete());
 
-            if self.current() != Some('=') {
-                return Err(self.error("Expected '=' in let binding"));
-            }
-            self.advance();
-            self.skip_whitespace();
+        // Liaison inachevée, ou `let` incomplet qui n'est pas en tête
+        assert!(parse_nix_fragment("let x = ").is_err());
+        assert!(parse_nix_fragment("[ (let x = 1;").is_err());
+        assert!(parse_nix_fragment("let a = let b = 1;").is_err());
+    }
 
-            let value = self.parse_value()?;
-            bindings.insert(key, value);
+    #[test]
+    fn test_programmatic_keys_are_quoted() {
+        let value = AttrSetBuilder::new()
+            .set("weird key", 1)
+            .set("1st", 2)
+            .set("rec", 3)
+            .set("or", 4)
+            .set("café", 5)
+            .set("ok-name'", 6)
+            .build();
+        let expected = "{\n  \"weird key\" = 1;\n  \"1st\" = 2;\n  \"rec\" = 3;\n  or = 4;\n  \
+                        \"café\" = 5;\n  ok-name' = 6;\n}";
+        assert_eq!(value.to_string(), expected);
+        assert_eq!(parse_nix_string(&value.to_string()).unwrap(), value);
 
-            self.skip_whitespace();
-            if self.current() == Some(';') {
-                self.advance();
-                self.skip_whitespace();
-            }
+        let value = LetBuilder::new()
+            .bind("weird key", 1)
+            .body(var("x"))
+            .build();
+        assert_eq!(value.to_string(), "let\n  \"weird key\" = 1;\nin x");
+    }
+
+    #[test]
+    fn test_no_c_style_escapes() {
+        // Comme Nix : l'antislash tombe, les chiffres restent littéraux. Dans une string
+        // multi-lignes, un antislash sans `''` devant est lui-même littéral
+        for (input, expected) in [
+            (r#""\x41""#, "x41"),
+            (r#""\101""#, "101"),
+            (r#""\0""#, "0"),
+            (r#""\a\b\f\v""#, "abfv"),
+            ("''''\\x41 \\0''", "x41 \\0"),
+        ] {
+            let value = parse_nix_string(input).unwrap();
+            assert_eq!(value.as_str(), Some(expected), "{}", input);
+            assert_eq!(
+                parse_nix_string(&value.to_string()).unwrap().as_str(),
+                Some(expected)
+            );
         }
+    }
 
-        for _ in 0..2 {
-            self.advance();
-        } // "in"
-        self.skip_whitespace();
+    #[test]
+    fn test_align_equals() {
+        let value = parse_nix_string("{ a = 1; bbbb = 2; }").unwrap();
+        let opts = FormatOptions::default().align_equals(true);
+        assert_eq!(value.format(&opts), "{\n  a    = 1;\n  bbbb = 2;\n}");
 
-        let body = self.parse_value()?;
+        // Une clé trop longue n'impose pas sa largeur aux autres
+        let value = parse_nix_string(
+            "{ a = 1; bb = [ 1 ]; services.nginx.virtualHosts.default = { root = ./.; }; }",
+        )
+        .unwrap();
+        let formatted = value.format(&FormatOptions::default().align_at_most(10));
+        assert_eq!(
+            formatted,
+            "{\n  a  = 1;\n  bb = [\n    1\n  ];\n  services.nginx.virtualHosts.default = {\n    \
+             root = ./.;\n  };\n}"
+        );
+        assert_eq!(parse_nix_string(&formatted).unwrap(), value);
 
-        Ok(NixValue::Let(Box::new(NixLet { bindings, body })))
+        let value = parse_nix_string("let x = 1; long = 2; in x").unwrap();
+        let opts = FormatOptions::default().align_equals(true);
+        assert_eq!(value.format(&opts), "let\n  x    = 1;\n  long = 2;\nin x");
     }
 
-    fn parse_value(&mut self) -> Result<NixValue, ParseError> {
-        self.skip_whitespace();
+    #[test]
+    fn test_top_level_attribute_path_is_select() {
+        let expected = NixValue::Select(Box::new(NixSelect {
+            expr: var("a"),
+            path: vec!["b".into(), "c".into()],
+            default: None,
+        }));
+        assert_eq!(parse_nix_string("a.b.c").unwrap(), expected);
+        assert_eq!(parse_nix_string("(a.b.c)").unwrap(), expected);
+        assert_eq!(
+            NixParser::new("  a.b.c\n")
+                .parse_expression()
+                .unwrap()
+                .to_string(),
+            "a.b.c"
+        );
 
-        // Détecter une fonction avec pattern { param1, param2, ... }:
-        if self.current() == Some('{') {
-            let saved_pos = self.pos;
-            let saved_line = self.line;
-            let saved_col = self.col;
+        // Aucune variable ne contient de point
+        let value = parse_nix_string("config.services.nginx").unwrap();
+        assert!(!value.free_variables().iter().any(|name| name.contains('.')));
+        assert_eq!(
+            value.free_variables(),
+            NameSet::from(["config".to_string()])
+        );
+    }
 
-            // Essayer de parser comme paramètres de fonction
-            if let Ok(params) = self.parse_function_params() {
-                self.skip_whitespace();
+    #[test]
+    fn test_long_prefix_chain() {
+        // Chaîne générée bien plus longue que `max_depth` : lue sans récursion par maillon
+        let input = format!("{}body", "with a; assert b; let c = 1; in ".repeat(5000));
+        let mut value = parse_nix_string(&input).unwrap();
+        let mut links = 0;
+        loop {
+            value = match value {
+                NixValue::With(with_expr) => with_expr.body,
+                NixValue::Assert(assert_expr) => assert_expr.body,
+                NixValue::Let(let_expr) => let_expr.body,
+                other => break assert_eq!(other, var("body")),
+            };
+            links += 1;
+        }
+        assert_eq!(links, 15000);
 
-                // Vérifier si c'est suivi de ':' pour confirmer que c'est une fonction
-                if self.current() == Some(':') {
-                    self.advance(); // ':'
-                    self.skip_whitespace();
+        let value = parse_nix_string("with a; with b; assert c; let d = 1; in let { body = d; }");
+        assert_eq!(
+            value.unwrap().to_string(),
+            "with a; with b; assert c; let\n  d = 1;\nin let\nin d"
+        );
+    }
 
-                    let body = self.parse_value()?;
-                    return Ok(NixValue::Function(Box::new(NixFunction { params, body })));
-                }
-            }
+    #[test]
+    fn test_from_str() {
+        let value: NixValue = "{ a = 1; }".parse().unwrap();
+        assert_eq!(value.get("a"), Some(&NixValue::Int(1)));
+        let err = "{ a = ; }".parse::<NixValue>().unwrap_err();
+        assert_eq!((err.line, err.col), (1, 7));
 
-            // Si ce n'est pas une fonction, revenir en arrière et parser comme attrset
-            self.pos = saved_pos;
-            self.line = saved_line;
-            self.col = saved_col;
-            return self.parse_attrset();
+        // Rien ne doit suivre l'expression, sauf des blancs et des commentaires
+        assert_eq!("1 # fin\n".parse::<NixValue>().unwrap(), NixValue::Int(1));
+        let cases = [
+            ("1 )", ')', 3),
+            ("{ a = 1; } }", '}', 12),
+            ("a @ b", '@', 3),
+        ];
+        for (input, c, col) in cases {
+            let err = input.parse::<NixValue>().unwrap_err();
+            assert_eq!(
+                err.message,
+                format!("Unexpected character '{}'", c),
+                "{}",
+                input
+            );
+            assert_eq!((err.line, err.col), (1, col), "{}", input);
         }
 
-        match self.current() {
-            Some('[') => self.parse_list(),
-            Some('"') => Ok(NixValue::String(self.parse_string()?)),
-            Some('\'') => {
-                // Peut être une string multi-ligne ou une string simple
-                if self.input.get(self.pos + 1) == Some(&'\'') {
-                    Ok(NixValue::String(self.parse_string()?))
-                } else {
-                    Ok(NixValue::String(self.parse_string()?))
-                }
-            }
-            Some(c) if c.is_numeric() || c == '-' && self.input.get(self.pos + 1).map_or(false, |ch| ch.is_numeric()) => {
-                self.parse_number()
-            }
-            Some('.') => {
-                // Peut être un chemin relatif (./path ou ../../path) ou un accès d'attribut
-                // On regarde le caractère suivant
-                if let Some(next) = self.input.get(self.pos + 1) {
-                    if *next == '/' || *next == '.' {
-                        // C'est un chemin relatif
-                        let path = self.parse_path()?;
-                        return Ok(NixValue::Path(path));
-                    }
-                }
-                // Sinon c'est probablement une erreur ou un cas spécial
-                Err(self.error("Unexpected '.' - expected path or attribute access"))
-            }
-            Some('/') => {
-                // C'est un chemin absolu
-                let path = self.parse_path()?;
-                Ok(NixValue::Path(path))
-            }
-            Some(_) => {
-                if self.peek_string("null") {
-                    for _ in 0..4 {
-                        self.advance();
-                    }
-                    Ok(NixValue::Null)
-                } else if self.peek_string("true") {
-                    for _ in 0..4 {
-                        self.advance();
-                    }
-                    Ok(NixValue::Bool(true))
-                } else if self.peek_string("false") {
-                    for _ in 0..5 {
-                        self.advance();
-                    }
-                    Ok(NixValue::Bool(false))
-                } else if self.peek_string("let") {
-                    self.parse_let()
-                } else if self.peek_string("import") {
-                    for _ in 0..6 {
-                        self.advance();
-                    }
-                    self.skip_whitespace();
-                    let path = self.parse_value()?;
-                    if let NixValue::String(p) | NixValue::Path(p) = path {
-                        Ok(NixValue::Import(p))
-                    } else {
-                        Err(self.error("Expected string or path after import"))
-                    }
-                } else {
-                    // Parser un identifiant/chemin d'attribut ou une fonction simple
-                    let id = self.parse_attribute_path()?;
-                    self.skip_whitespace();
+        // Dans une fonction renvoyant le type d'erreur unifié
+        #[cfg(feature = "std")]
+        {
+            let parse = |input: &str| -> Result<NixValue> { Ok(input.parse::<NixValue>()?) };
+            assert!(matches!(parse("[ 1 "), Err(NixError::Parse(_))));
+        }
 
-                    // Vérifier si c'est une fonction simple: param: body
-                    if self.current() == Some(':') {
-                        self.advance();
-                        self.skip_whitespace();
-                        let body = self.parse_value()?;
-                        Ok(NixValue::Function(Box::new(NixFunction {
-                            params: vec![id],
-                            body,
-                        })))
-                    } else {
-                        Ok(NixValue::Variable(id))
-                    }
-                }
-            }
-            None => Err(self.error("Unexpected end of input")),
+        // `From<&str>` garde son sens : une chaîne, sans analyse
+        assert_eq!(
+            NixValue::from("{ a = 1; }"),
+            NixValue::String("{ a = 1; }".into())
+        );
+    }
+
+    #[test]
+    fn test_inherit_only_attrset() {
+        for input in [
+            "{ inherit a b; }",
+            "{ inherit (pkgs) a b; }",
+            "rec { inherit a; }",
+        ] {
+            let value = parse_nix_string(input).unwrap();
+            assert_eq!(value.is_empty(), Some(false), "{}", input);
+            assert_eq!(value.to_compact_string(), input);
+            assert_eq!(parse_nix_string(&value.to_string()).unwrap(), value);
         }
+        let value = parse_nix_string("{ inherit a b; }").unwrap();
+        assert_eq!(value.to_string(), "{\n  inherit a b;\n}");
+        let opts = FormatOptions {
+            max_width: 80,
+            ..FormatOptions::default()
+        };
+        assert_eq!(value.format(&opts), "{ inherit a b; }");
     }
 
-    pub fn parse(&mut self) -> Result<NixValue, ParseError> {
-        self.parse_value()
+    #[test]
+    fn test_rename_attr() {
+        let mut value =
+            parse_nix_string("{ services = { old = { enable = true; }; other = 1; }; }").unwrap();
+        assert!(value.rename_attr(&["services", "old"], &["services", "new"]));
+        let expected = parse_nix_string("{ services = { other = 1; new = { enable = true; }; }; }");
+        assert_eq!(value, expected.unwrap());
+
+        // Clés pointées : chaque liaison sous l'ancien chemin est déplacée
+        let mut value = NixParser::new(
+            "{\n  # Port\n  services.old.port = 80;\n  services.old.enable = true;\n  x = 1;\n}",
+        )
+        .with_comments(true)
+        .parse()
+        .unwrap();
+        assert!(value.rename_attr(&["services", "old"], &["services", "new"]));
+        assert_eq!(
+            value.to_string(),
+            "{\n  x = 1;\n  # Port\n  services.new.port = 80;\n  services.new.enable = true;\n}"
+        );
+
+        // Vers un autre ensemble : l'ensemble vidé disparaît
+        let mut value = parse_nix_string("{ a = { b = 1; }; c = { d = 2; }; }").unwrap();
+        assert!(value.rename_attr(&["a", "b"], &["c", "e", "f"]));
+        assert_eq!(
+            value,
+            parse_nix_string("{ c = { d = 2; e.f = 1; }; }").unwrap()
+        );
+
+        // Source absente ou cible déjà définie : rien ne change
+        let original = parse_nix_string("{ a = 1; b = 2; }").unwrap();
+        let mut value = original.clone();
+        assert!(!value.rename_attr(&["missing"], &["z"]));
+        assert!(!value.rename_attr(&["a"], &["b"]));
+        assert!(!value.rename_attr(&["a"], &["a", "x"]));
+        assert_eq!(value, original);
     }
-}
 
-// Fonction principale pour parser un fichier
-pub fn parse_nix_file<P: AsRef<Path>>(path: P) -> Result<NixValue, ParseError> {
-    let content = fs::read_to_string(&path)
-        .map_err(|e| ParseError {
-            message: format!("Failed to read file: {}", e),
-            line: 0,
-            col: 0,
-            context: format!("File: {:?}", path.as_ref()),
-        })?;
-    let mut parser = NixParser::new(&content);
-    parser.parse()
-}
+    #[test]
+    fn test_import_search_path_application() {
+        let input = "import <nixpkgs> { }";
+        let value = parse_nix_string(input).unwrap();
+        let empty = NixValue::AttrSet(AttrSetMap::new());
+        assert_eq!(value, apply(import(PathKind::SearchPath, "nixpkgs"), empty));
+        assert_eq!(value.to_string(), input);
+        assert_eq!(parse_nix_string("import <nixpkgs> {}").unwrap(), value);
 
-// Fonction pour parser une chaîne Nix
-pub fn parse_nix_string(input: &str) -> Result<NixValue, ParseError> {
-    let mut parser = NixParser::new(input);
-    parser.parse()
-}
+        let value = parse_nix_string("import <nixpkgs> { config = { }; overlays = [ ]; }").unwrap();
+        assert_eq!(
+            value.to_string(),
+            "import <nixpkgs> {\n  config = { };\n  overlays = [ ];\n}"
+        );
+        assert_eq!(parse_nix_string(&value.to_string()).unwrap(), value);
 
-// Formatteur pour écrire des valeurs Nix
-impl fmt::Display for NixValue {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        self.write_with_indent(f, 0)
+        // Le nœud `Import` couvre le mot-clé et le chemin, son seul enfant
+        let (_, tree) = NixParser::new(input).parse_with_spans().unwrap();
+        assert_eq!(tree.span, Span { start: 0, end: 20 });
+        assert_eq!(tree.children[0].span, Span { start: 0, end: 16 });
+        assert_eq!(tree.children[0].children.len(), 1);
+        assert_eq!(
+            tree.children[0].children[0].span,
+            Span { start: 7, end: 16 }
+        );
+
+        // Sur une autre expression, `import` reste une fonction appliquée
+        let value = parse_nix_string("import (./a + \"/b.nix\")").unwrap();
+        assert!(matches!(&value, NixValue::Apply(a) if a.func == var("import")));
+        let value = parse_nix_string("map import [ ./a.nix ]").unwrap();
+        assert!(matches!(&value, NixValue::Apply(a) if a.arg.as_list().is_some()));
     }
-}
 
-impl NixValue {
-    fn write_with_indent(&self, f: &mut fmt::Formatter, indent: usize) -> fmt::Result {
-        let indent_str = "  ".repeat(indent);
+    #[test]
+    fn test_import_round_trip() {
+        // Une chaîne importée reste une chaîne, un chemin reste un chemin
+        let value = parse_nix_string("import \"foo.nix\"").unwrap();
+        let string = NixValue::String("foo.nix".to_string());
+        assert_eq!(value, NixValue::Import(Box::new(string)));
+        assert_eq!(value.to_string(), "import \"foo.nix\"");
 
-        match self {
-            NixValue::Null => write!(f, "null"),
-            NixValue::Bool(b) => write!(f, "{}", b),
-            NixValue::Int(i) => write!(f, "{}", i),
-            NixValue::Float(fl) => write!(f, "{}", fl),
-            NixValue::String(s) => write!(f, "\"{}\"", s.replace('\"', "\\\"")),
-            NixValue::Path(p) => write!(f, "{}", p),
-            NixValue::Variable(v) => write!(f, "{}", v),
-            NixValue::Import(p) => write!(f, "import {}", p),
+        let value = parse_nix_string("import \"./a.nix\"").unwrap();
+        assert_eq!(value.to_string(), "import \"./a.nix\"");
+        assert_eq!(parse_nix_string(&value.to_string()).unwrap(), value);
 
-            NixValue::List(items) => {
-                writeln!(f, "[")?;
-                for item in items.iter() {
-                    write!(f, "{}  ", indent_str)?;
-                    item.write_with_indent(f, indent + 1)?;
-                    writeln!(f)?;
-                }
-                write!(f, "{}]", indent_str)
-            }
+        let value = parse_nix_string("import ./a.nix").unwrap();
+        assert_eq!(value, import(PathKind::Relative, "./a.nix"));
+        assert_eq!(value.to_string(), "import ./a.nix");
+        assert_eq!(parse_nix_string(&value.to_string()).unwrap(), value);
+    }
 
-            NixValue::AttrSet(attrs) => {
-                writeln!(f, "{{")?;
-                for (key, value) in attrs.iter() {
-                    // La clé contient déjà les guillemets si nécessaire (format: fileSystems."/".options)
-                    write!(f, "{}  {} = ", indent_str, key)?;
-                    value.write_with_indent(f, indent + 1)?;
-                    writeln!(f, ";")?;
-                }
-                write!(f, "{}}}", indent_str)
-            }
+    #[test]
+    fn test_to_toml_string() {
+        let config = parse_nix_string(
+            r#"{
+                name = "web";
+                port = 8080;
+                ratio = 1.0;
+                tags = [ "a" "b\"c" ];
+                server.tls.enable = true;
+                server.host = "0.0.0.0";
+                users = [ { name = "alice"; } { } ];
+                "my key" = ''
+                  line
+                '';
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.to_toml_string().unwrap(),
+            concat!(
+                "name = \"web\"\n",
+                "port = 8080\n",
+                "ratio = 1.0\n",
+                "tags = [\"a\", \"b\\\"c\"]\n",
+                "users = [{ name = \"alice\" }, {}]\n",
+                "\"my key\" = \"line\\n\"\n",
+                "\n",
+                "[server]\n",
+                "host = \"0.0.0.0\"\n",
+                "\n",
+                "[server.tls]\n",
+                "enable = true\n",
+            )
+        );
+        assert_eq!(
+            parse_nix_string("{ }").unwrap().to_toml_string().unwrap(),
+            ""
+        );
+    }
 
-            NixValue::Let(let_expr) => {
-                writeln!(f, "let")?;
-                for (key, value) in let_expr.bindings.iter() {
-                    write!(f, "{}  {} = ", indent_str, key)?;
-                    value.write_with_indent(f, indent + 1)?;
-                    writeln!(f, ";")?;
-                }
-                write!(f, "{}in ", indent_str)?;
-                let_expr.body.write_with_indent(f, indent)
+    #[test]
+    fn test_to_toml_string_unrepresentable() {
+        let err = |input: &str| {
+            parse_nix_string(input)
+                .unwrap()
+                .to_toml_string()
+                .unwrap_err()
+        };
+        assert_eq!(
+            err("{ a.b = null; }"),
+            ConvertError {
+                path: "a.b".to_string(),
+                type_name: "null"
             }
-
-            NixValue::Function(func) => {
-                if func.params.len() == 1 {
-                    // Fonction simple: x: body
-                    write!(f, "{}: ", func.params[0])?;
-                } else {
-                    // Fonction avec pattern: { x, y, ... }:
-                    write!(f, "{{ ")?;
-                    for (i, param) in func.params.iter().enumerate() {
-                        if i > 0 {
-                            write!(f, ", ")?;
-                        }
-                        write!(f, "{}", param)?;
-                    }
-                    write!(f, ", ... }}: ")?;
-                }
-                func.body.write_with_indent(f, indent)
+        );
+        assert_eq!(
+            err("{ f = [ 1 (x: x) ]; }"),
+            ConvertError {
+                path: "f.1".to_string(),
+                type_name: "lambda"
             }
+        );
+        assert_eq!(err("{ inherit x; }").path, "x");
+        assert_eq!(err("{ p = ./a; }").type_name, "path");
+        assert_eq!(
+            err("[ 1 ]").to_string(),
+            "Une valeur de type list n'a pas d'équivalent TOML"
+        );
+        assert_eq!(
+            err("{ a = null; }").to_string(),
+            "'a' (de type null) n'a pas d'équivalent TOML"
+        );
+    }
+
+    #[test]
+    fn test_reparse_range() {
+        let source = "{\n  server = { port = 8080; host = \"abc\"; };\n  users = [ 1 2 ];\n}";
+        let old = NixParser::new(source).parse_with_spans().unwrap();
+        let edit = |from: &str, to: &str| {
+            let start = source.find(from).unwrap();
+            let input = source.replacen(from, to, 1);
+            let new = reparse_range(&old, &input, start..start + from.len(), to.len()).unwrap();
+            assert_eq!(new, NixParser::new(&input).parse_with_spans().unwrap());
+            new.0
+        };
 
-            _ => write!(f, "/* non implémenté */"),
+        let new = edit("8080", "9090");
+        let changes = old.0.diff(&new);
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].path(), "server.port");
+        assert_eq!(new.get("users"), old.0.get("users"));
+
+        // L'ensemble englobant, seul reparsé, peut changer de forme
+        edit("port = 8080;", "port = 8080; tls = true;");
+        edit("b", "b${x}");
+        edit(" 2 ]", " 2 3 ]");
+        // Modification qui touche un délimiteur : c'est l'ensemble parent qui est reparsé
+        edit("[ 1", "[ 0 1");
+        edit("{ port", "rec { port");
+        edit("];\n}", "] ++ [ 3 ];\n}");
+        // Le nœud reparsé n'est plus une seule string ou liste : tout est reparsé
+        edit("b", "b\" + \"");
+        edit("2", "2 ] [ 3");
+        edit("{\n", "let x = 1; in {\n");
+
+        // Le texte relu l'est avec les options du parseur d'origine
+        let source = "{ a = { # doc\n x = 1; }; }";
+        fn parser(input: &str) -> NixParser<'_> {
+            NixParser::new(input)
+                .with_comments(true)
+                .with_raw_strings(true)
+        }
+        let old = parser(source).parse_with_spans().unwrap();
+        for (from, to) in [("1", "2"), ("1", "\"\\n\"")] {
+            let start = source.find(from).unwrap();
+            let input = source.replacen(from, to, 1);
+            let edited = start..start + from.len();
+            let new = parser(&input)
+                .reparse_range(&old, edited, to.len())
+                .unwrap();
+            assert_eq!(new, parser(&input).parse_with_spans().unwrap());
+            let inner = new.0.get("a").and_then(NixValue::as_attrset).unwrap();
+            assert_eq!(inner.entries()[0].comments, vec!["# doc"]);
         }
     }
-}
 
-// Fonction pour écrire un agrégat Nix dans un fichier
-pub fn write_nix_file<P: AsRef<Path>>(path: P, value: &NixValue) -> Result<(), ParseError> {
-    let content = value.to_string();
-    fs::write(path, content).map_err(|e| ParseError {
-        message: format!("Failed to write file: {}", e),
-        line: 0,
-        col: 0,
-        context: String::new(),
-    })
-}
+    #[test]
+    fn test_comma_separators() {
+        let input = "{ a = 1, b.c = [ 1 2 ], d = 3; }";
+        assert!(parse_nix_string(input).is_err());
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+        let mut parser = NixParser::new(input).with_comma_separators(true);
+        let value = parser.parse().unwrap();
+        assert_eq!(
+            value,
+            parse_nix_string("{ a = 1; b.c = [ 1 2 ]; d = 3; }").unwrap()
+        );
+        let names: Vec<_> = parser.warnings().iter().map(|w| w.name.as_str()).collect();
+        assert_eq!(names, ["a", "b.c"]);
+        assert_eq!(
+            parser.warnings()[0].to_string(),
+            "La liaison 'a' se termine par ',' au lieu de ';'"
+        );
+
+        let mut parser = NixParser::new("{ a = 1, b = 2 }").with_comma_separators(true);
+        assert_eq!(parser.parse().unwrap().get("b"), Some(&NixValue::Int(2)));
+        let kinds: Vec<_> = parser.warnings().iter().map(|w| w.kind).collect();
+        assert_eq!(
+            kinds,
+            [WarningKind::CommaSeparator, WarningKind::MissingSeparator]
+        );
+        assert!(parse_nix_string("{ a = 1, b = 2 }").is_err());
+    }
 
     #[test]
-    fn test_parse_simple_attrset() {
-        let input = r#"{ name = "test"; version = 1; }"#;
-        let result = parse_nix_string(input).unwrap();
+    fn test_missing_let_binding_separator() {
+        for input in ["let a = 1 in a", "let a = 1; b = 2 in a"] {
+            let err = parse_nix_string(input).unwrap_err();
+            assert!(
+                err.message.starts_with("Expected ';' after let binding"),
+                "{}",
+                input
+            );
 
-        if let NixValue::AttrSet(attrs) = result {
-            assert_eq!(attrs.len(), 2);
-            assert_eq!(attrs.get("name"), Some(&NixValue::String("test".to_string())));
-            assert_eq!(attrs.get("version"), Some(&NixValue::Int(1)));
-        } else {
-            panic!("Expected AttrSet");
+            let mut parser = NixParser::new(input).with_comma_separators(true);
+            assert!(parser.parse().is_ok());
+            assert_eq!(parser.warnings()[0].kind, WarningKind::MissingSeparator);
         }
+
+        let mut parser = NixParser::new("let a = 1, b = a; in b").with_comma_separators(true);
+        let expected = parse_nix_string("let a = 1; b = a; in b").unwrap();
+        assert_eq!(parser.parse().unwrap(), expected);
+        assert_eq!(parser.warnings()[0].kind, WarningKind::CommaSeparator);
+
+        // En mode reprise, la liaison est gardée
+        let (value, errors) = parse_with_recovery("let a = 1 in a");
+        assert_eq!(value, Some(parse_nix_string("let a = 1; in a").unwrap()));
+        assert_eq!(errors.len(), 1);
     }
 
     #[test]
-    fn test_parse_list() {
-        let input = r#"[1 2 3 "test"]"#;
-        let result = parse_nix_string(input).unwrap();
+    fn test_missing_binding_separator() {
+        for input in ["{ a = 1 }", "{ a = 1; b = 2 }"] {
+            let err = parse_nix_string(input).unwrap_err();
+            assert!(
+                err.message.starts_with("Expected ';' after attribute"),
+                "{}",
+                input
+            );
 
-        if let NixValue::List(items) = result {
-            assert_eq!(items.len(), 4);
-        } else {
-            panic!("Expected List");
+            let mut parser = NixParser::new(input).with_comma_separators(true);
+            assert!(parser.parse().is_ok());
+            assert_eq!(parser.warnings().len(), 1, "{}", input);
+            assert_eq!(parser.warnings()[0].kind, WarningKind::MissingSeparator);
         }
+        let err = parse_nix_string("{ a = 1; b = 2 }").unwrap_err();
+        assert_eq!(err.message, "Expected ';' after attribute 'b'");
+        assert_eq!((err.line, err.col), (1, 16));
+
+        // En mode récupération, la liaison est gardée et l'erreur notée
+        let (value, errors) = parse_with_recovery("{ a = 1 }");
+        assert_eq!(value.unwrap().get("a"), Some(&NixValue::Int(1)));
+        assert_eq!(errors.len(), 1);
     }
 
     #[test]
-    fn test_parse_let() {
-        let input = r#"let x = 5; y = 10; in { result = x; }"#;
-        let result = parse_nix_string(input).unwrap();
-
-        if let NixValue::Let(let_expr) = result {
-            assert_eq!(let_expr.bindings.len(), 2);
-        } else {
-            panic!("Expected Let");
-        }
+    fn test_canonicalize_paths() {
+        let canonical = |input: &str| {
+            parse_nix_string(input)
+                .unwrap()
+                .canonicalize_paths()
+                .to_compact_string()
+        };
+        assert_eq!(canonical("./foo/../bar"), "./bar");
+        assert_eq!(canonical("././x"), "./x");
+        assert_eq!(canonical("./a/.."), "./.");
+        assert_eq!(canonical("../a/./../../b"), "../../b");
+        assert_eq!(canonical("./.."), "../.");
+        assert_eq!(canonical("/etc//nixos/../x"), "/etc/x");
+        assert_eq!(canonical("/../x"), "/x");
+        assert_eq!(canonical("~/a/../b"), "~/b");
+        assert_eq!(canonical("<nixpkgs/lib/../pkgs>"), "<nixpkgs/pkgs>");
+        assert_eq!(canonical("<nixpkgs/lib/..>"), "<nixpkgs>");
+        assert_eq!(canonical("import ./a/../b.nix"), "import ./b.nix");
+        assert_eq!(
+            canonical("import \"./a/../b.nix\""),
+            "import \"./a/../b.nix\""
+        );
+        assert_eq!(
+            canonical("[ ./a/${x}/.. \"./a/..\" ]"),
+            "[ ./a/${x}/.. \"./a/..\" ]"
+        );
     }
 
     #[test]
-    fn test_parse_function_pattern() {
-        let input = r#"{ self, config, inputs, pkgs, pkgs-unstable, ... }: { name = "test"; }"#;
-        let result = parse_nix_string(input).unwrap();
+    fn test_eval_with() {
+        let eval = |input: &str| parse_nix_string(input).unwrap().eval();
 
-        if let NixValue::Function(func) = result {
-            assert!(func.params.contains(&"self".to_string()));
-            assert!(func.params.contains(&"config".to_string()));
-            assert!(func.params.contains(&"pkgs".to_string()));
-        } else {
-            panic!("Expected Function, got {:?}", result);
-        }
+        assert_eq!(eval("with { a = 1; }; a"), Ok(NixValue::Int(1)));
+        // Le `with` le plus proche l'emporte, mais jamais sur une liaison lexicale
+        assert_eq!(
+            eval("with { a = 1; }; with { a = 2; }; a"),
+            Ok(NixValue::Int(2))
+        );
+        assert_eq!(
+            eval("let a = 3; in with { a = 1; }; with { a = 2; }; a"),
+            Ok(NixValue::Int(3))
+        );
+        assert_eq!(
+            eval("with { a = 1; }; let b = a + 1; in b"),
+            Ok(NixValue::Int(2))
+        );
+        assert_eq!(
+            eval("let s = { a = 1; }; in with s; a"),
+            Ok(NixValue::Int(1))
+        );
+        assert_eq!(
+            eval("with { a = 1; }; b"),
+            Err(EvalError::UndefinedVariable("b".to_string()))
+        );
+        assert!(matches!(eval("with 1; a"), Err(EvalError::TypeMismatch(_))));
     }
 
+    // Le cœur doit compiler en `no_std` : construit dans un répertoire cible à part pour ne
+    // pas attendre le verrou du `cargo test` en cours
     #[test]
-    fn test_parse_simple_function() {
-        let input = r#"x: x + 1"#;
-        let result = parse_nix_string(input).unwrap();
+    #[cfg(feature = "std")]
+    fn test_builds_without_std() {
+        let manifest_dir = env!("CARGO_MANIFEST_DIR");
+        let cargo = std::env::var("CARGO").unwrap_or_else(|_| "cargo".to_string());
+        let output = std::process::Command::new(cargo)
+            .args([
+                "build",
+                "--lib",
+                "--offline",
+                "--no-default-features",
+                "--target-dir",
+            ])
+            .arg(
+                std::path::Path::new(manifest_dir)
+                    .join("target")
+                    .join("no-std"),
+            )
+            .current_dir(manifest_dir)
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
 
-        if let NixValue::Function(func) = result {
-            assert_eq!(func.params.len(), 1);
-            assert_eq!(func.params[0], "x");
-        } else {
-            panic!("Expected Function");
+    #[test]
+    fn test_select_default_round_trip() {
+        let cases = [
+            ("a.b or (f x)", "a.b or (f x)"),
+            ("(a.b or c).d", "(a.b or c).d"),
+            ("a.b or (x: x)", "a.b or (x: x)"),
+            ("f (a.b or c)", "f (a.b or c)"),
+            ("(a.b or c) x", "a.b or c x"),
+            ("a.b or (c.d or e)", "a.b or (c.d or e)"),
+            ("a.b or c.d", "a.b or c.d"),
+            ("a.b or 1 + x", "a.b or 1 + x"),
+        ];
+        for (input, expected) in cases {
+            let value = parse_nix_string(input).unwrap();
+            let printed = value.to_string();
+            assert_eq!(printed, expected);
+            assert_eq!(parse_nix_string(&printed).unwrap(), value, "{}", input);
         }
     }
 }
@@ -0,0 +1,39 @@
+{
+  description = "Configuration NixOS du laptop";
+
+  inputs = {
+    nixpkgs.url = "github:NixOS/nixpkgs/nixos-unstable";
+    home-manager = {
+      url = "github:nix-community/home-manager";
+      inputs.nixpkgs.follows = "nixpkgs";
+    };
+    flake-utils.url = "github:numtide/flake-utils";
+  };
+
+  outputs = { self, nixpkgs, home-manager, ... }:
+    let
+      system = "x86_64-linux";
+      pkgs = nixpkgs.legacyPackages.${system};
+    in {
+      nixosConfigurations.laptop = nixpkgs.lib.nixosSystem {
+        inherit system;
+        modules = [
+          ./hosts/laptop/configuration.nix
+          home-manager.nixosModules.home-manager
+          {
+            home-manager.useGlobalPkgs = true;
+            home-manager.users.quentin = import ./home.nix;
+          }
+        ];
+      };
+
+      devShells.${system}.default = pkgs.mkShell {
+        packages = [ pkgs.git pkgs.nixfmt-rfc-style ];
+        shellHook = ''
+          echo "Bienvenue dans ${self}"
+        '';
+      };
+
+      formatter.${system} = pkgs.nixfmt-rfc-style;
+    };
+}